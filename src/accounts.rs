@@ -0,0 +1,456 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Write as _},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::Path,
+    process,
+    sync::OnceLock,
+};
+
+use anyhow::anyhow;
+use log::warn;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::auth::{derive_key, login_proof};
+
+/// Number of PBKDF2 iterations used for newly created accounts
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const SALT_LEN: usize = 16;
+
+/// Registered player with hashed credentials
+///
+/// Stored in the accounts file as a single line:
+/// `username:player_id:iterations:salt_hex:hash_hex`
+///
+/// `hash` is the key the client derives from the password, which is enough to answer
+/// login challenges. Accounts file is therefore as sensitive as the passwords themselves
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub username: String,
+    /// Stable ID assigned to the player on every login
+    pub player_id: Uuid,
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub hash: Vec<u8>,
+}
+
+impl Account {
+    pub fn new(username: &str, password: &str) -> Result<Self, anyhow::Error> {
+        validate_username(username)?;
+        if password.is_empty() {
+            return Err(anyhow!("Password cannot be empty"));
+        }
+        let mut salt = vec![0u8; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|e| anyhow!("Unable to generate salt: {e}"))?;
+        let hash = derive_key(password, &salt, PBKDF2_ITERATIONS);
+
+        Ok(Self {
+            username: username.to_string(),
+            player_id: Uuid::new_v4(),
+            iterations: PBKDF2_ITERATIONS,
+            salt,
+            hash,
+        })
+    }
+
+    /// Check the client's answer to the login challenge in constant time
+    /// Stored hash is the key the client derives from the password
+    pub fn verify_proof(&self, nonce: &[u8], proof: &[u8]) -> bool {
+        let expected = login_proof(&self.hash, nonce);
+        expected.len() == proof.len()
+            && expected
+                .iter()
+                .zip(proof.iter())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}",
+            self.username,
+            self.player_id,
+            self.iterations,
+            to_hex(&self.salt),
+            to_hex(&self.hash)
+        )
+    }
+
+    fn parse(line: &str) -> Result<Self, anyhow::Error> {
+        let fields = line.split(':').collect::<Vec<&str>>();
+        let [username, player_id, iterations, salt, hash] = fields[..] else {
+            return Err(anyhow!("expected 5 fields, found {}", fields.len()));
+        };
+        validate_username(username)?;
+        Ok(Self {
+            username: username.to_string(),
+            player_id: Uuid::parse_str(player_id)?,
+            iterations: iterations.parse()?,
+            salt: from_hex(salt)?,
+            hash: from_hex(hash)?,
+        })
+    }
+}
+
+/// Accounts stored in a local file
+#[derive(Debug, Default)]
+pub struct Accounts {
+    accounts: Vec<Account>,
+}
+
+impl Accounts {
+    /// Load accounts from `path`. Missing file is treated as no accounts
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Accounts::default()),
+            Err(e) => return Err(anyhow!("Unable to read {}: {e}", path.display())),
+        };
+        // Stored keys are enough to log in, nobody but the server should read them
+        if fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o077 != 0) {
+            warn!(
+                "{} can be accessed by other users, restrict it with `chmod 600`",
+                path.display()
+            );
+        }
+        let accounts = content
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|(idx, line)| {
+                Account::parse(line.trim())
+                    .map_err(|e| anyhow!("{}:{}: invalid account: {e}", path.display(), idx + 1))
+            })
+            .collect::<Result<Vec<Account>, anyhow::Error>>()?;
+        Ok(Self { accounts })
+    }
+
+    /// Write accounts to a temporary file next to `path` readable only by its owner
+    /// and rename it over `path`, so readers never see a partially written file
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut content = String::from("# username:player_id:iterations:salt:hash\n");
+        for account in &self.accounts {
+            content.push_str(&account.to_line());
+            content.push('\n');
+        }
+        let file_name = path
+            .file_name()
+            .ok_or(anyhow!("{} is not a file", path.display()))?;
+        let temp_path = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            file_name.to_string_lossy(),
+            process::id()
+        ));
+        write_private(&temp_path, content.as_bytes())
+            .and_then(|_| fs::rename(&temp_path, path))
+            .map_err(|e| {
+                let _ = fs::remove_file(&temp_path);
+                anyhow!("Unable to write {}: {e}", path.display())
+            })
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Account> {
+        self.accounts
+            .iter()
+            .find(|account| account.username == username)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    pub fn add(&mut self, username: &str, password: &str) -> Result<&Account, anyhow::Error> {
+        if self.get(username).is_some() {
+            return Err(anyhow!("Account {username} already exists"));
+        }
+        self.accounts.push(Account::new(username, password)?);
+        Ok(self.accounts.last().expect("account has just been added"))
+    }
+
+    /// Remove the account, returns `false` if it didn't exist
+    pub fn remove(&mut self, username: &str) -> bool {
+        let count = self.accounts.len();
+        self.accounts.retain(|account| account.username != username);
+        count != self.accounts.len()
+    }
+
+    /// Salt and iteration count the client needs to derive the key of the account
+    /// Unknown usernames get a made up salt so they can't be told apart from existing accounts
+    pub fn key_params(&self, username: &str) -> (Vec<u8>, u32) {
+        match self.get(username) {
+            Some(account) => (account.salt.clone(), account.iterations),
+            None => (fake_salt(username), PBKDF2_ITERATIONS),
+        }
+    }
+
+    /// Returns player ID of the account if the proof answers the login challenge
+    pub fn verify_proof(&self, username: &str, nonce: &[u8], proof: &[u8]) -> Option<Uuid> {
+        self.get(username)
+            .filter(|account| account.verify_proof(nonce, proof))
+            .map(|account| account.player_id)
+    }
+}
+
+/// Salt derived from the username and a secret generated on server start,
+/// stays the same for repeated logins of the same unknown username
+fn fake_salt(username: &str) -> Vec<u8> {
+    static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+    let secret = SECRET.get_or_init(|| {
+        let mut secret = [0u8; 32];
+        getrandom::getrandom(&mut secret).expect("Unable to generate secret for fake salts");
+        secret
+    });
+    let mut hasher = Sha256::new();
+    hasher.update(secret);
+    hasher.update(username.as_bytes());
+    hasher.finalize()[..SALT_LEN].to_vec()
+}
+
+/// Create a new file with mode `0600` and flush `content` to the disk
+fn write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    // Leftover of an interrupted save could have other permissions
+    let _ = fs::remove_file(path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content)?;
+    file.sync_all()
+}
+
+fn validate_username(username: &str) -> Result<(), anyhow::Error> {
+    if username.is_empty()
+        || username.len() > 32
+        || !username
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '.')
+    {
+        return Err(anyhow!(
+            "Username must have 1 to 32 characters and contain only letters, digits, '_', '-' or '.'"
+        ));
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, anyhow::Error> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(anyhow!("invalid hex string"));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).map_err(|e| anyhow!(e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    /// Few iterations keep the tests fast
+    fn account(username: &str, password: &str) -> Account {
+        let salt = username.as_bytes().to_vec();
+        Account {
+            username: username.to_string(),
+            player_id: Uuid::new_v4(),
+            iterations: 10,
+            hash: derive_key(password, &salt, 10),
+            salt,
+        }
+    }
+
+    /// Proof the client sends for the challenge of the account
+    fn answer(accounts: &Accounts, username: &str, password: &str, nonce: &[u8]) -> Vec<u8> {
+        let (salt, iterations) = accounts.key_params(username);
+        login_proof(&derive_key(password, &salt, iterations), nonce)
+    }
+
+    #[test]
+    fn correct_proof_logs_in() {
+        let alice = account("alice", "secret");
+        let player_id = alice.player_id;
+        let accounts = Accounts {
+            accounts: vec![alice],
+        };
+        let proof = answer(&accounts, "alice", "secret", b"nonce");
+        assert_eq!(
+            accounts.verify_proof("alice", b"nonce", &proof),
+            Some(player_id)
+        );
+    }
+
+    #[test]
+    fn wrong_proof_is_rejected() {
+        let accounts = Accounts {
+            accounts: vec![account("alice", "secret")],
+        };
+        let proof = answer(&accounts, "alice", "wrong", b"nonce");
+        assert_eq!(accounts.verify_proof("alice", b"nonce", &proof), None);
+
+        let proof = answer(&accounts, "alice", "secret", b"nonce");
+        assert_eq!(accounts.verify_proof("alice", b"nonce", &proof[1..]), None);
+        assert_eq!(accounts.verify_proof("alice", b"nonce", &[]), None);
+        assert_eq!(accounts.verify_proof("bob", b"nonce", &proof), None);
+    }
+
+    #[test]
+    fn proof_cannot_be_replayed_for_another_nonce() {
+        let accounts = Accounts {
+            accounts: vec![account("alice", "secret")],
+        };
+        let proof = answer(&accounts, "alice", "secret", b"first nonce");
+        assert!(accounts
+            .verify_proof("alice", b"first nonce", &proof)
+            .is_some());
+        assert_eq!(
+            accounts.verify_proof("alice", b"second nonce", &proof),
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_usernames_get_stable_fake_salt() {
+        let accounts = Accounts {
+            accounts: vec![account("alice", "secret")],
+        };
+        let (salt, iterations) = accounts.key_params("ghost");
+        assert_eq!(accounts.key_params("ghost"), (salt.clone(), iterations));
+        assert_eq!(salt.len(), SALT_LEN);
+        assert_eq!(iterations, PBKDF2_ITERATIONS);
+        assert_ne!(accounts.key_params("ghost2").0, salt);
+        assert_eq!(accounts.key_params("alice"), (b"alice".to_vec(), 10));
+    }
+
+    fn temp_path() -> std::path::PathBuf {
+        env::temp_dir().join(format!("luxonis-accounts-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn saved_accounts_are_loaded_again() {
+        let path = temp_path();
+        let accounts = Accounts {
+            accounts: vec![account("alice", "secret"), account("bob", "hunter2")],
+        };
+        accounts.save(&path).unwrap();
+        let loaded = Accounts::load(&path);
+        let _ = fs::remove_file(&path);
+
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded.iter().count(), 2);
+        for account in accounts.iter() {
+            let other = loaded.get(&account.username).unwrap();
+            assert_eq!(other.player_id, account.player_id);
+            assert_eq!(other.iterations, account.iterations);
+            assert_eq!(other.salt, account.salt);
+            assert_eq!(other.hash, account.hash);
+        }
+    }
+
+    #[test]
+    fn accounts_file_is_private_and_replaced_whole() {
+        let path = temp_path();
+        fs::write(&path, "# old accounts\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let accounts = Accounts {
+            accounts: vec![account("alice", "secret")],
+        };
+        accounts.save(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = Accounts::load(&path);
+        let leftovers = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                name.contains(&path.file_name().unwrap().to_string_lossy().into_owned())
+                    && name.ends_with(".tmp")
+            })
+            .count();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(loaded.unwrap().get("alice").is_some());
+        assert_eq!(leftovers, 0);
+    }
+
+    #[test]
+    fn missing_file_has_no_accounts() {
+        assert!(Accounts::load(&temp_path()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let valid = account("alice", "secret").to_line();
+        let player_id = Uuid::new_v4();
+        for line in [
+            "alice".to_string(),
+            format!("{valid}:extra"),
+            format!("al ice:{player_id}:10:00:00"),
+            "alice:not-a-uuid:10:00:00".to_string(),
+            format!("alice:{player_id}:many:00:00"),
+            format!("alice:{player_id}:10:0g:00"),
+            format!("alice:{player_id}:10:00:0"),
+        ] {
+            let path = temp_path();
+            fs::write(&path, format!("# header\n{valid}\n\n{line}\n")).unwrap();
+            let result = Accounts::load(&path);
+            let _ = fs::remove_file(&path);
+
+            let error = result.unwrap_err().to_string();
+            assert!(
+                error.starts_with(&format!("{}:4: invalid account", path.display())),
+                "{line}: {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn usernames_are_validated() {
+        for username in ["alice", "Bob_2", "a.b-c", &"x".repeat(32)] {
+            assert!(validate_username(username).is_ok(), "{username}");
+        }
+        for username in ["", "al ice", "alice:bob", "alice!", &"x".repeat(33)] {
+            assert!(validate_username(username).is_err(), "{username}");
+        }
+    }
+
+    #[test]
+    fn player_ids_survive_adding_and_removing_accounts() {
+        let mut accounts = Accounts {
+            accounts: vec![account("alice", "secret"), account("bob", "hunter2")],
+        };
+        let alice = accounts.get("alice").unwrap().player_id;
+        let bob = accounts.get("bob").unwrap().player_id;
+
+        let carol = accounts.add("carol", "password").unwrap().player_id;
+        assert!(accounts.add("carol", "other").is_err());
+        assert!(accounts.remove("alice"));
+        assert!(!accounts.remove("alice"));
+
+        let path = temp_path();
+        accounts.save(&path).unwrap();
+        let loaded = Accounts::load(&path);
+        let _ = fs::remove_file(&path);
+
+        let loaded = loaded.unwrap();
+        assert!(loaded.get("alice").is_none());
+        assert_eq!(loaded.get("bob").unwrap().player_id, bob);
+        assert_eq!(loaded.get("carol").unwrap().player_id, carol);
+        assert_ne!(carol, alice);
+    }
+}
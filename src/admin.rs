@@ -0,0 +1,55 @@
+use std::{
+    io::{stdin, IsTerminal},
+    path::Path,
+};
+
+use anyhow::anyhow;
+use indoc::formatdoc;
+
+use crate::accounts::Accounts;
+
+/// Run `server accounts ...` administration command
+/// `args` are the arguments following `accounts`
+pub fn run_accounts_command(args: &[String], accounts_file: &Path) -> Result<(), anyhow::Error> {
+    let mut accounts = Accounts::load(accounts_file)?;
+    match args {
+        [command, username] if command == "add" => {
+            let password = read_password()?;
+            let account = accounts.add(username, &password)?;
+            println!("Account {} created with ID {}", username, account.player_id);
+            accounts.save(accounts_file)?;
+        }
+        [command, username] if command == "remove" => {
+            if !accounts.remove(username) {
+                return Err(anyhow!("Account {username} doesn't exist"));
+            }
+            accounts.save(accounts_file)?;
+            println!("Account {username} removed");
+        }
+        [command] if command == "list" => {
+            for account in accounts.iter() {
+                println!("{}\t{}", account.username, account.player_id);
+            }
+        }
+        _ => {
+            return Err(anyhow!(formatdoc! {"
+                Usage:
+                    server accounts add <username>     (password is prompted without echo or piped to stdin)
+                    server accounts remove <username>
+                    server accounts list
+            "}));
+        }
+    }
+    Ok(())
+}
+
+/// Prompt for the password without echoing it on a terminal,
+/// scripts can pipe it to stdin instead
+fn read_password() -> Result<String, anyhow::Error> {
+    if stdin().is_terminal() {
+        return Ok(rpassword::prompt_password("Password: ")?);
+    }
+    let mut password = String::new();
+    stdin().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,55 @@
+//! Challenge-response login shared by the client and the server.
+//!
+//! Password never leaves the client. The server sends a random nonce together with
+//! the salt and iteration count of the account, the client derives the key from the password
+//! and answers with `login_proof` of the nonce. The server computes the same proof
+//! from the stored key and compares both.
+
+use hmac::{Hmac, Mac};
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+/// Length of the key derived from the password
+pub const KEY_LEN: usize = 32;
+
+/// Derive the key from the password with PBKDF2-HMAC-SHA256
+pub fn derive_key(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut key = vec![0u8; KEY_LEN];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, iterations, &mut key);
+    key
+}
+
+/// HMAC-SHA256 of the server's `nonce` keyed by the derived key
+pub fn login_proof(key: &[u8], nonce: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn derive_key_matches_rfc_7914_vector() {
+        assert_eq!(
+            derive_key("passwd", b"salt", 1),
+            from_hex("55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc")
+        );
+    }
+
+    #[test]
+    fn login_proof_matches_rfc_4231_vector() {
+        assert_eq!(
+            login_proof(b"Jefe", b"what do ya want for nothing?"),
+            from_hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+    }
+}
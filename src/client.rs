@@ -1,18 +1,38 @@
-use client_connection::{create_connection, handle_server_connection};
+use client_connection::{create_connection, handle_server_connection, reconnect_delay};
 use client_state::{ClientState, State};
+use codec::MalformedMessage;
+use connection::WireVersion;
 use log::{debug, error, info};
+use protocol::{ClientMessage, ServerMessage};
 use std::{env, process};
 use tokio::{
     io::{stdin, AsyncBufReadExt, BufReader, Stdin},
-    select, signal,
-    sync::mpsc::{self},
+    select,
+    signal::{
+        self,
+        unix::{Signal, SignalKind},
+    },
+    sync::mpsc::{self, Receiver, Sender},
+    time::sleep,
 };
 
+mod auth;
 mod client_connection;
 mod client_state;
+mod codec;
 mod connection;
+mod framing;
 mod protocol;
-mod validation;
+mod tls;
+
+/// Client gives up after this many failed attempts to reconnect
+const RECONNECT_ATTEMPTS: u32 = 10;
+
+type ServerConnection = (
+    Sender<ClientMessage>,
+    Receiver<Result<ServerMessage, MalformedMessage>>,
+    WireVersion,
+);
 
 /// Client application for "guess a word" game
 #[tokio::main]
@@ -22,20 +42,21 @@ async fn main() -> Result<(), anyhow::Error> {
     let args: Vec<String> = env::args().collect();
 
     if args.len() != 2 {
-        eprintln!("Usage: {} <TCP URL or .sock path>", args[0]);
+        eprintln!(
+            "Usage: {} <TCP URL, tls://host:port, .sock path or @abstract socket name>",
+            args[0]
+        );
         process::exit(1);
     }
 
     let input = &args[1];
     // let mut client_state = Arc::new(Mutex::new(ClientState::default()));
     let mut client_state = ClientState::default();
-    let (tx, mut rx) = mpsc::channel(100);
-    let connection = create_connection(input).await?;
-    let server_tx = handle_server_connection(connection, tx).await?;
+    let (mut server_tx, mut rx, mut wire_version) = connect(input).await?;
 
     info!("Connection successful");
-    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-        .expect("Failed to register SIGTERM handler");
+    let mut terminate =
+        signal::unix::signal(SignalKind::terminate()).expect("Failed to register SIGTERM handler");
     let mut user_input = get_user_input_stream();
 
     loop {
@@ -43,7 +64,27 @@ async fn main() -> Result<(), anyhow::Error> {
         select! {
             server_msg = rx.recv() => {
                 match server_msg {
-                    Some(msg) =>  client_state.update_from_server(msg),
+                    // Keep the connection alive even while waiting for user input
+                    Some(Ok(ServerMessage::Ping)) => send_to_server(&server_tx, ClientMessage::Pong).await,
+                    Some(Ok(msg)) => {
+                        let reply = client_state.update_from_server(msg);
+                        // Following messages use the version negotiated by `Hello`
+                        wire_version.set(client_state.protocol_version);
+                        if let Some(reply) = reply {
+                            send_to_server(&server_tx, reply).await;
+                        }
+                    }
+                    Some(Err(malformed)) => {
+                        error!("Unable to decode message from server: {malformed}");
+                    }
+                    None if client_state.should_reconnect() => {
+                        let Some(connection) = reconnect(input, &mut terminate).await else {
+                            break;
+                        };
+                        println!("Reconnected to the server.");
+                        (server_tx, rx, wire_version) = connection;
+                        client_state.reset_connection();
+                    }
                     None => {
                         error!("Server disconnected");
                         break;
@@ -53,7 +94,7 @@ async fn main() -> Result<(), anyhow::Error> {
             input = user_input.next_line() => {
                 let input = input.unwrap().unwrap();
                 if let Some(msg) = client_state.update_from_user(&input) {
-                    server_tx.send(msg).await?;
+                    send_to_server(&server_tx, msg).await;
                 }
             }
             _ = signal::ctrl_c() => {
@@ -67,7 +108,7 @@ async fn main() -> Result<(), anyhow::Error> {
         if !client_state.status.eq(&previous_status) {
             if let Some(msg) = client_state.process() {
                 debug!("process {msg:?}");
-                server_tx.send(msg).await?;
+                send_to_server(&server_tx, msg).await;
             }
         }
 
@@ -80,6 +121,46 @@ async fn main() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Messages sent while the connection is going down are lost,
+/// the client reconnects as soon as the incoming side closes as well
+async fn send_to_server(server_tx: &Sender<ClientMessage>, msg: ClientMessage) {
+    if let Err(e) = server_tx.send(msg).await {
+        debug!("Unable to send {:?}, connection is closed", e.0);
+    }
+}
+
+async fn connect(input: &str) -> Result<ServerConnection, anyhow::Error> {
+    let (tx, rx) = mpsc::channel(100);
+    let connection = create_connection(input).await?;
+    let wire_version = WireVersion::default();
+    let server_tx = handle_server_connection(connection, tx, wire_version.clone()).await?;
+    Ok((server_tx, rx, wire_version))
+}
+
+/// Try to connect again with exponential backoff after the connection dropped
+/// Returns `None` if all attempts fail or the user quits in the meantime
+async fn reconnect(input: &str, terminate: &mut Signal) -> Option<ServerConnection> {
+    for attempt in 0..RECONNECT_ATTEMPTS {
+        let delay = reconnect_delay(attempt);
+        println!(
+            "Connection lost. Reconnecting in {:.1}s (attempt {}/{RECONNECT_ATTEMPTS})...",
+            delay.as_secs_f32(),
+            attempt + 1
+        );
+        select! {
+            _ = sleep(delay) => {}
+            _ = signal::ctrl_c() => return None,
+            _ = terminate.recv() => return None,
+        }
+        match connect(input).await {
+            Ok(connection) => return Some(connection),
+            Err(e) => debug!("Reconnect attempt failed: {e}"),
+        }
+    }
+    error!("Unable to reconnect to the server after {RECONNECT_ATTEMPTS} attempts");
+    None
+}
+
 fn get_user_input_stream() -> tokio::io::Lines<BufReader<Stdin>> {
     let stdin = stdin();
     let reader = BufReader::new(stdin);
@@ -1,32 +1,80 @@
+use anyhow::anyhow;
 use log::info;
-use std::path::Path;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
     net::{TcpStream, UnixStream},
     sync::mpsc::Sender,
 };
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
 
 use crate::{
-    connection::handle_stream,
+    codec::MalformedMessage,
+    connection::{handle_stream, WireVersion},
     protocol::{ClientMessage, ServerMessage},
+    tls::{load_certs, load_private_key, load_root_store},
 };
 
+/// Prefix of addresses that should be connected to over TLS
+const TLS_SCHEME: &str = "tls://";
+const TLS_CA_ENV: &str = "LUXONIS_TLS_CA";
+const TLS_CLIENT_CERT_ENV: &str = "LUXONIS_TLS_CLIENT_CERT";
+const TLS_CLIENT_KEY_ENV: &str = "LUXONIS_TLS_CLIENT_KEY";
+/// Number of messages waiting to be sent to the server
+const CHANNEL_CAPACITY: usize = 100;
+/// Delay before the first reconnect attempt, doubled with every next attempt
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
 pub enum ClientConnection {
     Tcp(TcpStream),
     Unix(UnixStream),
+    Tls(Box<TlsStream<TcpStream>>),
 }
 
 pub async fn handle_server_connection(
     connection: ClientConnection,
-    output_tx: Sender<ServerMessage>,
+    output_tx: Sender<Result<ServerMessage, MalformedMessage>>,
+    version: WireVersion,
 ) -> Result<Sender<ClientMessage>, anyhow::Error> {
     match connection {
-        ClientConnection::Tcp(stream) => handle_stream(stream, output_tx).await,
-        ClientConnection::Unix(stream) => handle_stream(stream, output_tx).await,
+        ClientConnection::Tcp(stream) => {
+            handle_stream(stream, output_tx, CHANNEL_CAPACITY, version).await
+        }
+        ClientConnection::Unix(stream) => {
+            handle_stream(stream, output_tx, CHANNEL_CAPACITY, version).await
+        }
+        ClientConnection::Tls(stream) => {
+            handle_stream(stream, output_tx, CHANNEL_CAPACITY, version).await
+        }
     }
 }
 
 pub async fn create_connection(input: &str) -> Result<ClientConnection, anyhow::Error> {
-    if is_valid_sock_path(input) {
+    if let Some(addr) = input.strip_prefix(TLS_SCHEME) {
+        info!("Attempting to connect to TLS address: {}", addr);
+        let (host, _port) = addr
+            .rsplit_once(':')
+            .ok_or(anyhow!("TLS address must be in the form tls://host:port"))?;
+        let server_name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())?;
+        let connector = create_tls_connector()?;
+        let tcp_stream = TcpStream::connect(addr).await?;
+        let tls_stream = connector.connect(server_name, tcp_stream).await?;
+        Ok(ClientConnection::Tls(Box::new(tls_stream)))
+    } else if let Some(name) = input.strip_prefix('@') {
+        info!("Attempting to connect to abstract Unix socket: {}", input);
+        // Abstract socket names start with a NUL byte instead of `@`
+        let unix_stream = UnixStream::connect(format!("\0{name}")).await?;
+        Ok(ClientConnection::Unix(unix_stream))
+    } else if is_valid_sock_path(input) {
         info!("Attempting to connect to Unix socket: {}", input);
         let unix_stream = UnixStream::connect(input).await?;
         Ok(ClientConnection::Unix(unix_stream))
@@ -37,7 +85,46 @@ pub async fn create_connection(input: &str) -> Result<ClientConnection, anyhow::
     }
 }
 
+/// Server certificate is verified against CAs from `LUXONIS_TLS_CA` or the bundled web PKI roots
+/// Client certificate is presented when both `LUXONIS_TLS_CLIENT_CERT` and `LUXONIS_TLS_CLIENT_KEY` are set
+fn create_tls_connector() -> Result<TlsConnector, anyhow::Error> {
+    let roots = match env::var(TLS_CA_ENV) {
+        Ok(ca_file) => load_root_store(&PathBuf::from(ca_file))?,
+        Err(_) => RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    };
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    let client_config = match (env::var(TLS_CLIENT_CERT_ENV), env::var(TLS_CLIENT_KEY_ENV)) {
+        (Ok(cert_file), Ok(key_file)) => builder
+            .with_client_auth_cert(
+                load_certs(&PathBuf::from(cert_file))?,
+                load_private_key(&PathBuf::from(key_file))?,
+            )
+            .map_err(|e| anyhow!("Invalid client certificate or key: {e}"))?,
+        (Err(_), Err(_)) => builder.with_no_client_auth(),
+        _ => {
+            return Err(anyhow!(
+                "Both {TLS_CLIENT_CERT_ENV} and {TLS_CLIENT_KEY_ENV} must be set to use a client certificate"
+            ))
+        }
+    };
+    Ok(TlsConnector::from(Arc::new(client_config)))
+}
+
+/// Delay before the reconnect attempt (counted from 0) with exponential backoff.
+/// Random jitter between half and the full delay spreads out clients dropped at the same time
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    let delay = RECONNECT_BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(RECONNECT_MAX_DELAY);
+    let mut random = [0u8; 4];
+    let jitter = match getrandom::getrandom(&mut random) {
+        Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64,
+        Err(_) => 1.0,
+    };
+    delay.mul_f64(0.5 + jitter / 2.0)
+}
+
 fn is_valid_sock_path(path: &str) -> bool {
     let path = Path::new(path);
-    path.exists() && path.extension().map_or(false, |ext| ext == "sock")
+    path.exists() && path.extension().is_some_and(|ext| ext == "sock")
 }
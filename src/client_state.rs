@@ -1,21 +1,54 @@
 use indoc::{indoc, printdoc};
-use log::error;
+use log::{error, info};
 use uuid::Uuid;
 
 use crate::{
-    protocol::{ClientMessage, ClientRequestError, ServerMessage},
-    validation::is_valid_word,
+    auth::{derive_key, login_proof},
+    protocol::{
+        negotiate_capabilities, negotiate_version, Capabilities, ClientMessage, ClientRequestError,
+        EndReason, GameMode, LetterFeedback, MatchLimits, MatchOutcome, PasswordChallenge,
+        PlayerStats, ServerMessage, GAME_MODES_VERSION, HANGMAN_VERSION, LIMITS_VERSION,
+        OUTCOME_VERSION, PROTOCOL_VERSION, STATS_VERSION, SUPPORTED_CAPABILITIES,
+    },
 };
 
+/// Upper bound of PBKDF2 iterations asked by the server.
+/// Higher counts would keep the client busy for minutes, the server uses 100 000
+const MAX_KEY_ITERATIONS: u32 = 5_000_000;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum State {
     Initial,
-    WaitingForPassword,
-    SendPassword(String),
+    /// Server has introduced itself, answer with our own `Hello`
+    SendHello,
+    WaitingForHandshake,
+    WaitingForUsername,
+    /// (username)
+    SendUsername(String),
+    WaitingForChallenge,
+    WaitingForPassword(PasswordChallenge),
+    /// (proof)
+    SendPassword(Vec<u8>),
     WaitingForPasswordValidation,
+    /// (resume_token)
+    SendResume(Vec<u8>),
+    WaitingForResume,
     MainMenu,
     ChoosingOpponent(Vec<Uuid>),
-    ChallengePlayer(Uuid),
+    /// (opponent_id)
+    ChoosingGameMode(Uuid),
+    /// (opponent_id, game_mode)
+    ChoosingLimits(Uuid, GameMode),
+    /// (opponent_id, game_mode, limits)
+    ChallengePlayer(Uuid, GameMode, MatchLimits),
+    /// Challenger waits for the opponent to answer the invite
+    /// (invite_id)
+    WaitingForOpponent(Uuid),
+    /// Player has been invited to a match
+    /// (challenger_id, invite_id, game_mode, limits)
+    RespondingToInvite(Uuid, Uuid, GameMode, MatchLimits),
+    /// Invite has been accepted, waiting for the match to start
+    JoiningMatch,
     InGameChallenger(Uuid),
     InGameGuesser(Uuid),
     /// Quit the application with goodbye msg
@@ -23,10 +56,40 @@ pub enum State {
     Quit,
 }
 
+/// Credentials of the latest login used to log in again after reconnecting.
+/// Only the key derived from the password is kept
+#[derive(Debug, Clone)]
+pub struct SavedLogin {
+    pub username: String,
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub key: Vec<u8>,
+}
+
+impl SavedLogin {
+    /// Key is still valid if the account uses the same salt and iterations
+    fn answers(&self, challenge: &PasswordChallenge) -> bool {
+        self.salt == challenge.salt && self.iterations == challenge.iterations
+    }
+}
+
 #[derive(Debug)]
 pub struct ClientState {
     pub player_id: Option<Uuid>,
     pub status: State,
+    /// Protocol version negotiated with the server, features of newer versions are not offered
+    pub protocol_version: u32,
+    /// Capabilities supported by both the server and this client
+    pub capabilities: Capabilities,
+    /// Username announced to the server in the current login attempt
+    pub username: Option<String>,
+    pub saved_login: Option<SavedLogin>,
+    /// Token from the latest `AssignId` to resume the session with after reconnecting
+    pub resume_token: Option<Vec<u8>>,
+    /// Match the player was in when the connection dropped, restored if the session is resumed
+    pub interrupted_match: Option<State>,
+    /// Latest guess sent to the server, shown with the letter feedback
+    pub last_guess: Option<String>,
 }
 
 impl Default for ClientState {
@@ -34,23 +97,85 @@ impl Default for ClientState {
         Self {
             player_id: None,
             status: State::Initial,
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+            username: None,
+            saved_login: None,
+            resume_token: None,
+            interrupted_match: None,
+            last_guess: None,
         }
     }
 }
 
 impl ClientState {
-    /// Process message from server
-    pub fn update_from_server(&mut self, msg: ServerMessage) {
+    /// Process message from server and optionally answer it right away
+    pub fn update_from_server(&mut self, msg: ServerMessage) -> Option<ClientMessage> {
         match msg {
-            ServerMessage::AskPassword => {
-                self.status = State::WaitingForPassword;
+            ServerMessage::Hello(version, capabilities) => {
+                if let Some(version) = negotiate_version(version) {
+                    self.protocol_version = version;
+                    self.capabilities = negotiate_capabilities(version, capabilities);
+                    info!(
+                        "Using protocol v{version} with capabilities: {}",
+                        self.capabilities
+                    );
+                    self.status = State::SendHello;
+                } else {
+                    self.status = State::Disconnect(format!(
+                        "Server speaks protocol version {version} which is no longer supported by this client."
+                    ));
+                }
+            }
+            // After reconnecting, try to resume the session first and fall back to the saved login
+            ServerMessage::AskUsername => {
+                self.status = if let Some(resume_token) = self.resume_token.take() {
+                    State::SendResume(resume_token)
+                } else if let Some(login) = &self.saved_login {
+                    State::SendUsername(login.username.clone())
+                } else {
+                    State::WaitingForUsername
+                };
+            }
+            ServerMessage::AskPassword(challenge) => {
+                if challenge.iterations > MAX_KEY_ITERATIONS {
+                    self.status = State::Disconnect(format!(
+                        "Server asks for {} password hashing iterations, refusing to log in.",
+                        challenge.iterations
+                    ));
+                    return None;
+                }
+                self.status = match &self.saved_login {
+                    Some(login) if login.answers(&challenge) => {
+                        State::SendPassword(login_proof(&login.key, &challenge.nonce))
+                    }
+                    _ => State::WaitingForPassword(challenge),
+                };
             }
             ServerMessage::WrongPassword => {
-                self.status = State::Disconnect("Wrong password. Please try again!".to_string());
+                // Saved credentials are no longer valid, user has to type them again
+                self.saved_login = None;
+                // Server either asks for the password again or disconnects us
+                printdoc! {"
+                    Wrong username or password.
+
+                "}
             }
-            ServerMessage::AssignId(id) => {
+            ServerMessage::AssignId(id, resume_token) => {
                 self.player_id = Some(id);
-                self.status = State::MainMenu;
+                // Servers older than session resumption send no token
+                self.resume_token = (!resume_token.is_empty()).then_some(resume_token);
+                let interrupted_match = self.interrupted_match.take();
+                self.status = match (&self.status, interrupted_match) {
+                    (State::WaitingForResume, Some(interrupted_match)) => {
+                        printdoc! {"
+                            Reconnected, you are back in the match.
+
+                        "}
+                        interrupted_match
+                    }
+                    _ => State::MainMenu,
+                };
             }
             ServerMessage::BadRequest(client_err) => match client_err {
                 ClientRequestError::CannotCreateMatch => {
@@ -75,6 +200,87 @@ impl ClientState {
 
                     "}
                 }
+                ClientRequestError::UnknownRequest(opcode) => {
+                    let opcode = opcode
+                        .map(|opcode| format!("{opcode:#04x}"))
+                        .unwrap_or("empty".to_string());
+                    printdoc! {"
+                        Server didn't understand the request ({opcode}).
+                        Client and server are probably out of sync, consider restarting the client.
+
+                    "}
+                }
+                ClientRequestError::TooManyAttempts => {
+                    self.status = State::Disconnect(
+                        "Too many failed attempts. You have been disconnected by the server."
+                            .to_string(),
+                    );
+                }
+                ClientRequestError::NotAuthenticated => {
+                    printdoc! {"
+                        You need to authenticate before you can play.
+
+                    "}
+                }
+                ClientRequestError::AccountInUse => {
+                    printdoc! {"
+                        This account is already logged in from another client.
+
+                    "}
+                }
+                ClientRequestError::InvalidWord(reason) => match self.status {
+                    State::InGameGuesser(_) => {
+                        printdoc! {"
+                            The guess was rejected, {reason}. It doesn't count as an attempt.
+                            Try again:
+
+                        "}
+                    }
+                    _ => {
+                        printdoc! {"
+                            The word was rejected, {reason}.
+                            Please specify another word:
+
+                        "}
+                    }
+                },
+                ClientRequestError::UnknownWord => match self.status {
+                    State::InGameGuesser(_) => {
+                        printdoc! {"
+                            That's not a word the server knows, it doesn't count as an attempt.
+                            Try again:
+
+                        "}
+                    }
+                    _ => {
+                        printdoc! {"
+                            That's not a word the server knows.
+                            Please specify another word:
+
+                        "}
+                    }
+                },
+                ClientRequestError::InviteNotFound => {
+                    printdoc! {"
+                        This invitation is no longer valid.
+
+                    "}
+
+                    self.status = State::MainMenu;
+                }
+                ClientRequestError::ResumeFailed => {
+                    // Server asks for the login next, matches of the expired session are gone
+                    self.interrupted_match = None;
+                    printdoc! {"
+                        Previous session has expired, logging in again.
+
+                    "}
+                }
+                ClientRequestError::UnsupportedVersion => {
+                    self.status = State::Disconnect(
+                        "This client is too old for the server. Please update it.".to_string(),
+                    );
+                }
             },
             ServerMessage::ListOpponents(opponents) => {
                 if opponents.is_empty() {
@@ -86,7 +292,8 @@ impl ClientState {
 
                         (0) Quit
                         (1) List and challenge available opponents
-                    "}
+                    "};
+                    self.print_stats_actions();
                 } else {
                     self.status = State::ChoosingOpponent(opponents.clone());
                     let text_block = opponents
@@ -106,6 +313,46 @@ impl ClientState {
                     "};
                 }
             }
+            ServerMessage::InviteSent(invite_id) => {
+                self.status = State::WaitingForOpponent(invite_id);
+            }
+            ServerMessage::MatchInvite(challenger, invite_id, mode, limits) => {
+                // Players in the middle of something else, e.g. waiting for their own invite,
+                // would lose track of it. Challenger learns right away instead of when the invite expires
+                if self.status != State::MainMenu {
+                    printdoc! {"
+                        Player {challenger} has challenged you, the challenge was declined as you are busy.
+
+                    "}
+                    return Some(ClientMessage::DeclineInvite(invite_id));
+                }
+                self.status = State::RespondingToInvite(challenger, invite_id, mode, limits);
+            }
+            ServerMessage::InviteDeclined(invite_id) => {
+                if self.status == State::WaitingForOpponent(invite_id) {
+                    printdoc! {"
+                        Your opponent has declined the challenge.
+
+                    "}
+                    self.status = State::MainMenu;
+                }
+            }
+            ServerMessage::InviteCancelled(invite_id) => {
+                let is_current_invite = match self.status {
+                    State::WaitingForOpponent(id) | State::RespondingToInvite(_, id, ..) => {
+                        id == invite_id
+                    }
+                    State::JoiningMatch => true,
+                    _ => false,
+                };
+                if is_current_invite {
+                    printdoc! {"
+                        The invitation has expired.
+
+                    "}
+                    self.status = State::MainMenu;
+                }
+            }
             ServerMessage::MatchAccepted(id) => {
                 printdoc! {"
                     Match between you and your opponent has started.
@@ -125,20 +372,55 @@ impl ClientState {
 
                 self.status = State::InGameGuesser(id);
             }
-            ServerMessage::MatchAttempt(_id, attempts, hints, latest_attempt) => {
+            ServerMessage::MatchAttempt(_id, attempts, hints, latest_attempt, feedback) => {
+                let latest_attempt = render_guess(&latest_attempt, &feedback);
                 printdoc! {"
                     Opponent has guessed {latest_attempt}.
                     They've made {attempts} attempts so far and you've given them {hints} hints.
 
                 "}
             }
-            ServerMessage::IncorrectGuess(_id, attempts) => {
+            ServerMessage::IncorrectGuess(_id, attempts, feedback) => {
+                if let Some(guess) = self.last_guess.as_deref().filter(|_| !feedback.is_empty()) {
+                    println!("{}", render_guess(guess, &feedback));
+                }
                 printdoc! {"
                     Incorrect. So far, you've made {attempts} attempts.
                     Try again! (Remember you can always `give up`)
 
                 "}
             }
+            ServerMessage::HangmanProgress(_id, attempts, mask, lives, tried_letters) => {
+                let mask = mask
+                    .chars()
+                    .map(String::from)
+                    .collect::<Vec<String>>()
+                    .join(" ");
+                let tried_letters = if tried_letters.is_empty() {
+                    "none".to_string()
+                } else {
+                    tried_letters
+                        .chars()
+                        .map(String::from)
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                };
+                if matches!(self.status, State::InGameChallenger(_)) {
+                    printdoc! {"
+                        Opponent has made {attempts} attempts, {lives} lives left.
+                        Word: {mask}
+                        Tried letters: {tried_letters}
+
+                    "}
+                } else {
+                    printdoc! {"
+                        Word: {mask}
+                        Lives left: {lives}, tried letters: {tried_letters}
+                        Guess a letter or the whole word (Remember you can always `give up`)
+
+                    "}
+                }
+            }
             ServerMessage::MatchHint(_id, hint) => {
                 printdoc! {"
                     Challenger provides a hint:
@@ -146,44 +428,143 @@ impl ClientState {
 
                 "}
             }
-            ServerMessage::MatchEnded(_id, attempts, hints, is_solved) => {
+            ServerMessage::MatchEnded(_id, outcome) => {
+                let MatchOutcome {
+                    reason,
+                    attempts,
+                    hints,
+                    word,
+                    duration_secs,
+                } = outcome;
+                // Servers before match outcomes don't say what the word was and how long it took
+                let (word, duration) = if self.protocol_version >= OUTCOME_VERSION {
+                    (
+                        format!("The word was {word}. "),
+                        format!(" in {}", format_duration(duration_secs)),
+                    )
+                } else {
+                    (String::new(), String::new())
+                };
                 if matches!(self.status, State::InGameChallenger(_)) {
-                    let solved_msg = if is_solved {
-                        "Your opponent has guessed the right word!"
-                    } else {
-                        "Your opponent has given up"
+                    let solved_msg = match reason {
+                        EndReason::Solved => "Your opponent has guessed the right word!",
+                        EndReason::GivenUp => "Your opponent has given up",
+                        EndReason::GuesserLeft => "Your opponent has left the game",
+                        EndReason::OutOfLives => "Your opponent has run out of lives",
+                        EndReason::OutOfAttempts => "Your opponent has run out of attempts",
+                        EndReason::TimedOut => "Your opponent has run out of time",
+                        EndReason::Cancelled | EndReason::ChallengerLeft => {
+                            "The match has been cancelled by the server"
+                        }
                     };
                     printdoc! {"
                         {solved_msg}
-                        They took {attempts} attempts. You've given them {hints} hints.
+                        They took {attempts} attempts{duration}. You've given them {hints} hints.
 
                     "}
                 } else {
-                    let solved_msg = if is_solved {
-                        "Congratulations!!! You have guessed the correct word!"
-                    } else {
-                        // FIXME match can be cancelled by challenger disconnecting
-                        "It's OK to admit defeat, better luck next time"
+                    let solved_msg = match reason {
+                        EndReason::Solved => {
+                            "Congratulations!!! You have guessed the correct word!"
+                        }
+                        EndReason::GivenUp | EndReason::GuesserLeft => {
+                            "It's OK to admit defeat, better luck next time"
+                        }
+                        EndReason::ChallengerLeft => "Your opponent has left the game",
+                        EndReason::OutOfLives => "You have run out of lives, better luck next time",
+                        EndReason::OutOfAttempts => {
+                            "You have run out of attempts, better luck next time"
+                        }
+                        EndReason::TimedOut => "You have run out of time, better luck next time",
+                        EndReason::Cancelled => "The match has been cancelled by the server",
                     };
                     printdoc! {"
-                       {solved_msg}
-                           
-                       "}
+                        {solved_msg}
+                        {word}You have made {attempts} attempts{duration}.
+
+                    "}
                 }
                 self.status = State::MainMenu;
             }
+            ServerMessage::PlayerStats(player_id, stats) => {
+                let whose = if self.player_id == Some(player_id) {
+                    "Your statistics".to_string()
+                } else {
+                    format!("Statistics of player {player_id}")
+                };
+                let summary = describe_stats(&stats);
+                printdoc! {"
+
+                    {whose}:
+                    Matches played as guesser: {}, as challenger: {}
+                    {summary}
+                    Average attempts: {:.1}, average hints: {:.1}
+
+                ",
+                    stats.games_as_guesser,
+                    stats.games_as_challenger,
+                    per_guesser_game(&stats, stats.attempts),
+                    per_guesser_game(&stats, stats.hints),
+                }
+            }
+            ServerMessage::Leaderboard(players) => {
+                let text_block = if players.is_empty() {
+                    "No matches have been played yet.".to_string()
+                } else {
+                    players
+                        .iter()
+                        .enumerate()
+                        .map(|(idx, (player_id, stats))| {
+                            let you = if self.player_id == Some(*player_id) {
+                                " (you)"
+                            } else {
+                                ""
+                            };
+                            format!(
+                                "({}) - {player_id}{you}\n    {}",
+                                idx + 1,
+                                describe_stats(stats)
+                            )
+                        })
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                };
+                printdoc! {"
+
+                    Leaderboard:
+
+                    {text_block}
+
+                "}
+            }
+            // Answered right away by the main loop
+            ServerMessage::Ping | ServerMessage::Pong => {}
             ServerMessage::Disconnect => {
                 self.status = State::Quit;
             }
         }
+        None
     }
 
     /// Update the state and optionally send a new message to the server if appropriate
     pub fn update_from_user(&mut self, input: &str) -> Option<ClientMessage> {
         let status = &self.status.clone();
         match status {
-            State::WaitingForPassword => {
-                self.status = State::SendPassword(input.to_string());
+            State::WaitingForUsername => {
+                self.status = State::SendUsername(input.trim().to_string());
+                None
+            }
+            State::WaitingForPassword(challenge) => {
+                // Only the proof derived from the password is sent to the server
+                let key = derive_key(input, &challenge.salt, challenge.iterations);
+                self.status = State::SendPassword(login_proof(&key, &challenge.nonce));
+                // Kept for logging in again after reconnecting, dropped on `WrongPassword`
+                self.saved_login = self.username.clone().map(|username| SavedLogin {
+                    username,
+                    salt: challenge.salt.clone(),
+                    iterations: challenge.iterations,
+                    key,
+                });
                 None
             }
             State::MainMenu => match input {
@@ -203,6 +584,13 @@ impl ClientState {
                     "};
                     Some(ClientMessage::GetOpponents)
                 }
+                // Servers before statistics don't offer them
+                "2" if self.protocol_version >= STATS_VERSION => {
+                    self.player_id.map(ClientMessage::GetStats)
+                }
+                "3" if self.protocol_version >= STATS_VERSION => {
+                    Some(ClientMessage::GetLeaderboard)
+                }
                 _ => {
                     printdoc! {
                         "Invalid input"
@@ -220,11 +608,29 @@ impl ClientState {
                     .ok()
                     .and_then(|input_idx| opponents.get(input_idx - 1));
                 if let Some(challenged_player) = challenged_player {
-                    self.status = State::ChallengePlayer(*challenged_player);
+                    if self.protocol_version < GAME_MODES_VERSION {
+                        // Server only knows classic matches
+                        self.status = State::ChallengePlayer(
+                            *challenged_player,
+                            GameMode::Classic,
+                            MatchLimits::default(),
+                        );
+                        printdoc! {"
+                            Specify word to guess:
+
+                        "};
+                        return None;
+                    }
+                    self.status = State::ChoosingGameMode(*challenged_player);
                     printdoc! {"
-                        Specify word to guess:
+                        Choose the game mode:
 
+                        (1) Classic - guess the whole word
+                        (2) Wordle - every guess reveals which letters are in the word
                     "};
+                    if self.protocol_version >= HANGMAN_VERSION {
+                        println!("(3) Hangman - reveal the word letter by letter before running out of lives");
+                    }
                     None
                 } else {
                     let text_block = opponents
@@ -249,17 +655,82 @@ impl ClientState {
                 }
             }
 
-            State::ChallengePlayer(opponent) => {
-                if is_valid_word(input) {
-                    Some(ClientMessage::RequestMatch(*opponent, input.to_string()))
-                } else {
+            State::ChoosingGameMode(opponent) => {
+                let mode = match input {
+                    "1" => GameMode::Classic,
+                    "2" => GameMode::Wordle,
+                    "3" if self.protocol_version >= HANGMAN_VERSION => GameMode::Hangman,
+                    _ if self.protocol_version < HANGMAN_VERSION => {
+                        printdoc! {"
+                            Invalid input. Type 1 for classic or 2 for wordle mode.
+
+                        "};
+                        return None;
+                    }
+                    _ => {
+                        printdoc! {"
+                            Invalid input. Type 1 for classic, 2 for wordle or 3 for hangman mode.
+
+                        "};
+                        return None;
+                    }
+                };
+                if self.protocol_version < LIMITS_VERSION {
+                    // Server only knows matches without limits
+                    self.status = State::ChallengePlayer(*opponent, mode, MatchLimits::default());
                     printdoc! {"
-                        Please specify a single word with only alphabetic lowercase characters.
+                        Specify word to guess:
 
                     "};
-                    None
+                    return None;
                 }
+                self.status = State::ChoosingLimits(*opponent, mode);
+                printdoc! {"
+                    Specify limits of the match as max attempts, time limit in seconds
+                    and seconds per guess, e.g. `10 300 30`. Use 0 or leave empty for no limit:
+
+                "};
+                None
+            }
+            State::ChoosingLimits(opponent, mode) => {
+                let Some(limits) = parse_limits(input) else {
+                    printdoc! {"
+                        Invalid input. Type up to three numbers separated by spaces.
+
+                    "};
+                    return None;
+                };
+                self.status = State::ChallengePlayer(*opponent, *mode, limits);
+                printdoc! {"
+                    Specify word to guess:
+
+                "};
+                None
             }
+            State::RespondingToInvite(_, invite_id, ..) => match input {
+                "0" => {
+                    self.status = State::MainMenu;
+                    Some(ClientMessage::DeclineInvite(*invite_id))
+                }
+                "1" => {
+                    self.status = State::JoiningMatch;
+                    Some(ClientMessage::AcceptInvite(*invite_id))
+                }
+                _ => {
+                    printdoc! {"
+                        Invalid input. Type 1 to accept or 0 to decline the challenge.
+
+                    "};
+                    None
+                }
+            },
+            // Server checks the word against its rules and answers with `InvalidWord`
+            State::ChallengePlayer(opponent, mode, limits) => Some(ClientMessage::RequestMatch(
+                *opponent,
+                input.to_string(),
+                *mode,
+                *limits,
+            )),
             State::InGameChallenger(match_id) => {
                 Some(ClientMessage::SendHint(*match_id, input.to_string()))
             }
@@ -267,6 +738,7 @@ impl ClientState {
                 if input.eq("give up") {
                     return Some(ClientMessage::GiveUp(*match_id));
                 }
+                self.last_guess = Some(input.to_string());
                 Some(ClientMessage::GuessAttempt(*match_id, input.to_string()))
             }
             _ => {
@@ -279,34 +751,114 @@ impl ClientState {
         }
     }
 
+    /// Whether the client should connect again after the server went away
+    /// Clients that are quitting or were disconnected on purpose don't come back
+    pub fn should_reconnect(&self) -> bool {
+        !matches!(self.status, State::Disconnect(_) | State::Quit)
+    }
+
+    /// Start over on a new connection after the previous one dropped
+    /// Match in progress is restored if the server resumes the session
+    pub fn reset_connection(&mut self) {
+        if matches!(
+            self.status,
+            State::InGameChallenger(_) | State::InGameGuesser(_)
+        ) {
+            self.interrupted_match = Some(self.status.clone());
+        }
+        self.status = State::Initial;
+        self.protocol_version = PROTOCOL_VERSION;
+        self.capabilities = Capabilities::NONE;
+    }
+
+    /// Main menu entries of statistics, servers before them don't answer these requests
+    fn print_stats_actions(&self) {
+        if self.protocol_version >= STATS_VERSION {
+            printdoc! {"
+                (2) Show your statistics
+                (3) Show leaderboard
+            "};
+        }
+    }
+
     /// Process state changes
     pub fn process(&mut self) -> Option<ClientMessage> {
         let status = &self.status.clone();
         match status {
             State::Initial
+            | State::WaitingForHandshake
+            | State::WaitingForChallenge
             | State::WaitingForPasswordValidation
+            | State::WaitingForResume
+            | State::JoiningMatch
             | State::ChoosingOpponent(_)
-            | State::ChallengePlayer(_)
+            | State::ChoosingGameMode(_)
+            | State::ChoosingLimits(..)
+            | State::ChallengePlayer(..)
             | State::InGameChallenger(_)
             | State::InGameGuesser(_)
             | State::Quit => None,
 
-            State::WaitingForPassword => {
+            State::SendHello => {
+                self.status = State::WaitingForHandshake;
+                Some(ClientMessage::Hello(
+                    PROTOCOL_VERSION,
+                    SUPPORTED_CAPABILITIES,
+                ))
+            }
+            State::WaitingForUsername => {
                 printdoc! {"
 
                         Welcome to WordGuesser.
-                        Please authenticate yourself with a _not really secret_ **password**.
-                    
+                        Please log in with your account.
+
+                        Username:
                 "};
                 None
             }
-            State::SendPassword(password) => {
+            State::WaitingForPassword(_) => {
                 printdoc! {"
-                    Attempting to authenticate with provided password
+                        Password:
+                "};
+                None
+            }
+            State::SendUsername(username) => {
+                self.username = Some(username.to_string());
+                self.status = State::WaitingForChallenge;
+                Some(ClientMessage::AnswerUsername(username.to_string()))
+            }
+            State::SendResume(resume_token) => {
+                self.status = State::WaitingForResume;
+                Some(ClientMessage::Resume(resume_token.clone()))
+            }
+            State::SendPassword(proof) => {
+                printdoc! {"
+                    Attempting to authenticate with provided credentials
 
                 "};
                 self.status = State::WaitingForPasswordValidation;
-                Some(ClientMessage::AnswerPassword(password.to_string()))
+                Some(ClientMessage::AnswerPassword(proof.clone()))
+            }
+            State::WaitingForOpponent(_) => {
+                printdoc! {"
+                    Challenge sent. Waiting for your opponent to accept it...
+
+                "};
+                None
+            }
+            State::RespondingToInvite(challenger, _, mode, limits) => {
+                let mode = match mode {
+                    GameMode::Classic => "a classic",
+                    GameMode::Wordle => "a wordle",
+                    GameMode::Hangman => "a hangman",
+                };
+                printdoc! {"
+                    Player {challenger} has challenged you to {mode} match ({limits}).
+
+                    (0) Decline
+                    (1) Accept
+                "};
+                None
             }
             State::MainMenu => {
                 printdoc! {
@@ -316,6 +868,7 @@ impl ClientState {
                     (1) List and challenge available opponents
                     "
                 };
+                self.print_stats_actions();
                 None
             }
             State::Disconnect(reason) => {
@@ -330,3 +883,245 @@ impl ClientState {
         }
     }
 }
+
+/// Show every letter of the guess as a tile coloured by its feedback
+fn render_guess(guess: &str, feedback: &[LetterFeedback]) -> String {
+    if feedback.is_empty() {
+        return guess.to_string();
+    }
+    guess
+        .chars()
+        .zip(feedback)
+        .map(|(letter, feedback)| {
+            let colour = match feedback {
+                LetterFeedback::Correct => "30;42",
+                LetterFeedback::Present => "30;43",
+                LetterFeedback::Absent => "97;100",
+            };
+            format!("\x1b[1;{colour}m {} \x1b[0m", letter.to_uppercase())
+        })
+        .collect()
+}
+
+/// Solved matches with the solve rate and the longest streak
+fn describe_stats(stats: &PlayerStats) -> String {
+    format!(
+        "Solved {} of {} matches ({:.0} %), longest streak: {}",
+        stats.solved,
+        stats.games_as_guesser,
+        per_guesser_game(stats, stats.solved) * 100.0,
+        stats.longest_streak
+    )
+}
+
+/// Average of the total over matches played as the guesser
+fn per_guesser_game(stats: &PlayerStats, total: u32) -> f64 {
+    if stats.games_as_guesser == 0 {
+        0.0
+    } else {
+        f64::from(total) / f64::from(stats.games_as_guesser)
+    }
+}
+
+/// Human readable duration of a match, e.g. `2 min 5 s`
+fn format_duration(secs: u32) -> String {
+    match (secs / 60, secs % 60) {
+        (0, secs) => format!("{secs} s"),
+        (mins, 0) => format!("{mins} min"),
+        (mins, secs) => format!("{mins} min {secs} s"),
+    }
+}
+
+/// Parse `max_attempts time_limit_secs guess_timeout_secs`, missing values mean no limit
+fn parse_limits(input: &str) -> Option<MatchLimits> {
+    let values = input
+        .split_whitespace()
+        .map(str::parse)
+        .collect::<Result<Vec<u32>, _>>()
+        .ok()?;
+    if values.len() > 3 {
+        return None;
+    }
+    let value = |idx: usize| values.get(idx).copied().unwrap_or(0);
+    Some(MatchLimits {
+        max_attempts: value(0),
+        time_limit_secs: value(1),
+        guess_timeout_secs: value(2),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_in(status: State) -> ClientState {
+        ClientState {
+            player_id: Some(Uuid::new_v4()),
+            status,
+            ..ClientState::default()
+        }
+    }
+
+    #[test]
+    fn invite_is_shown_in_main_menu() {
+        let mut client = logged_in(State::MainMenu);
+        let (challenger, invite_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let reply = client.update_from_server(ServerMessage::MatchInvite(
+            challenger,
+            invite_id,
+            GameMode::Wordle,
+            MatchLimits::default(),
+        ));
+
+        assert!(reply.is_none());
+        assert_eq!(
+            client.status,
+            State::RespondingToInvite(
+                challenger,
+                invite_id,
+                GameMode::Wordle,
+                MatchLimits::default()
+            )
+        );
+    }
+
+    #[test]
+    fn busy_player_declines_invite() {
+        let own_invite = Uuid::new_v4();
+        for status in [
+            State::WaitingForOpponent(own_invite),
+            State::ChoosingOpponent(vec![Uuid::new_v4()]),
+            State::ChallengePlayer(Uuid::new_v4(), GameMode::Classic, MatchLimits::default()),
+            State::InGameGuesser(Uuid::new_v4()),
+        ] {
+            let mut client = logged_in(status.clone());
+            let invite_id = Uuid::new_v4();
+            let reply = client.update_from_server(ServerMessage::MatchInvite(
+                Uuid::new_v4(),
+                invite_id,
+                GameMode::Classic,
+                MatchLimits::default(),
+            ));
+
+            assert!(matches!(reply, Some(ClientMessage::DeclineInvite(id)) if id == invite_id));
+            assert_eq!(client.status, status);
+        }
+    }
+
+    /// Go through the handshake of a new connection until the client answers `AskUsername`
+    fn reconnect(client: &mut ClientState) -> Option<ClientMessage> {
+        client.reset_connection();
+        assert_eq!(client.status, State::Initial);
+        client.update_from_server(ServerMessage::Hello(
+            PROTOCOL_VERSION,
+            SUPPORTED_CAPABILITIES,
+        ));
+        assert!(matches!(client.process(), Some(ClientMessage::Hello(..))));
+        client.update_from_server(ServerMessage::AskUsername);
+        client.process()
+    }
+
+    #[test]
+    fn resumed_session_returns_to_main_menu() {
+        let mut client = logged_in(State::ChoosingOpponent(vec![Uuid::new_v4()]));
+        client.resume_token = Some(vec![1; 16]);
+
+        let resume = reconnect(&mut client);
+        assert!(matches!(resume, Some(ClientMessage::Resume(token)) if token == [1; 16]));
+        assert_eq!(client.status, State::WaitingForResume);
+        assert_eq!(client.interrupted_match, None);
+
+        client.update_from_server(ServerMessage::AssignId(Uuid::new_v4(), vec![2; 16]));
+        assert_eq!(client.status, State::MainMenu);
+        assert_eq!(client.resume_token, Some(vec![2; 16]));
+    }
+
+    #[test]
+    fn resumed_session_returns_to_match() {
+        let match_id = Uuid::new_v4();
+        let mut client = logged_in(State::InGameGuesser(match_id));
+        client.resume_token = Some(vec![1; 16]);
+
+        reconnect(&mut client);
+        assert_eq!(
+            client.interrupted_match,
+            Some(State::InGameGuesser(match_id))
+        );
+        client.update_from_server(ServerMessage::AssignId(Uuid::new_v4(), vec![2; 16]));
+        assert_eq!(client.status, State::InGameGuesser(match_id));
+        assert_eq!(client.interrupted_match, None);
+    }
+
+    #[test]
+    fn expired_session_logs_in_again_to_main_menu() {
+        let mut client = logged_in(State::InGameChallenger(Uuid::new_v4()));
+        client.resume_token = Some(vec![1; 16]);
+        client.saved_login = Some(SavedLogin {
+            username: "alice".to_string(),
+            salt: vec![3; 16],
+            iterations: 1,
+            key: vec![4; 32],
+        });
+
+        reconnect(&mut client);
+        client.update_from_server(ServerMessage::BadRequest(ClientRequestError::ResumeFailed));
+        assert_eq!(client.interrupted_match, None);
+
+        // Server asks for the login, saved key answers the challenge without the user
+        client.update_from_server(ServerMessage::AskUsername);
+        assert!(matches!(
+            client.process(),
+            Some(ClientMessage::AnswerUsername(username)) if username == "alice"
+        ));
+        client.update_from_server(ServerMessage::AskPassword(PasswordChallenge {
+            salt: vec![3; 16],
+            iterations: 1,
+            nonce: vec![5; 16],
+        }));
+        assert!(matches!(
+            client.process(),
+            Some(ClientMessage::AnswerPassword(proof)) if proof == login_proof(&[4; 32], &[5; 16])
+        ));
+        client.update_from_server(ServerMessage::AssignId(Uuid::new_v4(), vec![2; 16]));
+        assert_eq!(client.status, State::MainMenu);
+    }
+
+    #[test]
+    fn only_dropped_connections_are_reconnected() {
+        assert!(logged_in(State::MainMenu).should_reconnect());
+        assert!(logged_in(State::InGameGuesser(Uuid::new_v4())).should_reconnect());
+        assert!(!logged_in(State::Disconnect("bye".to_string())).should_reconnect());
+        assert!(!logged_in(State::Quit).should_reconnect());
+    }
+
+    #[test]
+    fn limits_are_parsed_in_order_with_missing_ones_unlimited() {
+        assert_eq!(parse_limits(""), Some(MatchLimits::default()));
+        assert_eq!(
+            parse_limits(" 10  300 "),
+            Some(MatchLimits {
+                max_attempts: 10,
+                time_limit_secs: 300,
+                guess_timeout_secs: 0,
+            })
+        );
+        assert_eq!(
+            parse_limits("0 0 30"),
+            Some(MatchLimits {
+                guess_timeout_secs: 30,
+                ..MatchLimits::default()
+            })
+        );
+        assert_eq!(parse_limits("1 2 3 4"), None);
+        assert_eq!(parse_limits("ten"), None);
+        assert_eq!(parse_limits("-1"), None);
+    }
+
+    #[test]
+    fn durations_are_shown_in_minutes_and_seconds() {
+        assert_eq!(format_duration(0), "0 s");
+        assert_eq!(format_duration(59), "59 s");
+        assert_eq!(format_duration(120), "2 min");
+        assert_eq!(format_duration(125), "2 min 5 s");
+    }
+}
@@ -0,0 +1,1330 @@
+//! Compact binary encoding of `ServerMessage` and `ClientMessage`.
+//!
+//! Every message is a single frame payload (see `framing`) with the following layout:
+//!
+//! ```text
+//! +--------+----------------------+
+//! | opcode | fields...            |
+//! | u8     | in declaration order |
+//! +--------+----------------------+
+//! ```
+//!
+//! Field types are encoded as:
+//!
+//! - `uuid`   - raw 16 bytes, big endian (RFC 4122 byte order)
+//! - `varint` - unsigned LEB128, at most 5 bytes for `u32`
+//! - `bool`   - single byte, `0x00` or `0x01`
+//! - `string` - `varint` byte length followed by UTF-8 bytes
+//! - `bytes`  - `varint` length followed by raw bytes
+//! - `list`   - `varint` item count followed by the items
+//! - `mode`   - single byte `GameMode`
+//! - `letter` - single byte `LetterFeedback`
+//! - `reason` - single byte `EndReason`
+//! - `limits` - `MatchLimits` as three `varint`s in declaration order
+//! - `stats`  - `PlayerStats` as six `varint`s in declaration order
+//!
+//! Messages are encoded for the protocol version negotiated by `Hello`.
+//! Fields and messages added after that version are left out and get their default values
+//! when decoded, opcodes the version doesn't know are rejected.
+//!
+//! Full table of opcodes is documented in `PROTOCOL.md`.
+
+use std::fmt;
+
+use uuid::Uuid;
+
+use crate::{
+    framing::write_varint,
+    protocol::{
+        Capabilities, ClientMessage, ClientRequestError, EndReason, GameMode, LetterFeedback,
+        MatchLimits, MatchOutcome, PasswordChallenge, PlayerStats, ServerMessage,
+        DICTIONARY_VERSION, GAME_MODES_VERSION, HANGMAN_VERSION, HEARTBEAT_VERSION,
+        INVITES_VERSION, LIMITS_VERSION, OUTCOME_VERSION, RESUME_VERSION, STATS_VERSION,
+        WORD_RULES_VERSION,
+    },
+};
+
+// `Hello` has the same opcode in both directions.
+// Its layout must never change so peers of any version can negotiate
+const OP_HELLO: u8 = 0x00;
+
+// Server -> client opcodes
+const OP_ASK_PASSWORD: u8 = 0x01;
+const OP_WRONG_PASSWORD: u8 = 0x02;
+const OP_ASSIGN_ID: u8 = 0x03;
+const OP_BAD_REQUEST: u8 = 0x04;
+const OP_LIST_OPPONENTS: u8 = 0x05;
+const OP_MATCH_ACCEPTED: u8 = 0x06;
+const OP_MATCH_STARTED: u8 = 0x07;
+const OP_MATCH_ATTEMPT: u8 = 0x08;
+const OP_INCORRECT_GUESS: u8 = 0x09;
+const OP_MATCH_HINT: u8 = 0x0a;
+const OP_MATCH_ENDED: u8 = 0x0b;
+const OP_DISCONNECT: u8 = 0x0c;
+const OP_ASK_USERNAME: u8 = 0x0d;
+const OP_SERVER_PING: u8 = 0x0e;
+const OP_SERVER_PONG: u8 = 0x0f;
+const OP_MATCH_INVITE: u8 = 0x10;
+const OP_INVITE_SENT: u8 = 0x11;
+const OP_INVITE_DECLINED: u8 = 0x12;
+const OP_INVITE_CANCELLED: u8 = 0x13;
+const OP_HANGMAN_PROGRESS: u8 = 0x14;
+const OP_PLAYER_STATS: u8 = 0x15;
+const OP_LEADERBOARD: u8 = 0x16;
+
+// Client -> server opcodes
+const OP_ANSWER_PASSWORD: u8 = 0x01;
+const OP_GET_OPPONENTS: u8 = 0x02;
+const OP_REQUEST_MATCH: u8 = 0x03;
+const OP_GUESS_ATTEMPT: u8 = 0x04;
+const OP_SEND_HINT: u8 = 0x05;
+const OP_GIVE_UP: u8 = 0x06;
+const OP_LEAVE_GAME: u8 = 0x07;
+const OP_ANSWER_USERNAME: u8 = 0x08;
+const OP_CLIENT_PING: u8 = 0x09;
+const OP_CLIENT_PONG: u8 = 0x0a;
+const OP_RESUME: u8 = 0x0b;
+const OP_ACCEPT_INVITE: u8 = 0x0c;
+const OP_DECLINE_INVITE: u8 = 0x0d;
+const OP_GET_STATS: u8 = 0x0e;
+const OP_GET_LEADERBOARD: u8 = 0x0f;
+
+// `ClientRequestError` codes carried by `BadRequest`
+const ERR_CANNOT_CREATE_MATCH: u8 = 0x01;
+const ERR_MATCH_404: u8 = 0x02;
+const ERR_PERMISSION_DENIED: u8 = 0x03;
+const ERR_UNSUPPORTED_VERSION: u8 = 0x04;
+const ERR_UNKNOWN_REQUEST: u8 = 0x05;
+const ERR_TOO_MANY_ATTEMPTS: u8 = 0x06;
+const ERR_NOT_AUTHENTICATED: u8 = 0x07;
+const ERR_ACCOUNT_IN_USE: u8 = 0x08;
+const ERR_RESUME_FAILED: u8 = 0x09;
+const ERR_INVITE_NOT_FOUND: u8 = 0x0a;
+const ERR_INVALID_WORD: u8 = 0x0b;
+const ERR_UNKNOWN_WORD: u8 = 0x0c;
+
+const MODE_CLASSIC: u8 = 0x00;
+const MODE_WORDLE: u8 = 0x01;
+const MODE_HANGMAN: u8 = 0x02;
+
+const LETTER_ABSENT: u8 = 0x00;
+const LETTER_PRESENT: u8 = 0x01;
+const LETTER_CORRECT: u8 = 0x02;
+
+const END_SOLVED: u8 = 0x00;
+const END_GIVEN_UP: u8 = 0x01;
+const END_CANCELLED: u8 = 0x02;
+const END_OUT_OF_LIVES: u8 = 0x03;
+const END_OUT_OF_ATTEMPTS: u8 = 0x04;
+const END_TIMED_OUT: u8 = 0x05;
+const END_GUESSER_LEFT: u8 = 0x06;
+const END_CHALLENGER_LEFT: u8 = 0x07;
+
+/// Reasons why a frame payload couldn't be decoded into a message
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Empty,
+    UnknownOpcode(u8),
+    UnknownErrorCode(u8),
+    UnknownGameMode(u8),
+    UnknownLetterFeedback(u8),
+    UnknownEndReason(u8),
+    UnexpectedEnd,
+    InvalidVarint,
+    InvalidBool(u8),
+    InvalidUtf8,
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Empty => write!(f, "empty message"),
+            DecodeError::UnknownOpcode(op) => write!(f, "unknown opcode {op:#04x}"),
+            DecodeError::UnknownErrorCode(code) => write!(f, "unknown error code {code:#04x}"),
+            DecodeError::UnknownGameMode(mode) => write!(f, "unknown game mode {mode:#04x}"),
+            DecodeError::UnknownLetterFeedback(letter) => {
+                write!(f, "unknown letter feedback {letter:#04x}")
+            }
+            DecodeError::UnknownEndReason(reason) => write!(f, "unknown end reason {reason:#04x}"),
+            DecodeError::UnexpectedEnd => write!(f, "message ended unexpectedly"),
+            DecodeError::InvalidVarint => write!(f, "invalid varint"),
+            DecodeError::InvalidBool(byte) => write!(f, "invalid bool value {byte:#04x}"),
+            DecodeError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            DecodeError::TrailingBytes(n) => write!(f, "{n} unexpected trailing bytes"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Frame payload that couldn't be decoded, with the opcode it started with (if any)
+#[derive(Debug)]
+pub struct MalformedMessage {
+    pub opcode: Option<u8>,
+    pub error: DecodeError,
+}
+
+impl fmt::Display for MalformedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.opcode {
+            Some(opcode) => write!(f, "{} (opcode {opcode:#04x})", self.error),
+            None => write!(f, "{}", self.error),
+        }
+    }
+}
+
+/// Message that can be sent over the wire
+pub trait WireMessage: Sized {
+    /// Append message encoded for protocol `version` to `buf`
+    fn encode(&self, buf: &mut Vec<u8>, version: u32);
+
+    /// Decode a message of protocol `version` from a complete frame payload
+    fn decode(bytes: &[u8], version: u32) -> Result<Self, DecodeError>;
+
+    /// Decode a frame payload, remembering the opcode of malformed messages
+    fn decode_frame(bytes: &[u8], version: u32) -> Result<Self, MalformedMessage> {
+        Self::decode(bytes, version).map_err(|error| MalformedMessage {
+            opcode: bytes.first().copied(),
+            error,
+        })
+    }
+
+    fn to_bytes(&self, version: u32) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf, version);
+        buf
+    }
+}
+
+fn write_uuid(buf: &mut Vec<u8>, id: &Uuid) {
+    buf.extend_from_slice(id.as_bytes());
+}
+
+fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_bytes(buf, value.as_bytes());
+}
+
+fn write_bytes(buf: &mut Vec<u8>, value: &[u8]) {
+    write_varint(buf, value.len() as u32);
+    buf.extend_from_slice(value);
+}
+
+fn write_hello(buf: &mut Vec<u8>, version: u32, capabilities: &Capabilities) {
+    buf.push(OP_HELLO);
+    write_varint(buf, version);
+    write_varint(buf, capabilities.0);
+}
+
+fn write_bool(buf: &mut Vec<u8>, value: bool) {
+    buf.push(value as u8);
+}
+
+fn write_game_mode(buf: &mut Vec<u8>, mode: &GameMode) {
+    buf.push(match mode {
+        GameMode::Classic => MODE_CLASSIC,
+        GameMode::Wordle => MODE_WORDLE,
+        GameMode::Hangman => MODE_HANGMAN,
+    });
+}
+
+fn write_limits(buf: &mut Vec<u8>, limits: &MatchLimits) {
+    write_varint(buf, limits.max_attempts);
+    write_varint(buf, limits.time_limit_secs);
+    write_varint(buf, limits.guess_timeout_secs);
+}
+
+fn write_stats(buf: &mut Vec<u8>, stats: &PlayerStats) {
+    write_varint(buf, stats.games_as_guesser);
+    write_varint(buf, stats.games_as_challenger);
+    write_varint(buf, stats.solved);
+    write_varint(buf, stats.attempts);
+    write_varint(buf, stats.hints);
+    write_varint(buf, stats.longest_streak);
+}
+
+fn write_end_reason(buf: &mut Vec<u8>, reason: &EndReason) {
+    buf.push(match reason {
+        EndReason::Solved => END_SOLVED,
+        EndReason::GivenUp => END_GIVEN_UP,
+        EndReason::Cancelled => END_CANCELLED,
+        EndReason::OutOfLives => END_OUT_OF_LIVES,
+        EndReason::OutOfAttempts => END_OUT_OF_ATTEMPTS,
+        EndReason::TimedOut => END_TIMED_OUT,
+        EndReason::GuesserLeft => END_GUESSER_LEFT,
+        EndReason::ChallengerLeft => END_CHALLENGER_LEFT,
+    });
+}
+
+/// `MatchEnded` of versions before the whole outcome was sent.
+/// Before limits only whether the word was solved, afterwards without the reasons of players leaving
+fn write_legacy_end_reason(buf: &mut Vec<u8>, reason: &EndReason, version: u32) {
+    if version >= LIMITS_VERSION {
+        let reason = match reason {
+            EndReason::GuesserLeft | EndReason::ChallengerLeft => &EndReason::Cancelled,
+            reason => reason,
+        };
+        write_end_reason(buf, reason);
+    } else {
+        write_bool(buf, *reason == EndReason::Solved);
+    }
+}
+
+fn write_feedback(buf: &mut Vec<u8>, feedback: &[LetterFeedback]) {
+    write_varint(buf, feedback.len() as u32);
+    buf.extend(feedback.iter().map(|letter| match letter {
+        LetterFeedback::Absent => LETTER_ABSENT,
+        LetterFeedback::Present => LETTER_PRESENT,
+        LetterFeedback::Correct => LETTER_CORRECT,
+    }));
+}
+
+/// Cursor over a frame payload
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u32, DecodeError> {
+        let mut value: u32 = 0;
+        for idx in 0..5 {
+            let byte = self.u8()?;
+            if idx == 4 && byte > 0x0f {
+                return Err(DecodeError::InvalidVarint);
+            }
+            value |= ((byte & 0x7f) as u32) << (7 * idx);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::InvalidVarint)
+    }
+
+    fn bool(&mut self) -> Result<bool, DecodeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(DecodeError::InvalidBool(byte)),
+        }
+    }
+
+    fn uuid(&mut self) -> Result<Uuid, DecodeError> {
+        let bytes = self.take(16)?;
+        Ok(Uuid::from_slice(bytes).expect("slice has exactly 16 bytes"))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, DecodeError> {
+        let len = self.varint()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        String::from_utf8(self.bytes()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn capabilities(&mut self) -> Result<Capabilities, DecodeError> {
+        Ok(Capabilities(self.varint()?))
+    }
+
+    fn game_mode(&mut self) -> Result<GameMode, DecodeError> {
+        match self.u8()? {
+            MODE_CLASSIC => Ok(GameMode::Classic),
+            MODE_WORDLE => Ok(GameMode::Wordle),
+            MODE_HANGMAN => Ok(GameMode::Hangman),
+            mode => Err(DecodeError::UnknownGameMode(mode)),
+        }
+    }
+
+    /// Game mode of versions that send it, classic otherwise.
+    /// Modes newer than `version` are unknown to it
+    fn game_mode_since(&mut self, version: u32) -> Result<GameMode, DecodeError> {
+        if version < GAME_MODES_VERSION {
+            return Ok(GameMode::Classic);
+        }
+        match self.game_mode()? {
+            GameMode::Hangman if version < HANGMAN_VERSION => {
+                Err(DecodeError::UnknownGameMode(MODE_HANGMAN))
+            }
+            mode => Ok(mode),
+        }
+    }
+
+    fn limits(&mut self) -> Result<MatchLimits, DecodeError> {
+        Ok(MatchLimits {
+            max_attempts: self.varint()?,
+            time_limit_secs: self.varint()?,
+            guess_timeout_secs: self.varint()?,
+        })
+    }
+
+    /// Limits of versions that send them, no limits otherwise
+    fn limits_since(&mut self, version: u32) -> Result<MatchLimits, DecodeError> {
+        if version >= LIMITS_VERSION {
+            self.limits()
+        } else {
+            Ok(MatchLimits::default())
+        }
+    }
+
+    fn end_reason(&mut self) -> Result<EndReason, DecodeError> {
+        match self.u8()? {
+            END_SOLVED => Ok(EndReason::Solved),
+            END_GIVEN_UP => Ok(EndReason::GivenUp),
+            END_CANCELLED => Ok(EndReason::Cancelled),
+            END_OUT_OF_LIVES => Ok(EndReason::OutOfLives),
+            END_OUT_OF_ATTEMPTS => Ok(EndReason::OutOfAttempts),
+            END_TIMED_OUT => Ok(EndReason::TimedOut),
+            END_GUESSER_LEFT => Ok(EndReason::GuesserLeft),
+            END_CHALLENGER_LEFT => Ok(EndReason::ChallengerLeft),
+            reason => Err(DecodeError::UnknownEndReason(reason)),
+        }
+    }
+
+    /// Counterpart of `write_legacy_end_reason`, unsolved matches count as given up
+    fn legacy_end_reason(&mut self, version: u32) -> Result<EndReason, DecodeError> {
+        if version >= LIMITS_VERSION {
+            self.end_reason()
+        } else if self.bool()? {
+            Ok(EndReason::Solved)
+        } else {
+            Ok(EndReason::GivenUp)
+        }
+    }
+
+    /// Letter feedback of versions that send it, none otherwise
+    fn feedback_since(&mut self, version: u32) -> Result<Vec<LetterFeedback>, DecodeError> {
+        if version >= GAME_MODES_VERSION {
+            self.feedback()
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn feedback(&mut self) -> Result<Vec<LetterFeedback>, DecodeError> {
+        let count = self.varint()? as usize;
+        self.take(count)?
+            .iter()
+            .map(|letter| match *letter {
+                LETTER_ABSENT => Ok(LetterFeedback::Absent),
+                LETTER_PRESENT => Ok(LetterFeedback::Present),
+                LETTER_CORRECT => Ok(LetterFeedback::Correct),
+                letter => Err(DecodeError::UnknownLetterFeedback(letter)),
+            })
+            .collect()
+    }
+
+    fn stats(&mut self) -> Result<PlayerStats, DecodeError> {
+        Ok(PlayerStats {
+            games_as_guesser: self.varint()?,
+            games_as_challenger: self.varint()?,
+            solved: self.varint()?,
+            attempts: self.varint()?,
+            hints: self.varint()?,
+            longest_streak: self.varint()?,
+        })
+    }
+
+    fn leaderboard(&mut self) -> Result<Vec<(Uuid, PlayerStats)>, DecodeError> {
+        let count = self.varint()? as usize;
+        // Every entry needs at least 16 bytes of the uuid and 6 single byte varints
+        if self.bytes.len() < count.saturating_mul(22) {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        (0..count)
+            .map(|_| Ok((self.uuid()?, self.stats()?)))
+            .collect()
+    }
+
+    fn uuid_list(&mut self) -> Result<Vec<Uuid>, DecodeError> {
+        let count = self.varint()? as usize;
+        // Do not trust the count for allocation, every item needs 16 bytes
+        if self.bytes.len() < count.saturating_mul(16) {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        (0..count).map(|_| self.uuid()).collect()
+    }
+
+    /// Make sure the whole payload has been consumed
+    fn finish(self) -> Result<(), DecodeError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes(self.bytes.len()))
+        }
+    }
+}
+
+impl ClientRequestError {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            ClientRequestError::CannotCreateMatch => buf.push(ERR_CANNOT_CREATE_MATCH),
+            ClientRequestError::Match404 => buf.push(ERR_MATCH_404),
+            ClientRequestError::PermissionDenied => buf.push(ERR_PERMISSION_DENIED),
+            ClientRequestError::UnsupportedVersion => buf.push(ERR_UNSUPPORTED_VERSION),
+            ClientRequestError::UnknownRequest(opcode) => {
+                buf.push(ERR_UNKNOWN_REQUEST);
+                write_bool(buf, opcode.is_some());
+                if let Some(opcode) = opcode {
+                    buf.push(*opcode);
+                }
+            }
+            ClientRequestError::TooManyAttempts => buf.push(ERR_TOO_MANY_ATTEMPTS),
+            ClientRequestError::NotAuthenticated => buf.push(ERR_NOT_AUTHENTICATED),
+            ClientRequestError::AccountInUse => buf.push(ERR_ACCOUNT_IN_USE),
+            ClientRequestError::ResumeFailed => buf.push(ERR_RESUME_FAILED),
+            ClientRequestError::InviteNotFound => buf.push(ERR_INVITE_NOT_FOUND),
+            ClientRequestError::InvalidWord(reason) => {
+                buf.push(ERR_INVALID_WORD);
+                write_string(buf, reason);
+            }
+            ClientRequestError::UnknownWord => buf.push(ERR_UNKNOWN_WORD),
+        }
+    }
+
+    fn decode(reader: &mut Reader, version: u32) -> Result<Self, DecodeError> {
+        match reader.u8()? {
+            ERR_CANNOT_CREATE_MATCH => Ok(ClientRequestError::CannotCreateMatch),
+            ERR_MATCH_404 => Ok(ClientRequestError::Match404),
+            ERR_PERMISSION_DENIED => Ok(ClientRequestError::PermissionDenied),
+            ERR_UNSUPPORTED_VERSION => Ok(ClientRequestError::UnsupportedVersion),
+            ERR_UNKNOWN_REQUEST => {
+                let opcode = if reader.bool()? {
+                    Some(reader.u8()?)
+                } else {
+                    None
+                };
+                Ok(ClientRequestError::UnknownRequest(opcode))
+            }
+            ERR_TOO_MANY_ATTEMPTS => Ok(ClientRequestError::TooManyAttempts),
+            ERR_NOT_AUTHENTICATED => Ok(ClientRequestError::NotAuthenticated),
+            ERR_ACCOUNT_IN_USE => Ok(ClientRequestError::AccountInUse),
+            ERR_RESUME_FAILED if version >= RESUME_VERSION => Ok(ClientRequestError::ResumeFailed),
+            ERR_INVITE_NOT_FOUND if version >= INVITES_VERSION => {
+                Ok(ClientRequestError::InviteNotFound)
+            }
+            ERR_INVALID_WORD if version >= WORD_RULES_VERSION => {
+                Ok(ClientRequestError::InvalidWord(reader.string()?))
+            }
+            ERR_UNKNOWN_WORD if version >= DICTIONARY_VERSION => {
+                Ok(ClientRequestError::UnknownWord)
+            }
+            code => Err(DecodeError::UnknownErrorCode(code)),
+        }
+    }
+}
+
+impl WireMessage for ServerMessage {
+    fn encode(&self, buf: &mut Vec<u8>, version: u32) {
+        match self {
+            ServerMessage::Hello(version, capabilities) => write_hello(buf, *version, capabilities),
+            ServerMessage::AskPassword(challenge) => {
+                buf.push(OP_ASK_PASSWORD);
+                write_bytes(buf, &challenge.nonce);
+                write_bytes(buf, &challenge.salt);
+                write_varint(buf, challenge.iterations);
+            }
+            ServerMessage::WrongPassword => buf.push(OP_WRONG_PASSWORD),
+            ServerMessage::AssignId(id, resume_token) => {
+                buf.push(OP_ASSIGN_ID);
+                write_uuid(buf, id);
+                if version >= RESUME_VERSION {
+                    write_bytes(buf, resume_token);
+                }
+            }
+            ServerMessage::BadRequest(err) => {
+                buf.push(OP_BAD_REQUEST);
+                err.encode(buf);
+            }
+            ServerMessage::ListOpponents(opponents) => {
+                buf.push(OP_LIST_OPPONENTS);
+                write_varint(buf, opponents.len() as u32);
+                opponents.iter().for_each(|id| write_uuid(buf, id));
+            }
+            ServerMessage::MatchAccepted(match_id) => {
+                buf.push(OP_MATCH_ACCEPTED);
+                write_uuid(buf, match_id);
+            }
+            ServerMessage::MatchStarted(match_id) => {
+                buf.push(OP_MATCH_STARTED);
+                write_uuid(buf, match_id);
+            }
+            ServerMessage::MatchAttempt(match_id, attempts, hints, latest_attempt, feedback) => {
+                buf.push(OP_MATCH_ATTEMPT);
+                write_uuid(buf, match_id);
+                write_varint(buf, *attempts);
+                write_varint(buf, *hints);
+                write_string(buf, latest_attempt);
+                if version >= GAME_MODES_VERSION {
+                    write_feedback(buf, feedback);
+                }
+            }
+            ServerMessage::IncorrectGuess(match_id, attempts, feedback) => {
+                buf.push(OP_INCORRECT_GUESS);
+                write_uuid(buf, match_id);
+                write_varint(buf, *attempts);
+                if version >= GAME_MODES_VERSION {
+                    write_feedback(buf, feedback);
+                }
+            }
+            ServerMessage::MatchHint(match_id, hint) => {
+                buf.push(OP_MATCH_HINT);
+                write_uuid(buf, match_id);
+                write_string(buf, hint);
+            }
+            ServerMessage::MatchEnded(match_id, outcome) => {
+                buf.push(OP_MATCH_ENDED);
+                write_uuid(buf, match_id);
+                if version >= OUTCOME_VERSION {
+                    write_end_reason(buf, &outcome.reason);
+                    write_varint(buf, outcome.attempts);
+                    write_varint(buf, outcome.hints);
+                    write_string(buf, &outcome.word);
+                    write_varint(buf, outcome.duration_secs);
+                } else {
+                    write_varint(buf, outcome.attempts);
+                    write_varint(buf, outcome.hints);
+                    write_legacy_end_reason(buf, &outcome.reason, version);
+                }
+            }
+            ServerMessage::Disconnect => buf.push(OP_DISCONNECT),
+            ServerMessage::AskUsername => buf.push(OP_ASK_USERNAME),
+            ServerMessage::Ping => buf.push(OP_SERVER_PING),
+            ServerMessage::Pong => buf.push(OP_SERVER_PONG),
+            ServerMessage::MatchInvite(challenger, invite_id, mode, limits) => {
+                buf.push(OP_MATCH_INVITE);
+                write_uuid(buf, challenger);
+                write_uuid(buf, invite_id);
+                if version >= GAME_MODES_VERSION {
+                    write_game_mode(buf, mode);
+                }
+                if version >= LIMITS_VERSION {
+                    write_limits(buf, limits);
+                }
+            }
+            ServerMessage::InviteSent(invite_id) => {
+                buf.push(OP_INVITE_SENT);
+                write_uuid(buf, invite_id);
+            }
+            ServerMessage::InviteDeclined(invite_id) => {
+                buf.push(OP_INVITE_DECLINED);
+                write_uuid(buf, invite_id);
+            }
+            ServerMessage::InviteCancelled(invite_id) => {
+                buf.push(OP_INVITE_CANCELLED);
+                write_uuid(buf, invite_id);
+            }
+            ServerMessage::HangmanProgress(match_id, attempts, mask, lives, tried_letters) => {
+                buf.push(OP_HANGMAN_PROGRESS);
+                write_uuid(buf, match_id);
+                write_varint(buf, *attempts);
+                write_string(buf, mask);
+                write_varint(buf, *lives);
+                write_string(buf, tried_letters);
+            }
+            ServerMessage::PlayerStats(player_id, stats) => {
+                buf.push(OP_PLAYER_STATS);
+                write_uuid(buf, player_id);
+                write_stats(buf, stats);
+            }
+            ServerMessage::Leaderboard(players) => {
+                buf.push(OP_LEADERBOARD);
+                write_varint(buf, players.len() as u32);
+                for (player_id, stats) in players {
+                    write_uuid(buf, player_id);
+                    write_stats(buf, stats);
+                }
+            }
+        }
+    }
+
+    fn decode(bytes: &[u8], version: u32) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let opcode = reader.u8().map_err(|_| DecodeError::Empty)?;
+        let msg = match opcode {
+            OP_HELLO => ServerMessage::Hello(reader.varint()?, reader.capabilities()?),
+            OP_ASK_PASSWORD => ServerMessage::AskPassword(PasswordChallenge {
+                nonce: reader.bytes()?,
+                salt: reader.bytes()?,
+                iterations: reader.varint()?,
+            }),
+            OP_WRONG_PASSWORD => ServerMessage::WrongPassword,
+            OP_ASSIGN_ID => ServerMessage::AssignId(
+                reader.uuid()?,
+                if version >= RESUME_VERSION {
+                    reader.bytes()?
+                } else {
+                    Vec::new()
+                },
+            ),
+            OP_BAD_REQUEST => {
+                ServerMessage::BadRequest(ClientRequestError::decode(&mut reader, version)?)
+            }
+            OP_LIST_OPPONENTS => ServerMessage::ListOpponents(reader.uuid_list()?),
+            OP_MATCH_ACCEPTED => ServerMessage::MatchAccepted(reader.uuid()?),
+            OP_MATCH_STARTED => ServerMessage::MatchStarted(reader.uuid()?),
+            OP_MATCH_ATTEMPT => ServerMessage::MatchAttempt(
+                reader.uuid()?,
+                reader.varint()?,
+                reader.varint()?,
+                reader.string()?,
+                reader.feedback_since(version)?,
+            ),
+            OP_INCORRECT_GUESS => ServerMessage::IncorrectGuess(
+                reader.uuid()?,
+                reader.varint()?,
+                reader.feedback_since(version)?,
+            ),
+            OP_MATCH_HINT => ServerMessage::MatchHint(reader.uuid()?, reader.string()?),
+            OP_MATCH_ENDED if version >= OUTCOME_VERSION => ServerMessage::MatchEnded(
+                reader.uuid()?,
+                MatchOutcome {
+                    reason: reader.end_reason()?,
+                    attempts: reader.varint()?,
+                    hints: reader.varint()?,
+                    word: reader.string()?,
+                    duration_secs: reader.varint()?,
+                },
+            ),
+            // Older versions don't send the word and the duration
+            OP_MATCH_ENDED => ServerMessage::MatchEnded(
+                reader.uuid()?,
+                MatchOutcome {
+                    attempts: reader.varint()?,
+                    hints: reader.varint()?,
+                    reason: reader.legacy_end_reason(version)?,
+                    word: String::new(),
+                    duration_secs: 0,
+                },
+            ),
+            OP_DISCONNECT => ServerMessage::Disconnect,
+            OP_ASK_USERNAME => ServerMessage::AskUsername,
+            OP_SERVER_PING if version >= HEARTBEAT_VERSION => ServerMessage::Ping,
+            OP_SERVER_PONG if version >= HEARTBEAT_VERSION => ServerMessage::Pong,
+            OP_MATCH_INVITE if version >= INVITES_VERSION => ServerMessage::MatchInvite(
+                reader.uuid()?,
+                reader.uuid()?,
+                reader.game_mode_since(version)?,
+                reader.limits_since(version)?,
+            ),
+            OP_INVITE_SENT if version >= INVITES_VERSION => {
+                ServerMessage::InviteSent(reader.uuid()?)
+            }
+            OP_INVITE_DECLINED if version >= INVITES_VERSION => {
+                ServerMessage::InviteDeclined(reader.uuid()?)
+            }
+            OP_INVITE_CANCELLED if version >= INVITES_VERSION => {
+                ServerMessage::InviteCancelled(reader.uuid()?)
+            }
+            OP_HANGMAN_PROGRESS if version >= HANGMAN_VERSION => ServerMessage::HangmanProgress(
+                reader.uuid()?,
+                reader.varint()?,
+                reader.string()?,
+                reader.varint()?,
+                reader.string()?,
+            ),
+            OP_PLAYER_STATS if version >= STATS_VERSION => {
+                ServerMessage::PlayerStats(reader.uuid()?, reader.stats()?)
+            }
+            OP_LEADERBOARD if version >= STATS_VERSION => {
+                ServerMessage::Leaderboard(reader.leaderboard()?)
+            }
+            op => return Err(DecodeError::UnknownOpcode(op)),
+        };
+        reader.finish()?;
+        Ok(msg)
+    }
+}
+
+impl WireMessage for ClientMessage {
+    fn encode(&self, buf: &mut Vec<u8>, version: u32) {
+        match self {
+            ClientMessage::Hello(version, capabilities) => write_hello(buf, *version, capabilities),
+            ClientMessage::AnswerPassword(proof) => {
+                buf.push(OP_ANSWER_PASSWORD);
+                write_bytes(buf, proof);
+            }
+            ClientMessage::GetOpponents => buf.push(OP_GET_OPPONENTS),
+            ClientMessage::RequestMatch(opponent, guess_word, mode, limits) => {
+                buf.push(OP_REQUEST_MATCH);
+                write_uuid(buf, opponent);
+                write_string(buf, guess_word);
+                if version >= GAME_MODES_VERSION {
+                    write_game_mode(buf, mode);
+                }
+                if version >= LIMITS_VERSION {
+                    write_limits(buf, limits);
+                }
+            }
+            ClientMessage::GuessAttempt(match_id, guess) => {
+                buf.push(OP_GUESS_ATTEMPT);
+                write_uuid(buf, match_id);
+                write_string(buf, guess);
+            }
+            ClientMessage::SendHint(match_id, hint) => {
+                buf.push(OP_SEND_HINT);
+                write_uuid(buf, match_id);
+                write_string(buf, hint);
+            }
+            ClientMessage::GiveUp(match_id) => {
+                buf.push(OP_GIVE_UP);
+                write_uuid(buf, match_id);
+            }
+            ClientMessage::LeaveGame => buf.push(OP_LEAVE_GAME),
+            ClientMessage::AnswerUsername(username) => {
+                buf.push(OP_ANSWER_USERNAME);
+                write_string(buf, username);
+            }
+            ClientMessage::Ping => buf.push(OP_CLIENT_PING),
+            ClientMessage::Pong => buf.push(OP_CLIENT_PONG),
+            ClientMessage::Resume(resume_token) => {
+                buf.push(OP_RESUME);
+                write_bytes(buf, resume_token);
+            }
+            ClientMessage::AcceptInvite(invite_id) => {
+                buf.push(OP_ACCEPT_INVITE);
+                write_uuid(buf, invite_id);
+            }
+            ClientMessage::DeclineInvite(invite_id) => {
+                buf.push(OP_DECLINE_INVITE);
+                write_uuid(buf, invite_id);
+            }
+            ClientMessage::GetStats(player_id) => {
+                buf.push(OP_GET_STATS);
+                write_uuid(buf, player_id);
+            }
+            ClientMessage::GetLeaderboard => buf.push(OP_GET_LEADERBOARD),
+        }
+    }
+
+    fn decode(bytes: &[u8], version: u32) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let opcode = reader.u8().map_err(|_| DecodeError::Empty)?;
+        let msg = match opcode {
+            OP_HELLO => ClientMessage::Hello(reader.varint()?, reader.capabilities()?),
+            OP_ANSWER_PASSWORD => ClientMessage::AnswerPassword(reader.bytes()?),
+            OP_GET_OPPONENTS => ClientMessage::GetOpponents,
+            OP_REQUEST_MATCH => ClientMessage::RequestMatch(
+                reader.uuid()?,
+                reader.string()?,
+                reader.game_mode_since(version)?,
+                reader.limits_since(version)?,
+            ),
+            OP_GUESS_ATTEMPT => ClientMessage::GuessAttempt(reader.uuid()?, reader.string()?),
+            OP_SEND_HINT => ClientMessage::SendHint(reader.uuid()?, reader.string()?),
+            OP_GIVE_UP => ClientMessage::GiveUp(reader.uuid()?),
+            OP_LEAVE_GAME => ClientMessage::LeaveGame,
+            OP_ANSWER_USERNAME => ClientMessage::AnswerUsername(reader.string()?),
+            OP_CLIENT_PING if version >= HEARTBEAT_VERSION => ClientMessage::Ping,
+            OP_CLIENT_PONG if version >= HEARTBEAT_VERSION => ClientMessage::Pong,
+            OP_RESUME if version >= RESUME_VERSION => ClientMessage::Resume(reader.bytes()?),
+            OP_ACCEPT_INVITE if version >= INVITES_VERSION => {
+                ClientMessage::AcceptInvite(reader.uuid()?)
+            }
+            OP_DECLINE_INVITE if version >= INVITES_VERSION => {
+                ClientMessage::DeclineInvite(reader.uuid()?)
+            }
+            OP_GET_STATS if version >= STATS_VERSION => ClientMessage::GetStats(reader.uuid()?),
+            OP_GET_LEADERBOARD if version >= STATS_VERSION => ClientMessage::GetLeaderboard,
+            op => return Err(DecodeError::UnknownOpcode(op)),
+        };
+        reader.finish()?;
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+    use uuid::uuid;
+
+    const MATCH_ID: Uuid = uuid!("00112233-4455-6677-8899-aabbccddeeff");
+    const MATCH_ID_BYTES: [u8; 16] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee,
+        0xff,
+    ];
+
+    fn with_id(opcode: u8, tail: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![opcode];
+        bytes.extend_from_slice(&MATCH_ID_BYTES);
+        bytes.extend_from_slice(tail);
+        bytes
+    }
+
+    fn assert_server_golden(msg: ServerMessage, expected: &[u8]) {
+        assert_server_golden_at(PROTOCOL_VERSION, msg, expected);
+    }
+
+    fn assert_server_golden_at(version: u32, msg: ServerMessage, expected: &[u8]) {
+        let bytes = msg.to_bytes(version);
+        assert_eq!(bytes, expected, "encoding of {msg:?} in v{version}");
+        let decoded = ServerMessage::decode(&bytes, version).unwrap();
+        assert_eq!(
+            decoded.to_bytes(version),
+            expected,
+            "round trip of {msg:?} in v{version}"
+        );
+    }
+
+    fn assert_client_golden(msg: ClientMessage, expected: &[u8]) {
+        assert_client_golden_at(PROTOCOL_VERSION, msg, expected);
+    }
+
+    fn assert_client_golden_at(version: u32, msg: ClientMessage, expected: &[u8]) {
+        let bytes = msg.to_bytes(version);
+        assert_eq!(bytes, expected, "encoding of {msg:?} in v{version}");
+        let decoded = ClientMessage::decode(&bytes, version).unwrap();
+        assert_eq!(
+            decoded.to_bytes(version),
+            expected,
+            "round trip of {msg:?} in v{version}"
+        );
+    }
+
+    #[test]
+    fn server_messages_golden_bytes() {
+        assert_server_golden(
+            ServerMessage::Hello(1, Capabilities::HEARTBEAT | Capabilities::SPECTATE),
+            &[0x00, 0x01, 0x06],
+        );
+        assert_server_golden(
+            ServerMessage::AskPassword(PasswordChallenge {
+                nonce: vec![0xaa, 0xbb],
+                salt: vec![0x01],
+                iterations: 100_000,
+            }),
+            &[0x01, 0x02, 0xaa, 0xbb, 0x01, 0x01, 0xa0, 0x8d, 0x06],
+        );
+        assert_server_golden(ServerMessage::WrongPassword, &[0x02]);
+        assert_server_golden(
+            ServerMessage::AssignId(MATCH_ID, vec![0x01, 0x02]),
+            &with_id(0x03, &[0x02, 0x01, 0x02]),
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::PermissionDenied),
+            &[0x04, 0x03],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::UnsupportedVersion),
+            &[0x04, 0x04],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::UnknownRequest(Some(0x42))),
+            &[0x04, 0x05, 0x01, 0x42],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::UnknownRequest(None)),
+            &[0x04, 0x05, 0x00],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::TooManyAttempts),
+            &[0x04, 0x06],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::NotAuthenticated),
+            &[0x04, 0x07],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::AccountInUse),
+            &[0x04, 0x08],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::ResumeFailed),
+            &[0x04, 0x09],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::InviteNotFound),
+            &[0x04, 0x0a],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::InvalidWord("no".to_string())),
+            &[0x04, 0x0b, 0x02, b'n', b'o'],
+        );
+        assert_server_golden(
+            ServerMessage::BadRequest(ClientRequestError::UnknownWord),
+            &[0x04, 0x0c],
+        );
+        assert_server_golden(ServerMessage::ListOpponents(vec![]), &[0x05, 0x00]);
+        let mut list = vec![0x05, 0x02];
+        list.extend_from_slice(&MATCH_ID_BYTES);
+        list.extend_from_slice(&MATCH_ID_BYTES);
+        assert_server_golden(
+            ServerMessage::ListOpponents(vec![MATCH_ID, MATCH_ID]),
+            &list,
+        );
+        assert_server_golden(ServerMessage::MatchAccepted(MATCH_ID), &with_id(0x06, &[]));
+        assert_server_golden(ServerMessage::MatchStarted(MATCH_ID), &with_id(0x07, &[]));
+        assert_server_golden(
+            ServerMessage::MatchAttempt(MATCH_ID, 10, 300, "cat".to_string(), vec![]),
+            &with_id(0x08, &[0x0a, 0xac, 0x02, 0x03, b'c', b'a', b't', 0x00]),
+        );
+        assert_server_golden(
+            ServerMessage::IncorrectGuess(MATCH_ID, 2, vec![]),
+            &with_id(0x09, &[0x02, 0x00]),
+        );
+        assert_server_golden(
+            ServerMessage::IncorrectGuess(
+                MATCH_ID,
+                1,
+                vec![
+                    LetterFeedback::Correct,
+                    LetterFeedback::Absent,
+                    LetterFeedback::Present,
+                ],
+            ),
+            &with_id(0x09, &[0x01, 0x03, 0x02, 0x00, 0x01]),
+        );
+        assert_server_golden(
+            ServerMessage::MatchHint(MATCH_ID, "pet".to_string()),
+            &with_id(0x0a, &[0x03, b'p', b'e', b't']),
+        );
+        assert_server_golden(
+            ServerMessage::MatchEnded(
+                MATCH_ID,
+                MatchOutcome {
+                    reason: EndReason::ChallengerLeft,
+                    attempts: 3,
+                    hints: 1,
+                    word: "pet".to_string(),
+                    duration_secs: 300,
+                },
+            ),
+            &with_id(
+                0x0b,
+                &[0x07, 0x03, 0x01, 0x03, b'p', b'e', b't', 0xac, 0x02],
+            ),
+        );
+        assert_server_golden(ServerMessage::Disconnect, &[0x0c]);
+        assert_server_golden(ServerMessage::AskUsername, &[0x0d]);
+        assert_server_golden(ServerMessage::Ping, &[0x0e]);
+        assert_server_golden(ServerMessage::Pong, &[0x0f]);
+        assert_server_golden(
+            ServerMessage::MatchInvite(
+                MATCH_ID,
+                MATCH_ID,
+                GameMode::Wordle,
+                MatchLimits {
+                    max_attempts: 6,
+                    time_limit_secs: 300,
+                    guess_timeout_secs: 0,
+                },
+            ),
+            &with_id(
+                0x10,
+                &[MATCH_ID_BYTES.as_slice(), &[0x01, 0x06, 0xac, 0x02, 0x00]].concat(),
+            ),
+        );
+        assert_server_golden(ServerMessage::InviteSent(MATCH_ID), &with_id(0x11, &[]));
+        assert_server_golden(ServerMessage::InviteDeclined(MATCH_ID), &with_id(0x12, &[]));
+        assert_server_golden(
+            ServerMessage::InviteCancelled(MATCH_ID),
+            &with_id(0x13, &[]),
+        );
+        assert_server_golden(
+            ServerMessage::HangmanProgress(MATCH_ID, 2, "_a_".to_string(), 5, "ax".to_string()),
+            &with_id(
+                0x14,
+                &[0x02, 0x03, b'_', b'a', b'_', 0x05, 0x02, b'a', b'x'],
+            ),
+        );
+        let stats = PlayerStats {
+            games_as_guesser: 4,
+            games_as_challenger: 1,
+            solved: 3,
+            attempts: 200,
+            hints: 2,
+            longest_streak: 2,
+        };
+        assert_server_golden(
+            ServerMessage::PlayerStats(MATCH_ID, stats),
+            &with_id(0x15, &[0x04, 0x01, 0x03, 0xc8, 0x01, 0x02, 0x02]),
+        );
+        let mut leaderboard = vec![0x16, 0x01];
+        leaderboard.extend_from_slice(&MATCH_ID_BYTES);
+        leaderboard.extend_from_slice(&[0x04, 0x01, 0x03, 0xc8, 0x01, 0x02, 0x02]);
+        assert_server_golden(
+            ServerMessage::Leaderboard(vec![(MATCH_ID, stats)]),
+            &leaderboard,
+        );
+    }
+
+    #[test]
+    fn client_messages_golden_bytes() {
+        assert_client_golden(
+            ClientMessage::Hello(300, Capabilities::NONE),
+            &[0x00, 0xac, 0x02, 0x00],
+        );
+        assert_client_golden(
+            ClientMessage::AnswerPassword(vec![0xde, 0xad]),
+            &[0x01, 0x02, 0xde, 0xad],
+        );
+        assert_client_golden(ClientMessage::GetOpponents, &[0x02]);
+        assert_client_golden(
+            ClientMessage::RequestMatch(
+                MATCH_ID,
+                "cat".to_string(),
+                GameMode::Classic,
+                MatchLimits::default(),
+            ),
+            &with_id(0x03, &[0x03, b'c', b'a', b't', 0x00, 0x00, 0x00, 0x00]),
+        );
+        assert_client_golden(
+            ClientMessage::GuessAttempt(MATCH_ID, "dog".to_string()),
+            &with_id(0x04, &[0x03, b'd', b'o', b'g']),
+        );
+        assert_client_golden(
+            ClientMessage::SendHint(MATCH_ID, "".to_string()),
+            &with_id(0x05, &[0x00]),
+        );
+        assert_client_golden(ClientMessage::GiveUp(MATCH_ID), &with_id(0x06, &[]));
+        assert_client_golden(ClientMessage::LeaveGame, &[0x07]);
+        assert_client_golden(
+            ClientMessage::AnswerUsername("me".to_string()),
+            &[0x08, 0x02, b'm', b'e'],
+        );
+        assert_client_golden(ClientMessage::Ping, &[0x09]);
+        assert_client_golden(ClientMessage::Pong, &[0x0a]);
+        assert_client_golden(
+            ClientMessage::Resume(vec![0xbe, 0xef]),
+            &[0x0b, 0x02, 0xbe, 0xef],
+        );
+        assert_client_golden(ClientMessage::AcceptInvite(MATCH_ID), &with_id(0x0c, &[]));
+        assert_client_golden(ClientMessage::DeclineInvite(MATCH_ID), &with_id(0x0d, &[]));
+        assert_client_golden(ClientMessage::GetStats(MATCH_ID), &with_id(0x0e, &[]));
+        assert_client_golden(ClientMessage::GetLeaderboard, &[0x0f]);
+    }
+
+    #[test]
+    fn older_versions_leave_out_newer_fields() {
+        assert_server_golden_at(
+            MIN_PROTOCOL_VERSION,
+            ServerMessage::AssignId(MATCH_ID, vec![]),
+            &with_id(0x03, &[]),
+        );
+        assert_server_golden_at(
+            8,
+            ServerMessage::MatchAttempt(MATCH_ID, 10, 300, "cat".to_string(), vec![]),
+            &with_id(0x08, &[0x0a, 0xac, 0x02, 0x03, b'c', b'a', b't']),
+        );
+        assert_server_golden_at(
+            8,
+            ServerMessage::IncorrectGuess(MATCH_ID, 2, vec![]),
+            &with_id(0x09, &[0x02]),
+        );
+        assert_server_golden_at(
+            6,
+            ServerMessage::MatchInvite(
+                MATCH_ID,
+                MATCH_ID,
+                GameMode::Classic,
+                MatchLimits::default(),
+            ),
+            &with_id(0x10, &MATCH_ID_BYTES),
+        );
+        assert_server_golden_at(
+            9,
+            ServerMessage::MatchInvite(
+                MATCH_ID,
+                MATCH_ID,
+                GameMode::Wordle,
+                MatchLimits::default(),
+            ),
+            &with_id(0x10, &[MATCH_ID_BYTES.as_slice(), &[0x01]].concat()),
+        );
+        let outcome = |reason| MatchOutcome {
+            reason,
+            attempts: 3,
+            hints: 1,
+            word: String::new(),
+            duration_secs: 0,
+        };
+        assert_server_golden_at(
+            MIN_PROTOCOL_VERSION,
+            ServerMessage::MatchEnded(MATCH_ID, outcome(EndReason::Solved)),
+            &with_id(0x0b, &[0x03, 0x01, 0x01]),
+        );
+        assert_server_golden_at(
+            10,
+            ServerMessage::MatchEnded(MATCH_ID, outcome(EndReason::TimedOut)),
+            &with_id(0x0b, &[0x03, 0x01, 0x00]),
+        );
+        assert_server_golden_at(
+            11,
+            ServerMessage::MatchEnded(MATCH_ID, outcome(EndReason::TimedOut)),
+            &with_id(0x0b, &[0x03, 0x01, 0x05]),
+        );
+        // Players leaving were reported as cancelled matches before the outcome was sent
+        assert_eq!(
+            ServerMessage::MatchEnded(MATCH_ID, outcome(EndReason::ChallengerLeft)).to_bytes(11),
+            with_id(0x0b, &[0x03, 0x01, 0x02])
+        );
+        assert_client_golden_at(
+            MIN_PROTOCOL_VERSION,
+            ClientMessage::RequestMatch(
+                MATCH_ID,
+                "cat".to_string(),
+                GameMode::Classic,
+                MatchLimits::default(),
+            ),
+            &with_id(0x03, &[0x03, b'c', b'a', b't']),
+        );
+        assert_client_golden_at(
+            10,
+            ClientMessage::RequestMatch(
+                MATCH_ID,
+                "cat".to_string(),
+                GameMode::Hangman,
+                MatchLimits {
+                    max_attempts: 6,
+                    time_limit_secs: 0,
+                    guess_timeout_secs: 0,
+                },
+            ),
+            &with_id(0x03, &[0x03, b'c', b'a', b't', 0x02]),
+        );
+    }
+
+    #[test]
+    fn older_versions_reject_newer_opcodes() {
+        assert_eq!(
+            ServerMessage::decode(&[0x0e], MIN_PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::UnknownOpcode(0x0e)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x04, 0x09], 4).unwrap_err(),
+            DecodeError::UnknownErrorCode(0x09)
+        );
+        assert_eq!(
+            ServerMessage::decode(&with_id(0x11, &[]), 5).unwrap_err(),
+            DecodeError::UnknownOpcode(0x11)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x04, 0x0a], 5).unwrap_err(),
+            DecodeError::UnknownErrorCode(0x0a)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x04, 0x0b, 0x00], 6).unwrap_err(),
+            DecodeError::UnknownErrorCode(0x0b)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x04, 0x0c], 7).unwrap_err(),
+            DecodeError::UnknownErrorCode(0x0c)
+        );
+        assert_eq!(
+            ServerMessage::decode(&with_id(0x14, &[]), 9).unwrap_err(),
+            DecodeError::UnknownOpcode(0x14)
+        );
+        assert_eq!(
+            ClientMessage::decode(&with_id(0x03, &[0x03, b'c', b'a', b't', 0x02]), 9).unwrap_err(),
+            DecodeError::UnknownGameMode(0x02)
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x0b, 0x00], 4).unwrap_err(),
+            DecodeError::UnknownOpcode(0x0b)
+        );
+        assert_eq!(
+            ClientMessage::decode(&with_id(0x0c, &[]), 5).unwrap_err(),
+            DecodeError::UnknownOpcode(0x0c)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x16, 0x00], 12).unwrap_err(),
+            DecodeError::UnknownOpcode(0x16)
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x0f], 12).unwrap_err(),
+            DecodeError::UnknownOpcode(0x0f)
+        );
+    }
+
+    #[test]
+    fn strings_are_utf8_byte_length_prefixed() {
+        let bytes = ClientMessage::AnswerUsername("žľ".to_string()).to_bytes(PROTOCOL_VERSION);
+        assert_eq!(bytes, [0x08, 0x04, 0xc5, 0xbe, 0xc4, 0xbe]);
+    }
+
+    #[test]
+    fn malformed_message_keeps_opcode() {
+        let err = ClientMessage::decode_frame(&[0x99, 0x01], PROTOCOL_VERSION).unwrap_err();
+        assert_eq!(err.opcode, Some(0x99));
+        assert_eq!(err.error, DecodeError::UnknownOpcode(0x99));
+
+        let err = ClientMessage::decode_frame(&[0x04, 0x01], PROTOCOL_VERSION).unwrap_err();
+        assert_eq!(err.opcode, Some(0x04));
+        assert_eq!(err.error, DecodeError::UnexpectedEnd);
+
+        let err = ClientMessage::decode_frame(&[], PROTOCOL_VERSION).unwrap_err();
+        assert_eq!(err.opcode, None);
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        assert_eq!(
+            ClientMessage::decode(&[], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::Empty
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0xff], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::UnknownOpcode(0xff)
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x06, 0x00], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x02, 0x00], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::TrailingBytes(1)
+        );
+        assert_eq!(
+            ClientMessage::decode(&[0x08, 0x02, 0xff, 0xfe], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::InvalidUtf8
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x04, 0x7f], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::UnknownErrorCode(0x7f)
+        );
+        assert_eq!(
+            ClientMessage::decode(&with_id(0x03, &[0x00, 0x09]), PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::UnknownGameMode(0x09)
+        );
+        assert_eq!(
+            ServerMessage::decode(&with_id(0x09, &[0x01, 0x01, 0x03]), PROTOCOL_VERSION)
+                .unwrap_err(),
+            DecodeError::UnknownLetterFeedback(0x03)
+        );
+        assert_eq!(
+            ServerMessage::decode(&with_id(0x0b, &[0x09]), PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::UnknownEndReason(0x09)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x04, 0x05, 0x02], PROTOCOL_VERSION).unwrap_err(),
+            DecodeError::InvalidBool(0x02)
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x05, 0xff, 0xff, 0xff, 0xff, 0x0f], PROTOCOL_VERSION)
+                .unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+        assert_eq!(
+            ServerMessage::decode(&[0x16, 0xff, 0xff, 0xff, 0xff, 0x0f], PROTOCOL_VERSION)
+                .unwrap_err(),
+            DecodeError::UnexpectedEnd
+        );
+    }
+}
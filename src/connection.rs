@@ -1,61 +1,88 @@
-use crate::protocol::ServerMessage;
-use anyhow::anyhow;
-use log::trace;
-use rmp_serde::Serializer;
-use serde::{Deserialize, Serialize};
+use crate::{
+    codec::{MalformedMessage, WireMessage},
+    framing::{read_frame, write_frame},
+    protocol::PROTOCOL_VERSION,
+};
+use log::{debug, trace};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
     sync::mpsc::{self, Sender},
 };
 
-#[derive(Clone)]
-pub struct Connection {
-    pub tx: Sender<ServerMessage>,
+/// Protocol version messages on the stream are encoded with, shared by both sides of the connection.
+/// Starts at `PROTOCOL_VERSION` since `Hello` is the same in every version
+/// and is lowered to the negotiated version once `Hello` has been processed
+#[derive(Debug, Clone)]
+pub struct WireVersion(Arc<AtomicU32>);
+
+impl Default for WireVersion {
+    fn default() -> Self {
+        Self(Arc::new(AtomicU32::new(PROTOCOL_VERSION)))
+    }
+}
+
+impl WireVersion {
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, version: u32) {
+        self.0.store(version, Ordering::Relaxed);
+    }
 }
 
 /// Generic handler for new connection used by client and server.
 /// Creates a new `mpsc::channel` that can be used for sending messages
 /// Creates a green thread for reading and writing to the channels encapsulated by the `mpsc::channel`
+/// Messages are encoded with `codec` and sent over the stream as length-prefixed frames (see `framing`)
+/// Frames that cannot be decoded are passed to `output_tx` as `MalformedMessage` so the receiver can react
+/// Dropping every clone of the returned `Sender` closes the stream once pending messages are written
+/// `capacity` limits the number of messages waiting to be written
+/// Messages are encoded and decoded with the current `version`
 pub async fn handle_stream<S, OutgoingMessageType, IncommingMessageType>(
     stream: S,
-    output_tx: Sender<IncommingMessageType>,
+    output_tx: Sender<Result<IncommingMessageType, MalformedMessage>>,
+    capacity: usize,
+    version: WireVersion,
     // connections: &mut ActiveConnections,
 ) -> Result<Sender<OutgoingMessageType>, anyhow::Error>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    OutgoingMessageType: Serialize + for<'a> Deserialize<'a> + std::fmt::Debug + Send + 'static,
-    IncommingMessageType: Serialize + for<'a> Deserialize<'a> + std::fmt::Debug + Send + 'static,
+    OutgoingMessageType: WireMessage + std::fmt::Debug + Send + 'static,
+    IncommingMessageType: WireMessage + std::fmt::Debug + Send + 'static,
 {
     let (reader, mut writer) = tokio::io::split(stream);
 
     // Create a channel for sending messages to this client
-    let (client_tx, mut client_rx) = mpsc::channel::<OutgoingMessageType>(100);
+    let (client_tx, mut client_rx) = mpsc::channel::<OutgoingMessageType>(capacity);
 
-    let _read_task = tokio::spawn({
+    let read_task = tokio::spawn({
+        let version = version.clone();
         async move {
             let mut buf = Vec::<u8>::new();
             let mut buf_reader = BufReader::new(reader);
             loop {
                 buf.clear();
                 trace!("at the start of the read task loop",);
-                match buf_reader.read_until(b'\n', &mut buf).await {
-                    Ok(0) => {
+                match read_frame(&mut buf_reader, &mut buf).await {
+                    Ok(false) => {
                         // Connection closed
-                        // println!("Client disconnected: {}", player_id);
                         break;
                     }
-                    Ok(n) => {
+                    Ok(true) => {
                         // Process the message (e.g., routing or broadcasting)
                         trace!("Message from client received: {:?}", &buf);
-                        if let Ok(msg) =
-                            rmp_serde::from_slice::<IncommingMessageType>(&buf[..n - 1])
-                                .map_err(|e| anyhow!("Error parsing {e:?}"))
-                        {
-                            trace!("Parsed Message from stream: {:?}", msg);
-                            let _ = output_tx.send(msg).await;
-
-                            trace!("Message sent to the output tx");
-                        };
+                        let msg = IncommingMessageType::decode_frame(&buf, version.get());
+                        match &msg {
+                            Ok(msg) => trace!("Parsed Message from stream: {:?}", msg),
+                            Err(e) => debug!("Error parsing message: {}", e.error),
+                        }
+                        let _ = output_tx.send(msg).await;
+                        trace!("Message sent to the output tx");
                     }
                     Err(e) => {
                         eprintln!("Error reading from incomming message{:?}", e);
@@ -70,16 +97,17 @@ where
     let _write_task = tokio::spawn(async move {
         while let Some(msg) = client_rx.recv().await {
             trace!("Sending msg {:?}", msg);
-            let mut payload = Vec::new();
-            msg.serialize(&mut Serializer::new(&mut payload)).unwrap();
-            payload.push(b'\n');
+            let payload = msg.to_bytes(version.get());
 
-            if writer.write_all(&payload).await.is_err() {
+            if write_frame(&mut writer, &payload).await.is_err() {
                 eprintln!("Error writing to stream");
                 break;
             }
             trace!("Message sent {:?}", msg);
         }
+        // All senders are gone, close the stream in both directions
+        let _ = writer.shutdown().await;
+        read_task.abort();
     });
 
     Ok(client_tx)
@@ -0,0 +1,134 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::validation::WordRules;
+
+/// Word list of a single language, plain text file with one word per line
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DictionaryConfig {
+    pub language: String,
+    pub path: PathBuf,
+}
+
+/// Known words of a single language
+pub struct Dictionary {
+    pub language: String,
+    /// Sorted and deduplicated for binary search
+    words: Vec<Box<str>>,
+}
+
+impl Dictionary {
+    pub fn new(language: &str, words: impl IntoIterator<Item = String>) -> Self {
+        let mut words = words
+            .into_iter()
+            .map(String::into_boxed_str)
+            .collect::<Vec<Box<str>>>();
+        words.sort_unstable();
+        words.dedup();
+        words.shrink_to_fit();
+        Self {
+            language: language.to_string(),
+            words,
+        }
+    }
+
+    /// Load the word list keeping only words that follow the word rules,
+    /// other words can't be picked or guessed anyway
+    pub fn load(config: &DictionaryConfig, rules: &WordRules) -> Result<Self, anyhow::Error> {
+        let content = fs::read_to_string(&config.path)
+            .map_err(|e| anyhow!("Unable to read {}: {e}", config.path.display()))?;
+        let words = content
+            .lines()
+            .filter_map(|line| rules.validate(line.trim()).ok());
+        Ok(Self::new(&config.language, words))
+    }
+
+    pub fn contains(&self, word: &str) -> bool {
+        self.words
+            .binary_search_by(|known| known.as_ref().cmp(word))
+            .is_ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+}
+
+/// Dictionaries of all configured languages, words aren't checked if there are none
+#[derive(Default)]
+pub struct Dictionaries(Vec<Dictionary>);
+
+impl Dictionaries {
+    pub fn new(dictionaries: Vec<Dictionary>) -> Self {
+        Self(dictionaries)
+    }
+
+    pub fn load(configs: &[DictionaryConfig], rules: &WordRules) -> Result<Self, anyhow::Error> {
+        let dictionaries = configs
+            .iter()
+            .map(|config| Dictionary::load(config, rules))
+            .collect::<Result<Vec<Dictionary>, anyhow::Error>>()?;
+        Ok(Self::new(dictionaries))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Dictionary> {
+        self.0.iter()
+    }
+
+    /// Language of the first dictionary that knows the word
+    pub fn language_of(&self, word: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|dictionary| dictionary.contains(word))
+            .map(|dictionary| dictionary.language.as_str())
+    }
+
+    /// Check the word in the dictionary of the language,
+    /// any dictionary is used if the language isn't loaded
+    pub fn allows(&self, language: Option<&str>, word: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        match language.and_then(|language| self.0.iter().find(|d| d.language == language)) {
+            Some(dictionary) => dictionary.contains(word),
+            None => self.language_of(word).is_some(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionaries() -> Dictionaries {
+        Dictionaries::new(vec![
+            Dictionary::new("en", ["dog", "cat", "cat"].map(String::from)),
+            Dictionary::new("sk", ["pes", "mačka"].map(String::from)),
+        ])
+    }
+
+    #[test]
+    fn words_are_looked_up_by_language() {
+        let dictionaries = dictionaries();
+        assert_eq!(dictionaries.language_of("mačka"), Some("sk"));
+        assert_eq!(dictionaries.language_of("cat"), Some("en"));
+        assert_eq!(dictionaries.language_of("kat"), None);
+        assert!(dictionaries.allows(Some("en"), "dog"));
+        assert!(!dictionaries.allows(Some("en"), "pes"));
+        assert!(dictionaries.allows(Some("de"), "pes"));
+        assert!(dictionaries.allows(None, "pes"));
+        assert_eq!(dictionaries.iter().map(Dictionary::len).sum::<usize>(), 4);
+    }
+
+    #[test]
+    fn without_dictionaries_every_word_is_allowed() {
+        assert!(Dictionaries::default().allows(None, "anything"));
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::anyhow;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Upper bound for a single frame payload.
+/// Anything bigger is treated as a protocol violation and the connection is dropped
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Maximum number of bytes a `u32` varint can occupy
+const MAX_VARINT_LEN: usize = 5;
+
+/// Append `value` encoded as LEB128 varint to `buf`
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Wrap `payload` into a frame: varint length prefix followed by the payload itself
+pub fn encode_frame(payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "Frame of {} bytes exceeds maximum frame size {MAX_FRAME_SIZE}",
+            payload.len()
+        ));
+    }
+    let mut frame = Vec::with_capacity(payload.len() + MAX_VARINT_LEN);
+    write_varint(&mut frame, payload.len() as u32);
+    frame.extend_from_slice(payload);
+    Ok(frame)
+}
+
+/// Write a single frame to the `writer`
+pub async fn write_frame<W>(writer: &mut W, payload: &[u8]) -> Result<(), anyhow::Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let frame = encode_frame(payload)?;
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read a single frame from the `reader` into `buf`.
+/// Returns `Ok(false)` if the stream has been closed cleanly between two frames.
+/// Partial reads are handled by waiting for the rest of the frame,
+/// stream closed in the middle of a frame is reported as an error.
+pub async fn read_frame<R>(reader: &mut R, buf: &mut Vec<u8>) -> Result<bool, anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(len) = read_length_prefix(reader).await? else {
+        return Ok(false);
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(anyhow!(
+            "Incoming frame of {len} bytes exceeds maximum frame size {MAX_FRAME_SIZE}"
+        ));
+    }
+
+    buf.clear();
+    buf.resize(len, 0);
+    reader.read_exact(buf).await?;
+    Ok(true)
+}
+
+/// Read varint length prefix byte by byte.
+/// Returns `None` when the stream is closed before the first byte
+async fn read_length_prefix<R>(reader: &mut R) -> Result<Option<usize>, anyhow::Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut value: u32 = 0;
+    for idx in 0..MAX_VARINT_LEN {
+        let mut byte = [0u8; 1];
+        if reader.read(&mut byte).await? == 0 {
+            if idx == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!("Stream closed in the middle of a frame header"));
+        }
+        if idx == MAX_VARINT_LEN - 1 && byte[0] > 0x0f {
+            return Err(anyhow!("Frame length prefix overflows u32"));
+        }
+        value |= ((byte[0] & 0x7f) as u32) << (7 * idx);
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value as usize));
+        }
+    }
+    Err(anyhow!("Frame length prefix is too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    /// Read a frame from raw `bytes`, the stream ends after them
+    async fn read_from(bytes: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        let mut reader = bytes;
+        let mut buf = Vec::new();
+        Ok(read_frame(&mut reader, &mut buf).await?.then_some(buf))
+    }
+
+    #[test]
+    fn length_prefix_is_varint() {
+        assert_eq!(encode_frame(&[0x42]).unwrap(), [0x01, 0x42]);
+        let frame = encode_frame(&[0u8; 300]).unwrap();
+        assert_eq!(frame[..2], [0xac, 0x02]);
+        assert_eq!(frame.len(), 302);
+    }
+
+    #[tokio::test]
+    async fn payload_with_newline_round_trips() {
+        let payload = b"two\nlines\n".to_vec();
+        let (mut client, mut server) = duplex(64);
+        write_frame(&mut client, &payload).await.unwrap();
+        write_frame(&mut client, &[]).await.unwrap();
+        drop(client);
+
+        let mut buf = Vec::new();
+        assert!(read_frame(&mut server, &mut buf).await.unwrap());
+        assert_eq!(buf, payload);
+        assert!(read_frame(&mut server, &mut buf).await.unwrap());
+        assert!(buf.is_empty());
+        assert!(!read_frame(&mut server, &mut buf).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads() {
+        let payload = vec![0x0a; 200];
+        let frames = [
+            encode_frame(&payload).unwrap(),
+            encode_frame(b"end").unwrap(),
+        ]
+        .concat();
+        // Tiny buffer makes every read return only a few bytes
+        let (mut client, mut server) = duplex(3);
+        let writer = tokio::spawn(async move {
+            for chunk in frames.chunks(2) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut buf = Vec::new();
+        assert!(read_frame(&mut server, &mut buf).await.unwrap());
+        assert_eq!(buf, payload);
+        assert!(read_frame(&mut server, &mut buf).await.unwrap());
+        assert_eq!(buf, b"end");
+        writer.await.unwrap();
+        assert!(!read_frame(&mut server, &mut buf).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn clean_eof_between_frames() {
+        assert_eq!(read_from(&[]).await.unwrap(), None);
+        assert_eq!(
+            read_from(&[0x02, 0x0a, 0x0a]).await.unwrap(),
+            Some(vec![0x0a, 0x0a])
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_frames() {
+        let err = read_from(&[0x80]).await.unwrap_err();
+        assert!(
+            err.to_string().contains("middle of a frame header"),
+            "{err}"
+        );
+        assert!(read_from(&[0x05, 0x01, 0x02]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames() {
+        assert!(encode_frame(&vec![0; MAX_FRAME_SIZE + 1]).is_err());
+        assert!(encode_frame(&vec![0; MAX_FRAME_SIZE]).is_ok());
+
+        let mut prefix = Vec::new();
+        write_varint(&mut prefix, MAX_FRAME_SIZE as u32 + 1);
+        let err = read_from(&prefix).await.unwrap_err();
+        assert!(
+            err.to_string().contains("exceeds maximum frame size"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_length_prefix_overflow() {
+        let err = read_from(&[0xff, 0xff, 0xff, 0xff, 0x1f])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("overflows u32"), "{err}");
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::anyhow;
+use log::error;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    protocol::{EndReason, GameMode, PlayerStats},
+    server_state::Match,
+};
+
+const DEFAULT_RETENTION: usize = 1000;
+
+/// Backend finished matches are written to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StorageConfig {
+    /// Matches are kept only in memory and lost on restart
+    #[default]
+    Memory,
+    /// SQLite database file, created if it doesn't exist
+    Sqlite { path: PathBuf },
+    /// Append-only file with one JSON object per match
+    Jsonl { path: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Number of latest finished matches kept in memory, older ones stay only in the storage
+    pub retention: usize,
+    pub storage: StorageConfig,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            retention: DEFAULT_RETENTION,
+            storage: StorageConfig::default(),
+        }
+    }
+}
+
+/// Hint as it was sent to the guesser
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HintRecord {
+    pub text: String,
+    /// Unix timestamp in seconds
+    pub sent_at: u64,
+}
+
+/// Finished match as it is stored in the history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchRecord {
+    pub id: Uuid,
+    pub challenger: Uuid,
+    pub guesser: Uuid,
+    pub word: String,
+    pub language: Option<String>,
+    pub mode: GameMode,
+    pub attempts: u32,
+    pub hints: Vec<HintRecord>,
+    pub end_reason: EndReason,
+    /// Unix timestamps in seconds
+    pub started_at: u64,
+    pub ended_at: u64,
+}
+
+impl MatchRecord {
+    /// Record of a match that has just ended
+    pub fn new(finished_match: &Match, end_reason: EndReason) -> Self {
+        let ended_at = SystemTime::now();
+        let started_at = ended_at - finished_match.started_at.elapsed();
+        Self {
+            id: finished_match.id,
+            challenger: finished_match.challenger,
+            guesser: finished_match.guesser,
+            word: finished_match.guess_word.clone(),
+            language: finished_match.language.clone(),
+            mode: finished_match.mode,
+            attempts: finished_match.attempts,
+            hints: finished_match
+                .hints
+                .iter()
+                .map(|hint| HintRecord {
+                    text: hint.text.clone(),
+                    sent_at: unix_secs(hint.sent_at),
+                })
+                .collect(),
+            end_reason,
+            started_at: unix_secs(started_at),
+            ended_at: unix_secs(ended_at),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Opened storage backend
+enum Storage {
+    Memory,
+    Sqlite(Connection),
+    Jsonl(File),
+}
+
+impl Storage {
+    /// Open the storage and pass all stored matches to `visit`, oldest first
+    fn open(config: &StorageConfig, visit: impl FnMut(MatchRecord)) -> Result<Self, anyhow::Error> {
+        match config {
+            StorageConfig::Memory => Ok(Storage::Memory),
+            StorageConfig::Sqlite { path } => {
+                let connection = open_sqlite(path)
+                    .map_err(|e| anyhow!("Unable to open {}: {e}", path.display()))?;
+                load_sqlite(&connection, visit)
+                    .map_err(|e| anyhow!("Unable to read {}: {e}", path.display()))?;
+                Ok(Storage::Sqlite(connection))
+            }
+            StorageConfig::Jsonl { path } => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .read(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| anyhow!("Unable to open {}: {e}", path.display()))?;
+                load_jsonl(&file, visit)
+                    .map_err(|e| anyhow!("Unable to read {}: {e}", path.display()))?;
+                Ok(Storage::Jsonl(file))
+            }
+        }
+    }
+
+    fn write(&mut self, record: &MatchRecord) -> Result<(), anyhow::Error> {
+        match self {
+            Storage::Memory => {}
+            Storage::Sqlite(connection) => {
+                connection.execute(
+                    "INSERT OR REPLACE INTO matches (id, challenger, guesser, ended_at, record)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        record.id.to_string(),
+                        record.challenger.to_string(),
+                        record.guesser.to_string(),
+                        record.ended_at as i64,
+                        serde_json::to_string(record)?,
+                    ],
+                )?;
+            }
+            Storage::Jsonl(file) => {
+                let mut line = serde_json::to_string(record)?;
+                line.push('\n');
+                file.write_all(line.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Thread the storage is written from, so the game loop never waits for the disk
+struct StorageWriter {
+    tx: Sender<MatchRecord>,
+    thread: JoinHandle<()>,
+}
+
+impl StorageWriter {
+    fn spawn(mut storage: Storage) -> Result<Self, anyhow::Error> {
+        let (tx, rx) = mpsc::channel::<MatchRecord>();
+        let thread = thread::Builder::new()
+            .name("history-writer".to_string())
+            .spawn(move || {
+                for record in rx {
+                    if let Err(e) = storage.write(&record) {
+                        error!("Unable to store match {} in the history: {e}", record.id);
+                    }
+                }
+            })?;
+        Ok(Self { tx, thread })
+    }
+
+    /// Wait until all records sent so far are written
+    fn close(self) {
+        drop(self.tx);
+        if self.thread.join().is_err() {
+            error!("History writer has panicked");
+        }
+    }
+}
+
+fn open_sqlite(path: &Path) -> Result<Connection, rusqlite::Error> {
+    let connection = Connection::open(path)?;
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS matches (
+            id TEXT PRIMARY KEY,
+            challenger TEXT NOT NULL,
+            guesser TEXT NOT NULL,
+            ended_at INTEGER NOT NULL,
+            record TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS matches_ended_at ON matches (ended_at);",
+    )?;
+    Ok(connection)
+}
+
+fn load_sqlite(
+    connection: &Connection,
+    mut visit: impl FnMut(MatchRecord),
+) -> Result<(), anyhow::Error> {
+    let mut statement =
+        connection.prepare("SELECT record FROM matches ORDER BY ended_at, rowid")?;
+    let mut rows = statement.query([])?;
+    while let Some(row) = rows.next()? {
+        visit(serde_json::from_str(&row.get::<_, String>(0)?)?);
+    }
+    Ok(())
+}
+
+/// Lines that can't be parsed, e.g. one cut short by a crash, are skipped
+fn load_jsonl(file: &File, mut visit: impl FnMut(MatchRecord)) -> Result<(), anyhow::Error> {
+    for line in BufReader::new(file).lines() {
+        if let Ok(record) = serde_json::from_str(&line?) {
+            visit(record);
+        }
+    }
+    Ok(())
+}
+
+/// Statistics of a player with the state needed to keep them up to date
+#[derive(Default)]
+struct PlayerTally {
+    stats: PlayerStats,
+    /// Matches solved in a row as the guesser until now
+    current_streak: u32,
+}
+
+/// Finished matches, the latest ones are kept in memory.
+/// Player statistics cover all stored matches
+pub struct MatchHistory {
+    /// `None` for histories kept only in memory
+    writer: Option<StorageWriter>,
+    /// Oldest first
+    pub records: VecDeque<MatchRecord>,
+    retention: usize,
+    players: HashMap<Uuid, PlayerTally>,
+}
+
+impl Default for MatchHistory {
+    fn default() -> Self {
+        Self {
+            writer: None,
+            records: VecDeque::new(),
+            retention: DEFAULT_RETENTION,
+            players: HashMap::new(),
+        }
+    }
+}
+
+impl MatchHistory {
+    /// Open the storage and load matches recorded by previous runs
+    pub fn open(config: &HistoryConfig) -> Result<Self, anyhow::Error> {
+        let mut history = Self::default();
+        history.retention = config.retention;
+        history.writer = match Storage::open(&config.storage, |record| history.remember(record))? {
+            Storage::Memory => None,
+            storage => Some(StorageWriter::spawn(storage)?),
+        };
+        Ok(history)
+    }
+
+    /// Keep the match in memory and pass it to the storage writer,
+    /// the oldest match is forgotten once over the retention
+    /// Match stays in memory even if the storage fails
+    pub fn record(&mut self, record: MatchRecord) -> Result<(), anyhow::Error> {
+        let result = match &self.writer {
+            Some(writer) => writer
+                .tx
+                .send(record.clone())
+                .map_err(|_| anyhow!("History writer has stopped")),
+            None => Ok(()),
+        };
+        self.remember(record);
+        result
+    }
+
+    fn remember(&mut self, record: MatchRecord) {
+        self.count(&record);
+        if self.records.len() == self.retention {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Add the match to statistics of both players
+    fn count(&mut self, record: &MatchRecord) {
+        if record.end_reason == EndReason::Cancelled {
+            return;
+        }
+        let challenger = self.players.entry(record.challenger).or_default();
+        challenger.stats.games_as_challenger += 1;
+
+        let guesser = self.players.entry(record.guesser).or_default();
+        guesser.stats.games_as_guesser += 1;
+        guesser.stats.attempts += record.attempts;
+        guesser.stats.hints += record.hints.len() as u32;
+        if record.end_reason == EndReason::Solved {
+            guesser.stats.solved += 1;
+            guesser.current_streak += 1;
+            guesser.stats.longest_streak = guesser.stats.longest_streak.max(guesser.current_streak);
+        } else {
+            guesser.current_streak = 0;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Statistics of the player, all zero if they haven't played yet
+    pub fn stats(&self, player_id: &Uuid) -> PlayerStats {
+        self.players
+            .get(player_id)
+            .map(|tally| tally.stats)
+            .unwrap_or_default()
+    }
+
+    /// Players with the most solved matches, ties are broken by fewer games played
+    /// and then by the longest streak
+    pub fn leaderboard(&self, size: usize) -> Vec<(Uuid, PlayerStats)> {
+        let mut players = self
+            .players
+            .iter()
+            .filter(|(_, tally)| tally.stats.games_as_guesser > 0)
+            .map(|(player_id, tally)| (*player_id, tally.stats))
+            .collect::<Vec<(Uuid, PlayerStats)>>();
+        players.sort_by(|(a_id, a), (b_id, b)| {
+            b.solved
+                .cmp(&a.solved)
+                .then(a.games_as_guesser.cmp(&b.games_as_guesser))
+                .then(b.longest_streak.cmp(&a.longest_streak))
+                .then(a_id.cmp(b_id))
+        });
+        players.truncate(size);
+        players
+    }
+}
+
+impl Drop for MatchHistory {
+    /// Matches finished right before shutdown are written as well
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            writer.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    fn record(word: &str, ended_at: u64) -> MatchRecord {
+        MatchRecord {
+            id: Uuid::new_v4(),
+            challenger: Uuid::new_v4(),
+            guesser: Uuid::new_v4(),
+            word: word.to_string(),
+            language: Some("en".to_string()),
+            mode: GameMode::Wordle,
+            attempts: 3,
+            hints: vec![HintRecord {
+                text: "pet".to_string(),
+                sent_at: ended_at - 1,
+            }],
+            end_reason: EndReason::Solved,
+            started_at: ended_at - 10,
+            ended_at,
+        }
+    }
+
+    fn reopen_keeps_latest_matches(storage: StorageConfig) {
+        let config = HistoryConfig {
+            retention: 2,
+            storage,
+        };
+        let guesser = Uuid::new_v4();
+        let records = [record("cat", 100), record("dog", 200), record("owl", 300)]
+            .map(|record| MatchRecord { guesser, ..record });
+        let mut history = MatchHistory::open(&config).unwrap();
+        for record in &records {
+            history.record(record.clone()).unwrap();
+        }
+        assert_eq!(history.records, records[1..].to_vec());
+        let stats = history.stats(&guesser);
+        drop(history);
+
+        let history = MatchHistory::open(&config).unwrap();
+        assert_eq!(history.records, records[1..].to_vec());
+        assert_eq!(history.stats(&guesser), stats);
+        assert_eq!(stats.games_as_guesser, 3);
+    }
+
+    #[test]
+    fn stats_are_counted_from_finished_matches() {
+        let mut history = MatchHistory::default();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let reasons = [
+            EndReason::Solved,
+            EndReason::Solved,
+            EndReason::GivenUp,
+            EndReason::Cancelled,
+            EndReason::Solved,
+        ];
+        for (idx, end_reason) in reasons.into_iter().enumerate() {
+            let record = MatchRecord {
+                challenger: bob,
+                guesser: alice,
+                end_reason,
+                ..record("cat", 100 + idx as u64)
+            };
+            history.record(record).unwrap();
+        }
+        history
+            .record(MatchRecord {
+                challenger: alice,
+                guesser: bob,
+                ..record("dog", 200)
+            })
+            .unwrap();
+
+        assert_eq!(
+            history.stats(&alice),
+            PlayerStats {
+                games_as_guesser: 4,
+                games_as_challenger: 1,
+                solved: 3,
+                attempts: 12,
+                hints: 4,
+                longest_streak: 2,
+            }
+        );
+        assert_eq!(history.stats(&Uuid::new_v4()), PlayerStats::default());
+        let leaderboard = history
+            .leaderboard(10)
+            .into_iter()
+            .map(|(player_id, _)| player_id)
+            .collect::<Vec<Uuid>>();
+        assert_eq!(leaderboard, [alice, bob]);
+        assert_eq!(history.leaderboard(1).len(), 1);
+    }
+
+    #[test]
+    fn jsonl_history_is_loaded_again() {
+        let path = env::temp_dir().join(format!("luxonis-history-{}.jsonl", Uuid::new_v4()));
+        reopen_keeps_latest_matches(StorageConfig::Jsonl { path: path.clone() });
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn sqlite_history_is_loaded_again() {
+        let path = env::temp_dir().join(format!("luxonis-history-{}.db", Uuid::new_v4()));
+        reopen_keeps_latest_matches(StorageConfig::Sqlite { path: path.clone() });
+        let _ = std::fs::remove_file(path);
+    }
+}
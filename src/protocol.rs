@@ -1,22 +1,262 @@
+use std::{fmt, ops::BitOr};
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Version of the protocol implemented by this build
+pub const PROTOCOL_VERSION: u32 = 13;
+/// Oldest protocol version this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+/// Optional features supported by this build
+pub const SUPPORTED_CAPABILITIES: Capabilities = Capabilities::HEARTBEAT;
+
+// First protocol versions with the given feature.
+// Peers that negotiated an older version never see its fields or messages
+/// `Ping` and `Pong`, used only with the `HEARTBEAT` capability
+pub const HEARTBEAT_VERSION: u32 = 4;
+/// Resume token in `AssignId` and the `Resume` request
+pub const RESUME_VERSION: u32 = 5;
+/// `RequestMatch` invites the opponent instead of starting the match
+pub const INVITES_VERSION: u32 = 6;
+/// `InvalidWord` error
+pub const WORD_RULES_VERSION: u32 = 7;
+/// `UnknownWord` error
+pub const DICTIONARY_VERSION: u32 = 8;
+/// Game mode in `RequestMatch` and `MatchInvite`, letter feedback of wordle guesses
+pub const GAME_MODES_VERSION: u32 = 9;
+/// Hangman mode and `HangmanProgress`
+pub const HANGMAN_VERSION: u32 = 10;
+/// Limits in `RequestMatch` and `MatchInvite`, end reason in `MatchEnded`
+pub const LIMITS_VERSION: u32 = 11;
+/// Word and duration in `MatchEnded`, separate end reasons for players leaving
+pub const OUTCOME_VERSION: u32 = 12;
+/// `GetStats`, `GetLeaderboard` and their answers
+pub const STATS_VERSION: u32 = 13;
+
+/// Bitset of optional protocol features.
+/// Each side announces what it supports in `Hello` and only the intersection is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 0);
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 1);
+    pub const SPECTATE: Capabilities = Capabilities(1 << 2);
+
+    const NAMED: [(Capabilities, &'static str); 3] = [
+        (Capabilities::COMPRESSION, "compression"),
+        (Capabilities::HEARTBEAT, "heartbeat"),
+        (Capabilities::SPECTATE, "spectate"),
+    ];
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Capabilities supported by both sides
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = Capabilities::NAMED
+            .iter()
+            .filter(|(capability, _)| self.contains(*capability))
+            .map(|(_, name)| *name)
+            .collect::<Vec<&str>>();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", names.join(", "))
+        }
+    }
+}
+
+/// Pick the protocol version both sides can speak.
+/// Returns `None` if the peer is too old
+pub fn negotiate_version(peer_version: u32) -> Option<u32> {
+    let version = peer_version.min(PROTOCOL_VERSION);
+    (version >= MIN_PROTOCOL_VERSION).then_some(version)
+}
+
+/// Pick the capabilities both sides can use with the negotiated `version`.
+/// Heartbeats are sent as `Ping` and `Pong`, which versions before `HEARTBEAT_VERSION` don't know
+pub fn negotiate_capabilities(version: u32, peer_capabilities: Capabilities) -> Capabilities {
+    let capabilities = peer_capabilities.intersection(SUPPORTED_CAPABILITIES);
+    if version >= HEARTBEAT_VERSION {
+        capabilities
+    } else {
+        Capabilities(capabilities.0 & !Capabilities::HEARTBEAT.0)
+    }
+}
+
+/// Data the client needs to prove it knows the password without sending it.
+/// Client derives a key with PBKDF2-HMAC-SHA256 from the password, `salt` and `iterations`
+/// and answers with HMAC-SHA256 of the `nonce` keyed by the derived key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordChallenge {
+    /// Random bytes unique for every login attempt
+    pub nonce: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+}
+
+/// Rules the match is played by, picked by the challenger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GameMode {
+    /// Guesser only learns whether the guess was right
+    #[default]
+    Classic,
+    /// Guesses must have the length of the word and every letter gets a `LetterFeedback`
+    Wordle,
+    /// Guesser reveals the word letter by letter and loses a life for every wrong guess
+    Hangman,
+}
+
+/// Limits of a match picked by the challenger, `0` means no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MatchLimits {
+    /// Guesser loses after this many wrong guesses
+    pub max_attempts: u32,
+    /// Match ends this many seconds after it started
+    pub time_limit_secs: u32,
+    /// Match ends if the guesser doesn't guess within this many seconds
+    pub guess_timeout_secs: u32,
+}
+
+impl fmt::Display for MatchLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut limits = Vec::new();
+        if self.max_attempts > 0 {
+            limits.push(format!("{} attempts", self.max_attempts));
+        }
+        if self.time_limit_secs > 0 {
+            limits.push(format!("{} seconds in total", self.time_limit_secs));
+        }
+        if self.guess_timeout_secs > 0 {
+            limits.push(format!("{} seconds per guess", self.guess_timeout_secs));
+        }
+        if limits.is_empty() {
+            write!(f, "no limits")
+        } else {
+            write!(f, "{}", limits.join(", "))
+        }
+    }
+}
+
+/// Why a match has ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    Solved,
+    GivenUp,
+    /// Server has shut down while the match was in progress
+    Cancelled,
+    /// Hangman guesser has run out of lives
+    OutOfLives,
+    /// Guesser has used up all attempts allowed by `MatchLimits`
+    OutOfAttempts,
+    /// Time limit or guess timeout of `MatchLimits` has passed
+    TimedOut,
+    /// Guesser has left the game or their session has expired
+    GuesserLeft,
+    /// Challenger has left the game or their session has expired
+    ChallengerLeft,
+}
+
+/// Summary of a finished match sent to both players
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchOutcome {
+    pub reason: EndReason,
+    pub attempts: u32,
+    pub hints: u32,
+    /// Word the guesser was looking for, revealed once the match is over
+    pub word: String,
+    /// Seconds since the match has started
+    pub duration_secs: u32,
+}
+
+/// Results of a player over all finished matches, matches cancelled by the server don't count
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PlayerStats {
+    pub games_as_guesser: u32,
+    pub games_as_challenger: u32,
+    /// Matches solved as the guesser
+    pub solved: u32,
+    /// Attempts made in all matches as the guesser
+    pub attempts: u32,
+    /// Hints received in all matches as the guesser
+    pub hints: u32,
+    /// Most matches solved in a row as the guesser
+    pub longest_streak: u32,
+}
+
+/// How a letter of a guess matches the word in wordle mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LetterFeedback {
+    /// Letter is not in the word, or all its occurrences are already marked
+    Absent,
+    /// Letter is in the word at another position
+    Present,
+    /// Letter is in the word at this position
+    Correct,
+}
+
 /// Error messages for clients
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 pub enum ClientRequestError {
     CannotCreateMatch,
     Match404,
     PermissionDenied,
+    /// Client protocol version is not supported by the server
+    UnsupportedVersion,
+    /// Server couldn't decode the request.
+    /// Carries the opcode of the request if the message wasn't empty
+    UnknownRequest(Option<u8>),
+    /// Client has run out of password attempts, server disconnects it right after
+    TooManyAttempts,
+    /// Request requires the client to authenticate first
+    NotAuthenticated,
+    /// Account is already logged in from another connection
+    AccountInUse,
+    /// Resume token is unknown or its grace period has expired
+    ResumeFailed,
+    /// Invite doesn't exist anymore or it wasn't addressed to the player
+    InviteNotFound,
+    /// Challenge word doesn't follow the word rules of the server,
+    /// or a wordle guess doesn't have the length of the word
+    /// (reason)
+    InvalidWord(String),
+    /// Word or guess isn't in the dictionary of the server, such guesses don't count as attempts
+    UnknownWord,
 }
 
 /// Messages that are passed from server to the clients
-#[derive(Serialize, Deserialize, Debug)]
-#[repr(u8)]
+/// Wire format of every variant is defined in `codec`
+#[derive(Debug)]
 pub enum ServerMessage {
-    AskPassword,
+    /// First message of every connection
+    /// (protocol_version, capabilities)
+    Hello(u32, Capabilities),
+    /// Server asks for the password of the user announced by `AnswerUsername`
+    AskPassword(PasswordChallenge),
     WrongPassword,
     /// ID has been assigned to a new connected client
-    AssignId(Uuid),
+    /// (player_id, resume_token)
+    /// Token lets the client take the session over from a new connection with `Resume`
+    AssignId(Uuid, Vec<u8>),
     BadRequest(ClientRequestError),
     /// Response to `GetOpponents`
     ListOpponents(Vec<Uuid>),
@@ -25,27 +265,124 @@ pub enum ServerMessage {
     /// Response for Guesser that the Match(Uuid) has been started
     MatchStarted(Uuid),
     /// Status message for Challenger about progress of the match
-    /// (match_id, attempts, hints, latest_attempt)
-    MatchAttempt(Uuid, u32, u32, String),
-    IncorrectGuess(Uuid, u32),
+    /// (match_id, attempts, hints, latest_attempt, feedback)
+    /// Feedback is empty unless the match is played in wordle mode
+    MatchAttempt(Uuid, u32, u32, String, Vec<LetterFeedback>),
+    /// (match_id, attempts, feedback)
+    IncorrectGuess(Uuid, u32, Vec<LetterFeedback>),
     /// Challenger can send a hint to Guesser
     /// (match_id, hint)
     MatchHint(Uuid, String),
-    /// Match can end by either giving up or guessing the correct word
-    /// (match_id, attempts, hints, solved)
-    MatchEnded(Uuid, u32, u32, bool),
+    /// Match has ended, sent to both players
+    /// (match_id, outcome)
+    MatchEnded(Uuid, MatchOutcome),
     Disconnect,
+    /// Server asks the client to log in
+    AskUsername,
+    /// Liveness check, answered with `Pong`. Only sent with the heartbeat capability
+    Ping,
+    /// Response to client's `Ping`
+    Pong,
+    /// Opponent is invited to a match and should answer with `AcceptInvite` or `DeclineInvite`
+    /// (challenger_id, invite_id, game_mode, limits)
+    MatchInvite(Uuid, Uuid, GameMode, MatchLimits),
+    /// Response for Challenger that the invite(Uuid) has been sent to the opponent
+    InviteSent(Uuid),
+    /// Opponent has declined the invite(Uuid)
+    InviteDeclined(Uuid),
+    /// Invite(Uuid) has expired or one of the players left, sent to both players
+    InviteCancelled(Uuid),
+    /// State of a hangman match sent to both players when it starts and after every guess
+    /// (match_id, attempts, masked_word, lives, tried_letters)
+    HangmanProgress(Uuid, u32, String, u32, String),
+    /// Response to `GetStats`
+    /// (player_id, stats)
+    PlayerStats(Uuid, PlayerStats),
+    /// Response to `GetLeaderboard`, best players first
+    /// (player_id, stats)
+    Leaderboard(Vec<(Uuid, PlayerStats)>),
 }
 
 /// Messages from clients
-#[derive(Serialize, Deserialize, Debug)]
-#[repr(u8)]
+#[derive(Debug)]
 pub enum ClientMessage {
-    AnswerPassword(String),
+    /// Response to server `Hello`
+    /// (protocol_version, capabilities)
+    Hello(u32, Capabilities),
+    /// Response to `AskPassword` with proof of knowing the password
+    /// (HMAC of the challenge nonce)
+    AnswerPassword(Vec<u8>),
     GetOpponents,
-    RequestMatch(Uuid, String),
+    /// (opponent_id, guess_word, game_mode, limits)
+    RequestMatch(Uuid, String, GameMode, MatchLimits),
     GuessAttempt(Uuid, String),
     SendHint(Uuid, String),
     GiveUp(Uuid),
     LeaveGame,
+    /// Response to `AskUsername`
+    AnswerUsername(String),
+    /// Liveness check, answered with `Pong`. Only sent with the heartbeat capability
+    Ping,
+    /// Response to server's `Ping`
+    Pong,
+    /// Reattach to the session of a dropped connection instead of logging in
+    /// (resume_token from the latest `AssignId`)
+    Resume(Vec<u8>),
+    /// Response to `MatchInvite`, starts the match
+    AcceptInvite(Uuid),
+    /// Response to `MatchInvite`, challenger is let know
+    DeclineInvite(Uuid),
+    /// Ask for statistics of the player(Uuid), answered with `PlayerStats`
+    GetStats(Uuid),
+    /// Ask for the best players, answered with `Leaderboard`
+    GetLeaderboard,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn too_old_peers_are_refused() {
+        assert_eq!(negotiate_version(0), None);
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION - 1), None);
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION),
+            Some(MIN_PROTOCOL_VERSION)
+        );
+    }
+
+    #[test]
+    fn lower_common_version_is_picked() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION), Some(PROTOCOL_VERSION));
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(negotiate_version(u32::MAX), Some(PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn only_common_capabilities_are_used() {
+        let peer = Capabilities::HEARTBEAT | Capabilities::SPECTATE;
+        let common = peer.intersection(Capabilities::COMPRESSION | Capabilities::HEARTBEAT);
+        assert_eq!(common, Capabilities::HEARTBEAT);
+        assert!(common.contains(Capabilities::HEARTBEAT));
+        assert!(!common.contains(Capabilities::SPECTATE));
+        assert_eq!(
+            Capabilities(u32::MAX).intersection(SUPPORTED_CAPABILITIES),
+            SUPPORTED_CAPABILITIES
+        );
+        assert_eq!(peer.intersection(Capabilities::NONE), Capabilities::NONE);
+        assert_eq!(common.to_string(), "heartbeat");
+        assert_eq!(Capabilities::NONE.to_string(), "none");
+        assert_eq!(
+            negotiate_capabilities(HEARTBEAT_VERSION, peer),
+            Capabilities::HEARTBEAT
+        );
+        assert_eq!(
+            negotiate_capabilities(HEARTBEAT_VERSION - 1, peer),
+            Capabilities::NONE
+        );
+    }
 }
@@ -1,90 +1,168 @@
-use connection::Connection;
-use log::{debug, error, info, trace};
+use accounts::Accounts;
+use admin::run_accounts_command;
+use clap::Parser;
+use dictionary::Dictionaries;
+use history::MatchHistory;
+use log::{debug, error, info, trace, warn};
 use protocol::ServerMessage;
-use server_connection::{handle_client, react_to_client_msg};
+use server_config::{ServerArgs, ServerCommand, ServerConfig};
+use server_connection::{
+    cancel_matches, expire_invites, expire_matches, expire_suspended_sessions, react_to_client_msg,
+    react_to_connection_closed, Connection, ConnectionEvent, SessionState,
+};
+use server_listener::{accept_clients, bind_listener, remove_socket_files};
 use server_state::ServerState;
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    process,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
-    fs::remove_file,
-    net::{TcpListener, UnixListener},
     select, signal,
     sync::{
         mpsc::{self},
         RwLock,
     },
+    time::{interval, sleep_until},
 };
 use uuid::Uuid;
 
+mod accounts;
+mod admin;
+mod auth;
+mod codec;
 mod connection;
+mod dictionary;
+mod framing;
+mod history;
 mod protocol;
+mod server_config;
 mod server_connection;
+mod server_listener;
 mod server_state;
-
-const TCP_ADDR: &str = "127.0.0.1:3301";
-const UNIX_ADDR: &str = "/tmp/luxonis.sock";
+mod server_tls;
+mod tls;
+mod validation;
 
 type ActiveConnections = Arc<RwLock<HashMap<Uuid, Connection>>>;
 
+/// How often suspended sessions and match invites are checked for expiry
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 ///  Server application for "guess a word" game
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    // Bind the listener to the address
-    let tcp_listener = TcpListener::bind(TCP_ADDR).await.unwrap();
-    debug!("TCP listener started at: {TCP_ADDR}");
-    let _ = remove_file(UNIX_ADDR).await; // Clean up if the file already exists.
-    let unix_listener = UnixListener::bind(UNIX_ADDR).unwrap();
-    debug!("TCP listener started at: {UNIX_ADDR}");
-
-    let server_state = Arc::new(RwLock::new(ServerState::default()));
+    let args = ServerArgs::parse();
+    let config = ServerConfig::load(&args).unwrap_or_else(|e| {
+        error!("Invalid server configuration: {e}");
+        process::exit(1);
+    });
+
+    if let Some(ServerCommand::Accounts { args }) = &args.command {
+        if let Err(e) = run_accounts_command(args, &config.accounts_file) {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+        return;
+    }
+
+    let accounts = Accounts::load(&config.accounts_file).unwrap_or_else(|e| {
+        error!("{e}");
+        process::exit(1);
+    });
+    log_accounts(&accounts, &config);
+
+    let dictionaries =
+        Dictionaries::load(&config.dictionaries, &config.words).unwrap_or_else(|e| {
+            error!("Unable to load dictionaries: {e}");
+            process::exit(1);
+        });
+    log_dictionaries(&dictionaries);
+    let history = MatchHistory::open(&config.history).unwrap_or_else(|e| {
+        error!("Unable to open match history: {e}");
+        process::exit(1);
+    });
+    info!("Loaded {} finished matches", history.len());
+    let server_state = Arc::new(RwLock::new(ServerState {
+        dictionaries,
+        history,
+        accounts,
+        ..ServerState::default()
+    }));
     let mut active_connections: ActiveConnections =
         Arc::new(RwLock::new(HashMap::<Uuid, Connection>::new()));
 
-    let (tx, mut rx) = mpsc::channel(100);
+    let (tx, mut rx) = mpsc::channel(config.channels.events);
+
+    // Bind all listeners before accepting anyone so a misconfigured one stops the server right away
+    let mut listeners = Vec::new();
+    for listener_config in &config.listeners {
+        match bind_listener(listener_config) {
+            Ok(listener) => {
+                debug!("Listening on {listener_config}");
+                listeners.push(listener);
+            }
+            Err(e) => {
+                error!("{e}");
+                // Only clean up after the listeners bound so far
+                remove_socket_files(&config.listeners[..listeners.len()]);
+                process::exit(1);
+            }
+        }
+    }
+    for listener in listeners {
+        tokio::spawn(accept_clients(
+            listener,
+            tx.clone(),
+            active_connections.clone(),
+            config.channels.connection,
+            config.heartbeat,
+        ));
+    }
 
     let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
         .expect("Failed to register SIGTERM handler");
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to register SIGHUP handler");
+    let mut expiry = interval(EXPIRY_CHECK_INTERVAL);
 
     loop {
+        // Wake up right when the first match runs out of time
+        let match_deadline = server_state.read().await.next_match_deadline();
         select! {
-            // Handle incoming TCP connections.
-            tcp_conn = tcp_listener.accept() => {
-                match tcp_conn {
-                    Ok((stream, _addr)) => {
-                        // let mut connections = active_connections.clone();
-                        let _ = handle_client(stream, tx.clone(), &mut active_connections).await;
-                    }
-                    Err(e) => {
-                        error!("Failed to accept TCP connection: {}", e);
-                    }
-                }
-            },
-            // Handle incoming Unix socket connections.
-            unix_conn = unix_listener.accept() => {
-                match unix_conn {
-                    Ok((stream, _addr)) => {
-                        // let mut connections = active_connections.clone();
-                        let _ = handle_client(stream, tx.clone(), &mut active_connections).await;
-                    }
-
-                    Err(e) => {
-                        error!("Failed to accept Unix socket connection: {}", e);
-                    }
-                }
-            },
             rx_msg = rx.recv() => {
                 let mut connections = active_connections.clone();
                 let mut server_state = server_state.write().await;
                 trace!("Received message: {:?}",rx_msg);
                 match rx_msg {
-                    Some((player_id, msg)) => {
-                      let _ = react_to_client_msg(&player_id, msg, &mut connections, &mut server_state).await;
+                    Some((connection_id, ConnectionEvent::Message(msg))) => {
+                      let _ = react_to_client_msg(&connection_id, msg, &mut connections, &mut server_state, &config).await;
+                    }
+                    Some((connection_id, ConnectionEvent::Closed)) => {
+                        react_to_connection_closed(&connection_id, &mut connections, &mut server_state, &config).await;
                     }
                     None => {
                         error!("Invalid msg sent to receiver");
                     }
                 }
             },
+            _ = expiry.tick() => {
+                let mut connections = active_connections.clone();
+                let mut server_state = server_state.write().await;
+                expire_suspended_sessions(&mut connections, &mut server_state, config.resume_grace()).await;
+                expire_invites(&mut connections, &mut server_state, config.invite_timeout()).await;
+            }
+            _ = sleep_until(match_deadline.unwrap_or_else(Instant::now).into()), if match_deadline.is_some() => {
+                let mut connections = active_connections.clone();
+                let mut server_state = server_state.write().await;
+                expire_matches(&mut connections, &mut server_state, Instant::now()).await;
+            }
+            _ = hangup.recv() => {
+                reload_accounts(&config, &server_state).await;
+                reload_dictionaries(&config, &server_state).await;
+            }
             _ = signal::ctrl_c() => {
                 break;
             }
@@ -95,15 +173,73 @@ async fn main() {
     }
 
     info!("Gracefully shutting down luxonis game server");
+    cancel_matches(
+        &mut active_connections.clone(),
+        &mut *server_state.write().await,
+    )
+    .await;
     let _ = drop_all_connections(&mut active_connections).await;
-    let _ = remove_file(UNIX_ADDR).await; // Clean up if the file already exists.
+    remove_socket_files(&config.listeners);
+}
+
+/// Read the accounts file again, keep the current accounts if it can't be read
+async fn reload_accounts(config: &ServerConfig, server_state: &RwLock<ServerState>) {
+    let accounts_file = config.accounts_file.clone();
+    match tokio::task::spawn_blocking(move || Accounts::load(&accounts_file)).await {
+        Ok(Ok(accounts)) => {
+            info!("Accounts reloaded");
+            log_accounts(&accounts, config);
+            server_state.write().await.accounts = accounts;
+        }
+        Ok(Err(e)) => error!("Unable to reload accounts: {e}"),
+        Err(e) => error!("Unable to reload accounts: {e}"),
+    }
+}
+
+fn log_accounts(accounts: &Accounts, config: &ServerConfig) {
+    if accounts.is_empty() {
+        warn!(
+            "No accounts found in {}. Create one with `server accounts add <username>`",
+            config.accounts_file.display()
+        );
+    }
+}
+
+/// Load the word lists again, keep the current ones if any of them can't be read
+async fn reload_dictionaries(config: &ServerConfig, server_state: &RwLock<ServerState>) {
+    let dictionaries = config.dictionaries.clone();
+    let words = config.words.clone();
+    match tokio::task::spawn_blocking(move || Dictionaries::load(&dictionaries, &words)).await {
+        Ok(Ok(dictionaries)) => {
+            info!("Dictionaries reloaded");
+            log_dictionaries(&dictionaries);
+            server_state.write().await.dictionaries = dictionaries;
+        }
+        Ok(Err(e)) => error!("Unable to reload dictionaries: {e}"),
+        Err(e) => error!("Unable to reload dictionaries: {e}"),
+    }
+}
+
+fn log_dictionaries(dictionaries: &Dictionaries) {
+    for dictionary in dictionaries.iter() {
+        info!(
+            "Loaded {} words of {} dictionary",
+            dictionary.len(),
+            dictionary.language
+        );
+    }
 }
 
 /// Send a disconnect message to all connected players
 async fn drop_all_connections(
     active_connections: &mut ActiveConnections,
 ) -> Result<(), anyhow::Error> {
-    for connection in active_connections.write().await.values_mut() {
+    for connection in active_connections
+        .write()
+        .await
+        .values_mut()
+        .filter(|connection| !matches!(connection.session, SessionState::Suspended { .. }))
+    {
         connection.tx.send(ServerMessage::Disconnect).await?;
     }
     Ok(())
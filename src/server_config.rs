@@ -0,0 +1,651 @@
+use std::{
+    collections::HashSet,
+    env, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use tokio::net::unix::UCred;
+
+use crate::{
+    dictionary::DictionaryConfig,
+    history::{HistoryConfig, StorageConfig},
+    validation::WordRules,
+};
+
+const MAX_PASSWORD_ATTEMPTS_ENV: &str = "LUXONIS_MAX_PASSWORD_ATTEMPTS";
+const DEFAULT_MAX_PASSWORD_ATTEMPTS: u32 = 3;
+const ACCOUNTS_FILE_ENV: &str = "LUXONIS_ACCOUNTS_FILE";
+const DEFAULT_ACCOUNTS_FILE: &str = "luxonis_accounts.txt";
+const TLS_ADDR_ENV: &str = "LUXONIS_TLS_ADDR";
+const DEFAULT_TLS_ADDR: &str = "127.0.0.1:3302";
+const TLS_CERT_ENV: &str = "LUXONIS_TLS_CERT";
+const TLS_KEY_ENV: &str = "LUXONIS_TLS_KEY";
+const TLS_CLIENT_CA_ENV: &str = "LUXONIS_TLS_CLIENT_CA";
+const TRUSTED_UIDS_ENV: &str = "LUXONIS_TRUSTED_UIDS";
+const TRUSTED_GIDS_ENV: &str = "LUXONIS_TRUSTED_GIDS";
+const DEFAULT_TCP_ADDR: &str = "127.0.0.1:3301";
+const DEFAULT_UNIX_PATH: &str = "/tmp/luxonis.sock";
+const DEFAULT_BACKLOG: u32 = 1024;
+const DEFAULT_CHANNEL_CAPACITY: usize = 100;
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 15;
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 45;
+const DEFAULT_RESUME_GRACE_SECS: u64 = 60;
+const DEFAULT_INVITE_TIMEOUT_SECS: u64 = 60;
+
+/// Server application for "guess a word" game
+#[derive(Debug, Parser)]
+#[command(name = "server")]
+pub struct ServerArgs {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Listen for TCP connections on ADDR, can be repeated.
+    /// Replaces TCP and Unix listeners from the configuration file
+    #[arg(long = "tcp", value_name = "ADDR")]
+    pub tcp: Vec<SocketAddr>,
+    /// Listen for connections on Unix socket PATH, `@name` binds an abstract socket. Can be repeated.
+    /// Replaces TCP and Unix listeners from the configuration file
+    #[arg(long = "unix", value_name = "PATH")]
+    pub unix: Vec<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<ServerCommand>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ServerCommand {
+    /// Manage player accounts (add <username> | remove <username> | list)
+    Accounts {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
+/// Socket the server accepts clients on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ListenerConfig {
+    Tcp {
+        addr: SocketAddr,
+        #[serde(default = "default_backlog")]
+        backlog: u32,
+    },
+    Unix {
+        /// Path of the socket file, `@name` binds an abstract socket
+        path: PathBuf,
+        /// Permissions of the socket file, e.g. `0o660`
+        mode: Option<u32>,
+        /// uid the socket file is owned by
+        owner: Option<u32>,
+        /// gid the socket file is owned by
+        group: Option<u32>,
+        #[serde(default = "default_backlog")]
+        backlog: u32,
+    },
+    Tls {
+        addr: SocketAddr,
+        #[serde(default = "default_backlog")]
+        backlog: u32,
+        /// PEM file with the certificate chain of the server
+        cert_file: PathBuf,
+        /// PEM file with the private key of the server
+        key_file: PathBuf,
+        /// PEM file with CAs of client certificates. When set, clients must present a certificate
+        client_ca_file: Option<PathBuf>,
+    },
+}
+
+impl ListenerConfig {
+    fn tcp(addr: SocketAddr) -> Self {
+        ListenerConfig::Tcp {
+            addr,
+            backlog: DEFAULT_BACKLOG,
+        }
+    }
+
+    fn unix(path: PathBuf) -> Self {
+        ListenerConfig::Unix {
+            path,
+            mode: None,
+            owner: None,
+            group: None,
+            backlog: DEFAULT_BACKLOG,
+        }
+    }
+
+    fn backlog(&self) -> u32 {
+        match self {
+            ListenerConfig::Tcp { backlog, .. }
+            | ListenerConfig::Unix { backlog, .. }
+            | ListenerConfig::Tls { backlog, .. } => *backlog,
+        }
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.backlog() == 0 || self.backlog() > i32::MAX as u32 {
+            return Err(anyhow!(
+                "{self}: backlog must be between 1 and {}",
+                i32::MAX
+            ));
+        }
+        if let ListenerConfig::Unix {
+            path,
+            mode,
+            owner,
+            group,
+            ..
+        } = self
+        {
+            if path.as_os_str().is_empty() || path.as_os_str() == "@" {
+                return Err(anyhow!("Unix socket path cannot be empty"));
+            }
+            if is_abstract_socket(path) && (mode.is_some() || owner.is_some() || group.is_some()) {
+                return Err(anyhow!(
+                    "{self}: abstract sockets have no file, mode, owner and group cannot be set"
+                ));
+            }
+            if mode.is_some_and(|mode| mode > 0o7777) {
+                return Err(anyhow!("{self}: mode must be an octal number up to 0o7777"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ListenerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenerConfig::Tcp { addr, .. } => write!(f, "tcp://{addr}"),
+            ListenerConfig::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+            ListenerConfig::Tls { addr, .. } => write!(f, "tls://{addr}"),
+        }
+    }
+}
+
+/// Unix socket paths starting with `@` are bound in the abstract namespace
+pub fn is_abstract_socket(path: &Path) -> bool {
+    path.as_os_str().as_encoded_bytes().starts_with(b"@")
+}
+
+/// Capacities of the queues between connections and the game loop
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelCapacities {
+    /// Messages from all clients waiting for the game loop
+    pub events: usize,
+    /// Messages waiting to be sent to or processed from a single connection
+    pub connection: usize,
+}
+
+impl Default for ChannelCapacities {
+    fn default() -> Self {
+        Self {
+            events: DEFAULT_CHANNEL_CAPACITY,
+            connection: DEFAULT_CHANNEL_CAPACITY,
+        }
+    }
+}
+
+/// Liveness checks of connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How often clients with the heartbeat capability are pinged
+    pub interval_secs: u64,
+    /// Connections that send nothing for this long are closed.
+    /// Doesn't apply to clients without the heartbeat capability once they finish the handshake
+    pub idle_timeout_secs: u64,
+}
+
+impl HeartbeatConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+        }
+    }
+}
+
+/// Unix socket peers that are logged in by their credentials (`SO_PEERCRED`)
+/// without the password prompt. Peer is trusted if either its uid or gid is listed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedPeers {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl TrustedPeers {
+    pub fn allows(&self, cred: &UCred) -> bool {
+        self.uids.contains(&cred.uid()) || self.gids.contains(&cred.gid())
+    }
+}
+
+/// Contents of the TOML configuration file, every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    max_password_attempts: Option<u32>,
+    resume_grace_secs: Option<u64>,
+    invite_timeout_secs: Option<u64>,
+    accounts_file: Option<PathBuf>,
+    trusted_uids: Option<Vec<u32>>,
+    trusted_gids: Option<Vec<u32>>,
+    channels: Option<ChannelCapacities>,
+    heartbeat: Option<HeartbeatConfig>,
+    words: Option<WordRules>,
+    dictionaries: Option<Vec<DictionaryConfig>>,
+    history: Option<HistoryConfig>,
+    listeners: Option<Vec<ListenerConfig>>,
+}
+
+/// Runtime configuration of the server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Number of wrong passwords after which the client is disconnected
+    pub max_password_attempts: u32,
+    /// How long players keep their matches after their connection drops, `0` ends them right away
+    pub resume_grace_secs: u64,
+    /// Match invites that aren't accepted or declined in time are cancelled
+    pub invite_timeout_secs: u64,
+    /// File with player accounts managed by `server accounts`
+    pub accounts_file: PathBuf,
+    pub trusted_peers: TrustedPeers,
+    pub listeners: Vec<ListenerConfig>,
+    pub channels: ChannelCapacities,
+    pub heartbeat: HeartbeatConfig,
+    pub words: WordRules,
+    /// Word lists secrets and guesses are checked against, reloaded on SIGHUP
+    pub dictionaries: Vec<DictionaryConfig>,
+    /// Storage of finished matches
+    pub history: HistoryConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_password_attempts: DEFAULT_MAX_PASSWORD_ATTEMPTS,
+            resume_grace_secs: DEFAULT_RESUME_GRACE_SECS,
+            invite_timeout_secs: DEFAULT_INVITE_TIMEOUT_SECS,
+            accounts_file: PathBuf::from(DEFAULT_ACCOUNTS_FILE),
+            trusted_peers: TrustedPeers::default(),
+            listeners: vec![
+                ListenerConfig::tcp(DEFAULT_TCP_ADDR.parse().expect("valid default address")),
+                ListenerConfig::unix(PathBuf::from(DEFAULT_UNIX_PATH)),
+            ],
+            channels: ChannelCapacities::default(),
+            heartbeat: HeartbeatConfig::default(),
+            words: WordRules::default(),
+            dictionaries: Vec::new(),
+            history: HistoryConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+
+    pub fn invite_timeout(&self) -> Duration {
+        Duration::from_secs(self.invite_timeout_secs)
+    }
+
+    /// Create configuration from defaults overridden by the configuration file,
+    /// environment variables and command line arguments in this order
+    pub fn load(args: &ServerArgs) -> Result<Self, anyhow::Error> {
+        let mut config = ServerConfig::default();
+        if let Some(path) = &args.config {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow!("Unable to read {}: {e}", path.display()))?;
+            config
+                .apply_file(&content)
+                .map_err(|e| anyhow!("{}: {e}", path.display()))?;
+        }
+        config.apply_env()?;
+        if !args.tcp.is_empty() || !args.unix.is_empty() {
+            // TLS listeners can't be given on the command line, keep the configured ones
+            config
+                .listeners
+                .retain(|listener| matches!(listener, ListenerConfig::Tls { .. }));
+            config.listeners.extend(
+                args.tcp
+                    .iter()
+                    .map(|addr| ListenerConfig::tcp(*addr))
+                    .chain(
+                        args.unix
+                            .iter()
+                            .map(|path| ListenerConfig::unix(path.clone())),
+                    ),
+            );
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_file(&mut self, content: &str) -> Result<(), anyhow::Error> {
+        let file: FileConfig = toml::from_str(content)?;
+        if let Some(max_password_attempts) = file.max_password_attempts {
+            self.max_password_attempts = max_password_attempts;
+        }
+        if let Some(resume_grace_secs) = file.resume_grace_secs {
+            self.resume_grace_secs = resume_grace_secs;
+        }
+        if let Some(invite_timeout_secs) = file.invite_timeout_secs {
+            self.invite_timeout_secs = invite_timeout_secs;
+        }
+        if let Some(accounts_file) = file.accounts_file {
+            self.accounts_file = accounts_file;
+        }
+        if let Some(uids) = file.trusted_uids {
+            self.trusted_peers.uids = uids;
+        }
+        if let Some(gids) = file.trusted_gids {
+            self.trusted_peers.gids = gids;
+        }
+        if let Some(channels) = file.channels {
+            self.channels = channels;
+        }
+        if let Some(heartbeat) = file.heartbeat {
+            self.heartbeat = heartbeat;
+        }
+        if let Some(words) = file.words {
+            self.words = words;
+        }
+        if let Some(dictionaries) = file.dictionaries {
+            self.dictionaries = dictionaries;
+        }
+        if let Some(history) = file.history {
+            self.history = history;
+        }
+        if let Some(listeners) = file.listeners {
+            self.listeners = listeners;
+        }
+        Ok(())
+    }
+
+    fn apply_env(&mut self) -> Result<(), anyhow::Error> {
+        if let Ok(value) = env::var(MAX_PASSWORD_ATTEMPTS_ENV) {
+            self.max_password_attempts = value
+                .parse::<u32>()
+                .ok()
+                .filter(|attempts| *attempts > 0)
+                .ok_or(anyhow!(
+                    "{MAX_PASSWORD_ATTEMPTS_ENV} must be a positive number, got {value:?}"
+                ))?;
+        }
+        if let Ok(value) = env::var(ACCOUNTS_FILE_ENV) {
+            self.accounts_file = PathBuf::from(value);
+        }
+        if let Ok(value) = env::var(TRUSTED_UIDS_ENV) {
+            self.trusted_peers.uids = parse_id_list(TRUSTED_UIDS_ENV, &value)?;
+        }
+        if let Ok(value) = env::var(TRUSTED_GIDS_ENV) {
+            self.trusted_peers.gids = parse_id_list(TRUSTED_GIDS_ENV, &value)?;
+        }
+        match (env::var(TLS_CERT_ENV), env::var(TLS_KEY_ENV)) {
+            (Ok(cert_file), Ok(key_file)) => {
+                let addr = env::var(TLS_ADDR_ENV).unwrap_or(DEFAULT_TLS_ADDR.to_string());
+                self.listeners.push(ListenerConfig::Tls {
+                    addr: addr.parse().map_err(|_| {
+                        anyhow!("{TLS_ADDR_ENV} must be an IP address with port, got {addr:?}")
+                    })?,
+                    backlog: DEFAULT_BACKLOG,
+                    cert_file: PathBuf::from(cert_file),
+                    key_file: PathBuf::from(key_file),
+                    client_ca_file: env::var(TLS_CLIENT_CA_ENV).ok().map(PathBuf::from),
+                });
+            }
+            (Err(_), Err(_)) => {}
+            _ => {
+                return Err(anyhow!(
+                    "Both {TLS_CERT_ENV} and {TLS_KEY_ENV} must be set to enable TLS"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.max_password_attempts == 0 {
+            return Err(anyhow!("max_password_attempts must be a positive number"));
+        }
+        if self.invite_timeout_secs == 0 {
+            return Err(anyhow!("invite_timeout_secs must be a positive number"));
+        }
+        if self.channels.events == 0 || self.channels.connection == 0 {
+            return Err(anyhow!("Channel capacities must be positive numbers"));
+        }
+        if self.heartbeat.interval_secs == 0
+            || self.heartbeat.idle_timeout_secs <= self.heartbeat.interval_secs
+        {
+            return Err(anyhow!(
+                "Heartbeat interval must be positive and shorter than the idle timeout"
+            ));
+        }
+        self.words.check()?;
+        let mut languages = HashSet::new();
+        for dictionary in &self.dictionaries {
+            if dictionary.language.is_empty() || !languages.insert(&dictionary.language) {
+                return Err(anyhow!(
+                    "Dictionary languages must be non-empty and configured only once"
+                ));
+            }
+        }
+        if self.history.retention == 0 {
+            return Err(anyhow!("History retention must be a positive number"));
+        }
+        if let StorageConfig::Sqlite { path } | StorageConfig::Jsonl { path } =
+            &self.history.storage
+        {
+            if path.as_os_str().is_empty() {
+                return Err(anyhow!("History storage path cannot be empty"));
+            }
+        }
+        if self.listeners.is_empty() {
+            return Err(anyhow!("At least one listener has to be configured"));
+        }
+        let mut seen = HashSet::new();
+        for listener in &self.listeners {
+            listener.validate()?;
+            if !seen.insert(listener.to_string()) {
+                return Err(anyhow!("Listener {listener} is configured more than once"));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_backlog() -> u32 {
+    DEFAULT_BACKLOG
+}
+
+/// Parse comma separated list of numeric user or group IDs
+fn parse_id_list(name: &str, value: &str) -> Result<Vec<u32>, anyhow::Error> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            id.parse::<u32>().map_err(|_| {
+                anyhow!("{name} must be a comma separated list of numeric IDs, got {value:?}")
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn from_file(content: &str) -> Result<ServerConfig, anyhow::Error> {
+        let mut config = ServerConfig::default();
+        config.apply_file(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn parses_listeners_from_file() {
+        let config = from_file(indoc! {r#"
+            [[listeners]]
+            type = "tcp"
+            addr = "[::]:3301"
+
+            [[listeners]]
+            type = "unix"
+            path = "/run/luxonis/game.sock"
+            mode = 0o660
+            group = 1000
+            backlog = 16
+
+            [[listeners]]
+            type = "unix"
+            path = "@luxonis"
+
+            [channels]
+            events = 500
+
+            [heartbeat]
+            idle_timeout_secs = 120
+
+            [words]
+            min_length = 3
+            case_folding = true
+
+            [[dictionaries]]
+            language = "en"
+            path = "/usr/share/dict/words"
+
+            [history.storage]
+            type = "sqlite"
+            path = "/var/lib/luxonis/history.db"
+        "#})
+        .unwrap();
+
+        assert_eq!(
+            config.listeners,
+            vec![
+                ListenerConfig::tcp("[::]:3301".parse().unwrap()),
+                ListenerConfig::Unix {
+                    path: PathBuf::from("/run/luxonis/game.sock"),
+                    mode: Some(0o660),
+                    owner: None,
+                    group: Some(1000),
+                    backlog: 16,
+                },
+                ListenerConfig::unix(PathBuf::from("@luxonis")),
+            ]
+        );
+        assert_eq!(
+            config.channels,
+            ChannelCapacities {
+                events: 500,
+                connection: DEFAULT_CHANNEL_CAPACITY,
+            }
+        );
+        assert_eq!(
+            config.heartbeat,
+            HeartbeatConfig {
+                interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+                idle_timeout_secs: 120,
+            }
+        );
+        assert_eq!(
+            config.words,
+            WordRules {
+                min_length: 3,
+                case_folding: true,
+                ..WordRules::default()
+            }
+        );
+        assert_eq!(
+            config.dictionaries,
+            vec![DictionaryConfig {
+                language: "en".to_string(),
+                path: PathBuf::from("/usr/share/dict/words"),
+            }]
+        );
+        assert_eq!(
+            config.history,
+            HistoryConfig {
+                storage: StorageConfig::Sqlite {
+                    path: PathBuf::from("/var/lib/luxonis/history.db"),
+                },
+                ..HistoryConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_defaults_for_missing_fields() {
+        let config = from_file("max_password_attempts = 5").unwrap();
+        assert_eq!(config.max_password_attempts, 5);
+        assert_eq!(config.resume_grace_secs, DEFAULT_RESUME_GRACE_SECS);
+        assert_eq!(config.listeners, ServerConfig::default().listeners);
+    }
+
+    #[test]
+    fn command_line_listeners_keep_tls_listener() {
+        env::set_var(TLS_CERT_ENV, "/etc/luxonis/cert.pem");
+        env::set_var(TLS_KEY_ENV, "/etc/luxonis/key.pem");
+        let args = ServerArgs::parse_from(["server", "--tcp", "127.0.0.1:4000"]);
+        let config = ServerConfig::load(&args);
+        env::remove_var(TLS_CERT_ENV);
+        env::remove_var(TLS_KEY_ENV);
+
+        assert_eq!(
+            config.unwrap().listeners,
+            vec![
+                ListenerConfig::Tls {
+                    addr: DEFAULT_TLS_ADDR.parse().unwrap(),
+                    backlog: DEFAULT_BACKLOG,
+                    cert_file: PathBuf::from("/etc/luxonis/cert.pem"),
+                    key_file: PathBuf::from("/etc/luxonis/key.pem"),
+                    client_ca_file: None,
+                },
+                ListenerConfig::tcp("127.0.0.1:4000".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let invalid = [
+            "unknown = 1",
+            "listeners = []",
+            "invite_timeout_secs = 0",
+            "[channels]\nconnection = 0",
+            "[heartbeat]\ninterval_secs = 0",
+            "[heartbeat]\ninterval_secs = 30\nidle_timeout_secs = 30",
+            "[words]\nmin_length = 5\nmax_length = 4",
+            "[words]\nalphabet = \"ABC\"",
+            "[[dictionaries]]\nlanguage = \"\"\npath = \"words.txt\"",
+            "[[dictionaries]]\nlanguage = \"en\"\npath = \"a.txt\"\n[[dictionaries]]\nlanguage = \"en\"\npath = \"b.txt\"",
+            "[history]\nretention = 0",
+            "[history.storage]\ntype = \"jsonl\"",
+            "[[listeners]]\ntype = \"tcp\"\naddr = \"localhost\"",
+            "[[listeners]]\ntype = \"tcp\"\naddr = \"0.0.0.0:1\"\nbacklog = 0",
+            "[[listeners]]\ntype = \"unix\"\npath = \"@abstract\"\nmode = 0o600",
+            "[[listeners]]\ntype = \"unix\"\npath = \"/tmp/a.sock\"\nmode = 0o17777",
+            "[[listeners]]\ntype = \"tcp\"\naddr = \"0.0.0.0:1\"\n[[listeners]]\ntype = \"tcp\"\naddr = \"0.0.0.0:1\"",
+        ];
+        for content in invalid {
+            assert!(from_file(content).is_err(), "accepted {content:?}");
+        }
+    }
+}
@@ -1,110 +1,1066 @@
+use std::time::Duration;
+
 use anyhow::anyhow;
-use log::{debug, info, trace};
-use rmp_serde::Serializer;
-use serde::Serialize;
+use log::{debug, info, trace, warn};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
+    net::unix::UCred,
+    select,
     sync::mpsc::{self, Sender},
+    time::{interval_at, Instant, MissedTickBehavior},
 };
 use uuid::Uuid;
 
 use crate::{
-    connection::{handle_stream, Connection},
-    protocol::{ClientMessage, ClientRequestError, ServerMessage},
-    server_state::{MatchState, ServerState},
+    accounts::Accounts,
+    codec::MalformedMessage,
+    connection::{handle_stream, WireVersion},
+    dictionary::Dictionaries,
+    protocol::{
+        negotiate_capabilities, negotiate_version, Capabilities, ClientMessage, ClientRequestError,
+        EndReason, GameMode, MatchLimits, MatchOutcome, PasswordChallenge, ServerMessage,
+        DICTIONARY_VERSION, GAME_MODES_VERSION, HANGMAN_VERSION, INVITES_VERSION, LIMITS_VERSION,
+        MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SUPPORTED_CAPABILITIES, WORD_RULES_VERSION,
+    },
+    server_config::{HeartbeatConfig, ServerConfig},
+    server_state::{Invite, Match, MatchRole, ServerState},
     ActiveConnections,
 };
 
+/// Length of the random nonce in login challenges
+const NONCE_LEN: usize = 32;
+/// Number of players sent in `Leaderboard`
+const LEADERBOARD_SIZE: usize = 10;
+/// Length of the random token a session can be resumed with
+const RESUME_TOKEN_LEN: usize = 32;
+/// Messages kept for a suspended session, later ones are dropped
+const MAX_QUEUED_MESSAGES: usize = 256;
+/// Namespace of player IDs derived from uids of trusted Unix socket peers
+const PEER_NAMESPACE: Uuid = Uuid::from_u128(0x6c75786f_6e69_7350_8565_657263726564);
+
+/// Lifecycle of a client session on a single connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SessionState {
+    /// Client has not answered the password yet
+    #[default]
+    Unauthenticated,
+    Authenticated,
+    /// Client has requested to leave, session is being torn down
+    Leaving,
+    /// Connection dropped without `LeaveGame`.
+    /// Messages for the player are queued until the client resumes the session
+    Suspended {
+        since: Instant,
+    },
+}
+
+/// Login started by `AnswerUsername` waiting for the client's proof
+#[derive(Debug, Clone)]
+pub struct PendingLogin {
+    pub username: String,
+    /// Nonce sent in `AskPassword`, valid for a single answer
+    pub nonce: Vec<u8>,
+}
+
+/// Connected client
+/// `ActiveConnections` are keyed by connection ID which is different from the player ID
+/// as players keep the same ID across connections
+pub struct Connection {
+    /// Writes to the stream, which is closed once every sender is dropped.
+    /// Suspended sessions hold a sender of a closed channel
+    pub tx: Sender<ServerMessage>,
+    /// ID of the logged in player, `None` until the client authenticates
+    pub player_id: Option<Uuid>,
+    /// Negotiated protocol version, `None` until client answers `Hello`
+    pub protocol_version: Option<u32>,
+    /// Version the stream encodes messages with, follows `protocol_version`
+    pub wire_version: WireVersion,
+    /// Capabilities supported by both the server and the client
+    pub capabilities: Capabilities,
+    /// Number of wrong passwords the client has sent on this connection
+    pub failed_password_attempts: u32,
+    pub session: SessionState,
+    pub pending_login: Option<PendingLogin>,
+    /// Credentials of the peer process, only known for Unix socket connections
+    pub peer_cred: Option<UCred>,
+    /// Secret from the latest `AssignId`, the session can be resumed with it from another connection
+    pub resume_token: Option<Vec<u8>>,
+    /// Messages sent to the player while the session is suspended
+    pub outbox: Vec<ServerMessage>,
+}
+
+impl Connection {
+    pub fn new(tx: Sender<ServerMessage>) -> Self {
+        Self {
+            tx,
+            player_id: None,
+            protocol_version: None,
+            wire_version: WireVersion::default(),
+            capabilities: Capabilities::NONE,
+            failed_password_attempts: 0,
+            session: SessionState::Unauthenticated,
+            pending_login: None,
+            peer_cred: None,
+            resume_token: None,
+            outbox: Vec::new(),
+        }
+    }
+}
+
+/// Events passed from connections to the game loop
+#[derive(Debug)]
+pub enum ConnectionEvent {
+    Message(ClientMessage),
+    /// Stream has been closed, either by the server or abruptly by the client
+    Closed,
+}
+
 /// Handle new connection
 /// Create a new channel for communication with client
 /// Save the channel in `connections` `HashMap` for an ability push communicate messages to them when needed
 pub async fn handle_client<S>(
     stream: S,
-    main_tx: Sender<(Uuid, ClientMessage)>,
+    peer_cred: Option<UCred>,
+    main_tx: Sender<(Uuid, ConnectionEvent)>,
     connections: &mut ActiveConnections,
+    channel_capacity: usize,
+    heartbeat: HeartbeatConfig,
 ) -> Result<(), anyhow::Error>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    let player_id = Uuid::new_v4();
+    let connection_id = Uuid::new_v4();
 
     // Create a channel for sending messages to this client
-    let (client_tx, mut client_rx) = mpsc::channel::<ClientMessage>(100);
+    let (client_tx, mut client_rx) =
+        mpsc::channel::<Result<ClientMessage, MalformedMessage>>(channel_capacity);
 
-    let client_sender = handle_stream(stream, client_tx).await?;
+    let wire_version = WireVersion::default();
+    let client_sender =
+        handle_stream(stream, client_tx, channel_capacity, wire_version.clone()).await?;
 
     tokio::spawn({
         let conns = connections.clone();
+        // Weak sender doesn't keep the stream open once the connection is dropped
+        let client_sender = client_sender.downgrade();
         async move {
-            // Start receiving messages
-            while let Some(msg) = client_rx.recv().await {
-                let _ = main_tx.send((player_id, msg)).await;
-            }
-            // Remove the connection from the shared HashMap
-            {
-                let mut conns = conns.write().await;
-                info!("Connection with {} closed", player_id);
-                conns.remove(&player_id);
+            let mut heartbeat_timer =
+                interval_at(Instant::now() + heartbeat.interval(), heartbeat.interval());
+            heartbeat_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut last_seen = Instant::now();
+            loop {
+                select! {
+                    msg = client_rx.recv() => {
+                        let Some(msg) = msg else {
+                            break;
+                        };
+                        last_seen = Instant::now();
+                        match msg {
+                            Ok(ClientMessage::Ping) => {
+                                if let Some(client_sender) = client_sender.upgrade() {
+                                    let _ = client_sender.try_send(ServerMessage::Pong);
+                                }
+                            }
+                            Ok(ClientMessage::Pong) => {}
+                            Ok(msg) => {
+                                let _ = main_tx
+                                    .send((connection_id, ConnectionEvent::Message(msg)))
+                                    .await;
+                            }
+                            Err(malformed) => {
+                                // Requests that can't be decoded are answered right away
+                                // so the client can tell that something went wrong
+                                debug!("Malformed message from {connection_id}: {malformed}");
+                                if let Some(client_sender) = client_sender.upgrade() {
+                                    let _ = client_sender
+                                        .send(ServerMessage::BadRequest(
+                                            ClientRequestError::UnknownRequest(malformed.opcode),
+                                        ))
+                                        .await;
+                                }
+                            }
+                        }
+                    }
+                    _ = heartbeat_timer.tick() => {
+                        let Some(uses_heartbeat) = uses_heartbeat(&conns, &connection_id).await else {
+                            continue;
+                        };
+                        // Clients without heartbeat might be silent for a long time,
+                        // they are only expected to finish the handshake in time
+                        let expects_traffic = uses_heartbeat.unwrap_or(true);
+                        if expects_traffic && last_seen.elapsed() >= heartbeat.idle_timeout() {
+                            info!("Connection {connection_id} timed out");
+                            break;
+                        }
+                        if uses_heartbeat == Some(true) {
+                            if let Some(client_sender) = client_sender.upgrade() {
+                                // Don't wait for a stuck connection, the idle timeout takes care of it
+                                let _ = client_sender.try_send(ServerMessage::Ping);
+                            }
+                        }
+                    }
+                }
             }
+            // Game loop cleans up after the player and removes the connection
+            let _ = main_tx.send((connection_id, ConnectionEvent::Closed)).await;
         }
     });
 
     {
         let mut conns = connections.write().await;
-        conns.insert(
-            player_id,
-            Connection {
-                tx: client_sender.clone(),
-            },
-        );
+        let mut connection = Connection::new(client_sender.clone());
+        connection.peer_cred = peer_cred;
+        connection.wire_version = wire_version;
+        conns.insert(connection_id, connection);
     }
 
-    info!("Client connected: {}", player_id);
-    let _ = client_sender.send(ServerMessage::AskPassword).await;
+    match peer_cred {
+        Some(cred) => info!(
+            "Client connected: {} (uid {}, gid {})",
+            connection_id,
+            cred.uid(),
+            cred.gid()
+        ),
+        None => info!("Client connected: {}", connection_id),
+    }
+    let _ = client_sender
+        .send(ServerMessage::Hello(
+            PROTOCOL_VERSION,
+            SUPPORTED_CAPABILITIES,
+        ))
+        .await;
 
     Ok(())
 }
 
-/// Sends a message to specific player
-async fn send_message(
+/// Whether the connection negotiated the heartbeat capability,
+/// `Some(None)` if the handshake isn't done yet and `None` if the connection is gone
+async fn uses_heartbeat(
+    active_connections: &ActiveConnections,
+    connection_id: &Uuid,
+) -> Option<Option<bool>> {
+    active_connections
+        .read()
+        .await
+        .get(connection_id)
+        .map(|connection| {
+            connection
+                .protocol_version
+                .map(|_| connection.capabilities.contains(Capabilities::HEARTBEAT))
+        })
+}
+
+/// Sends a message over specific connection
+/// Messages for suspended sessions are queued until the client resumes the session
+async fn send_to_connection(
     active_connections: &mut ActiveConnections,
-    player_id: &Uuid,
+    connection_id: &Uuid,
     msg: ServerMessage,
 ) -> Result<(), anyhow::Error> {
     let mut connections = active_connections.write().await;
     let connection = connections
-        .get_mut(player_id)
-        .ok_or(anyhow!("Player does no longer exists"))?
-        .clone();
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    if let SessionState::Suspended { .. } = connection.session {
+        if connection.outbox.len() < MAX_QUEUED_MESSAGES {
+            trace!(
+                "Queueing {:?} for suspended connection {connection_id}",
+                msg
+            );
+            connection.outbox.push(msg);
+        } else {
+            warn!(
+                "Dropping {:?} for suspended connection {connection_id}",
+                msg
+            );
+        }
+        return Ok(());
+    }
+    let version = connection.protocol_version.unwrap_or(PROTOCOL_VERSION);
+    let Some(msg) = adapt_to_version(msg, version) else {
+        trace!("Client of connection {connection_id} doesn't need the message");
+        return Ok(());
+    };
+    let tx = connection.tx.clone();
     drop(connections);
 
-    trace!("Message about to be sent");
-    trace!("Before LOCK {:?}", msg);
-    let mut payload = Vec::new();
-    msg.serialize(&mut Serializer::new(&mut payload))?;
     trace!("About to send {:?}", msg);
-    connection.tx.send(msg).await?;
+    tx.send(msg).await?;
     trace!("Message sent");
     Ok(())
 }
 
+/// Replace messages that are newer than the client's protocol `version` with the closest older ones.
+/// Returns `None` if the client has no use for the message
+fn adapt_to_version(msg: ServerMessage, version: u32) -> Option<ServerMessage> {
+    match msg {
+        // Only words of match requests are rejected for clients without word rules or dictionaries
+        ServerMessage::BadRequest(ClientRequestError::InvalidWord(_))
+            if version < WORD_RULES_VERSION =>
+        {
+            Some(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch,
+            ))
+        }
+        ServerMessage::BadRequest(ClientRequestError::UnknownWord)
+            if version < DICTIONARY_VERSION =>
+        {
+            Some(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch,
+            ))
+        }
+        // Challengers without invites wait for `MatchAccepted` or a refusal
+        ServerMessage::InviteSent(_) if version < INVITES_VERSION => None,
+        ServerMessage::InviteDeclined(_) | ServerMessage::InviteCancelled(_)
+            if version < INVITES_VERSION =>
+        {
+            Some(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch,
+            ))
+        }
+        msg => Some(msg),
+    }
+}
+
+/// Whether a client of protocol `version` knows the game mode and the limits of a match
+fn can_play(version: u32, mode: GameMode, limits: &MatchLimits) -> bool {
+    let mode_version = match mode {
+        GameMode::Classic => MIN_PROTOCOL_VERSION,
+        GameMode::Wordle => GAME_MODES_VERSION,
+        GameMode::Hangman => HANGMAN_VERSION,
+    };
+    version >= mode_version && (version >= LIMITS_VERSION || *limits == MatchLimits::default())
+}
+
+/// Protocol version negotiated by the player's client
+async fn protocol_version_of(active_connections: &ActiveConnections, player_id: &Uuid) -> u32 {
+    active_connections
+        .read()
+        .await
+        .values()
+        .find(|connection| connection.player_id.as_ref() == Some(player_id))
+        .and_then(|connection| connection.protocol_version)
+        .unwrap_or(PROTOCOL_VERSION)
+}
+
+/// Find the connection the player is logged in with
+async fn find_player_connection(
+    active_connections: &ActiveConnections,
+    player_id: &Uuid,
+) -> Option<Uuid> {
+    active_connections
+        .read()
+        .await
+        .iter()
+        .find(|(_, connection)| connection.player_id.as_ref() == Some(player_id))
+        .map(|(connection_id, _)| *connection_id)
+}
+
+/// Sends a message to specific player
+async fn send_message(
+    active_connections: &mut ActiveConnections,
+    player_id: &Uuid,
+    msg: ServerMessage,
+) -> Result<(), anyhow::Error> {
+    let connection_id = find_player_connection(active_connections, player_id)
+        .await
+        .ok_or(anyhow!("Player does no longer exists"))?;
+    send_to_connection(active_connections, &connection_id, msg).await
+}
+
+/// Sends `Disconnect` to the client and closes the connection
+/// Stream is closed as soon as the pending messages are written
+async fn disconnect(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+) -> Result<(), anyhow::Error> {
+    let connection = active_connections
+        .write()
+        .await
+        .remove(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    info!("Disconnecting {connection_id}");
+    connection.tx.send(ServerMessage::Disconnect).await?;
+    Ok(())
+}
+
+/// Increase the number of wrong passwords on the connection and return the new count
+async fn register_failed_password(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+) -> Result<u32, anyhow::Error> {
+    let mut connections = active_connections.write().await;
+    let connection = connections
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    connection.failed_password_attempts += 1;
+    Ok(connection.failed_password_attempts)
+}
+
+/// Current session state of the connection and ID of the logged in player
+async fn session_state(
+    active_connections: &ActiveConnections,
+    connection_id: &Uuid,
+) -> Result<(SessionState, Option<Uuid>), anyhow::Error> {
+    active_connections
+        .read()
+        .await
+        .get(connection_id)
+        .map(|connection| (connection.session, connection.player_id))
+        .ok_or(anyhow!("Connection does no longer exists"))
+}
+
+async fn set_session_state(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    session: SessionState,
+) -> Result<(), anyhow::Error> {
+    let mut connections = active_connections.write().await;
+    let connection = connections
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    connection.session = session;
+    Ok(())
+}
+
+/// Mark the connection as authenticated by the player
+async fn log_in(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    player_id: &Uuid,
+) -> Result<(), anyhow::Error> {
+    let mut connections = active_connections.write().await;
+    let connection = connections
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    connection.player_id = Some(*player_id);
+    connection.session = SessionState::Authenticated;
+    Ok(())
+}
+
+/// Store negotiated protocol version and capabilities for the connection.
+/// Returns `false` if the handshake has already been done
+async fn complete_handshake(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    version: u32,
+    capabilities: Capabilities,
+) -> Result<bool, anyhow::Error> {
+    let mut connections = active_connections.write().await;
+    let connection = connections
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    if connection.protocol_version.is_some() {
+        return Ok(false);
+    }
+    let capabilities = negotiate_capabilities(version, capabilities);
+    connection.protocol_version = Some(version);
+    connection.wire_version.set(version);
+    connection.capabilities = capabilities;
+    debug!("Client {connection_id} speaks protocol v{version} with capabilities: {capabilities}");
+    Ok(true)
+}
+
+/// Check if the client has already negotiated the protocol version with `Hello`
+async fn has_completed_handshake(
+    active_connections: &ActiveConnections,
+    connection_id: &Uuid,
+) -> bool {
+    active_connections
+        .read()
+        .await
+        .get(connection_id)
+        .is_some_and(|connection| connection.protocol_version.is_some())
+}
+
+/// Remember the login challenge sent to the client
+async fn start_login(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    pending_login: PendingLogin,
+) -> Result<(), anyhow::Error> {
+    let mut connections = active_connections.write().await;
+    let connection = connections
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    connection.pending_login = Some(pending_login);
+    Ok(())
+}
+
+/// Take the login challenge, so every nonce can be answered only once
+async fn take_pending_login(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+) -> Result<Option<PendingLogin>, anyhow::Error> {
+    let mut connections = active_connections.write().await;
+    let connection = connections
+        .get_mut(connection_id)
+        .ok_or(anyhow!("Connection does no longer exists"))?;
+    Ok(connection.pending_login.take())
+}
+
+/// Create a login challenge for the account
+fn create_challenge(
+    accounts: &Accounts,
+    username: &str,
+) -> Result<PasswordChallenge, anyhow::Error> {
+    let (salt, iterations) = accounts.key_params(username);
+    Ok(PasswordChallenge {
+        nonce: random_bytes(NONCE_LEN)?,
+        salt,
+        iterations,
+    })
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut bytes = vec![0u8; len];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow!("Unable to generate random bytes: {e}"))?;
+    Ok(bytes)
+}
+
+/// Log the player in on the connection and send them their ID
+/// Suspended session of the player is taken over together with its matches.
+/// Returns `false` and answers with `AccountInUse` if the player is already logged in elsewhere
+async fn complete_login(
+    connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    player_id: &Uuid,
+    server_state: &mut ServerState,
+) -> Result<bool, anyhow::Error> {
+    match find_player_connection(connections, player_id).await {
+        Some(previous_id) if is_suspended(connections, &previous_id).await => {
+            take_over_session(connections, connection_id, &previous_id, server_state).await?;
+        }
+        Some(_) => {
+            send_to_connection(
+                connections,
+                connection_id,
+                ServerMessage::BadRequest(ClientRequestError::AccountInUse),
+            )
+            .await?;
+            return Ok(false);
+        }
+        None => {
+            log_in(connections, connection_id, player_id).await?;
+            server_state.add_available_player(player_id);
+            assign_id(connections, connection_id, player_id).await?;
+        }
+    }
+    Ok(true)
+}
+
+/// Send the player their ID with a new resume token which replaces the previous one
+async fn assign_id(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    player_id: &Uuid,
+) -> Result<(), anyhow::Error> {
+    let resume_token = random_bytes(RESUME_TOKEN_LEN)?;
+    {
+        let mut connections = active_connections.write().await;
+        let connection = connections
+            .get_mut(connection_id)
+            .ok_or(anyhow!("Connection does no longer exists"))?;
+        connection.resume_token = Some(resume_token.clone());
+    }
+    send_to_connection(
+        active_connections,
+        connection_id,
+        ServerMessage::AssignId(*player_id, resume_token),
+    )
+    .await
+}
+
+async fn is_suspended(active_connections: &ActiveConnections, connection_id: &Uuid) -> bool {
+    active_connections
+        .read()
+        .await
+        .get(connection_id)
+        .is_some_and(|connection| matches!(connection.session, SessionState::Suspended { .. }))
+}
+
+/// Find the logged in or suspended connection the resume token was issued for
+async fn find_resumable_session(
+    active_connections: &ActiveConnections,
+    resume_token: &[u8],
+) -> Option<Uuid> {
+    active_connections
+        .read()
+        .await
+        .iter()
+        .find(|(_, connection)| {
+            matches!(
+                connection.session,
+                SessionState::Authenticated | SessionState::Suspended { .. }
+            ) && connection
+                .resume_token
+                .as_deref()
+                .is_some_and(|expected| tokens_match(expected, resume_token))
+        })
+        .map(|(connection_id, _)| *connection_id)
+}
+
+/// Compare tokens in constant time
+fn tokens_match(expected: &[u8], token: &[u8]) -> bool {
+    expected.len() == token.len()
+        && expected
+            .iter()
+            .zip(token.iter())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Move the player of `previous_id` to the new connection and replay messages queued for them.
+/// Previous connection that is still open is disconnected.
+/// Returns the ID of the player
+async fn take_over_session(
+    active_connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    previous_id: &Uuid,
+    server_state: &mut ServerState,
+) -> Result<Uuid, anyhow::Error> {
+    let (player_id, outbox) = {
+        let mut connections = active_connections.write().await;
+        if !connections.contains_key(connection_id) {
+            return Err(anyhow!("Connection does no longer exists"));
+        }
+        let previous = connections
+            .remove(previous_id)
+            .ok_or(anyhow!("Connection does no longer exists"))?;
+        let player_id = previous
+            .player_id
+            .ok_or(anyhow!("Session of {previous_id} has no player"))?;
+        if !matches!(previous.session, SessionState::Suspended { .. }) {
+            info!("Disconnecting {previous_id}, its session moved to {connection_id}");
+            let _ = previous.tx.try_send(ServerMessage::Disconnect);
+        }
+        let connection = connections
+            .get_mut(connection_id)
+            .ok_or(anyhow!("Connection does no longer exists"))?;
+        connection.player_id = Some(player_id);
+        connection.session = SessionState::Authenticated;
+        (player_id, previous.outbox)
+    };
+
+    // Player coming back to an idle session can be challenged again
+    server_state.end_suspension(&player_id);
+    let in_match = server_state
+        .active_matches
+        .values()
+        .any(|active_match| active_match.role_of(&player_id).is_some());
+    if !in_match {
+        server_state.add_available_player(&player_id);
+    }
+
+    assign_id(active_connections, connection_id, &player_id).await?;
+    for msg in outbox {
+        send_to_connection(active_connections, connection_id, msg).await?;
+    }
+    Ok(player_id)
+}
+
+/// Credentials of the process on the other side of a Unix socket connection
+async fn peer_credentials(
+    active_connections: &ActiveConnections,
+    connection_id: &Uuid,
+) -> Option<UCred> {
+    active_connections
+        .read()
+        .await
+        .get(connection_id)
+        .and_then(|connection| connection.peer_cred)
+}
+
+/// Stable player ID of a peer logged in by its uid
+fn peer_player_id(uid: u32) -> Uuid {
+    Uuid::new_v5(&PEER_NAMESPACE, format!("uid:{uid}").as_bytes())
+}
+
+/// Check that the connection is allowed to log in and answer with an error if it isn't
+async fn can_log_in(
+    connections: &mut ActiveConnections,
+    connection_id: &Uuid,
+    session: SessionState,
+) -> Result<bool, anyhow::Error> {
+    // Clients that skip `Hello` predate protocol versioning
+    if !has_completed_handshake(connections, connection_id).await {
+        send_to_connection(
+            connections,
+            connection_id,
+            ServerMessage::BadRequest(ClientRequestError::UnsupportedVersion),
+        )
+        .await?;
+        disconnect(connections, connection_id).await?;
+        return Ok(false);
+    }
+    if session != SessionState::Unauthenticated {
+        send_to_connection(
+            connections,
+            connection_id,
+            ServerMessage::BadRequest(ClientRequestError::PermissionDenied),
+        )
+        .await?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
 /// Process messages from clients and update `server_state` accordingly
-/// React to messages and let other players know if there is an update
+/// Handles the handshake and authentication of the connection,
+/// requests of authenticated players are passed to `react_to_player_msg`
 pub async fn react_to_client_msg(
-    player_id: &Uuid,
+    connection_id: &Uuid,
     msg: ClientMessage,
     connections: &mut ActiveConnections,
     server_state: &mut ServerState,
+    config: &ServerConfig,
 ) -> Result<(), anyhow::Error> {
+    let (session, player_id) = session_state(connections, connection_id).await?;
+
     match msg {
-        ClientMessage::AnswerPassword(password) => {
+        ClientMessage::Hello(version, capabilities) => {
+            let Some(version) = negotiate_version(version) else {
+                info!("Client {connection_id} uses unsupported protocol version {version}");
+                send_to_connection(
+                    connections,
+                    connection_id,
+                    ServerMessage::BadRequest(ClientRequestError::UnsupportedVersion),
+                )
+                .await?;
+                disconnect(connections, connection_id).await?;
+                return Ok(());
+            };
+            if !complete_handshake(connections, connection_id, version, capabilities).await? {
+                send_to_connection(
+                    connections,
+                    connection_id,
+                    ServerMessage::BadRequest(ClientRequestError::PermissionDenied),
+                )
+                .await?;
+                return Ok(());
+            }
+            let trusted_peer = peer_credentials(connections, connection_id)
+                .await
+                .filter(|cred| config.trusted_peers.allows(cred));
+            if let Some(cred) = trusted_peer {
+                let player_id = peer_player_id(cred.uid());
+                if complete_login(connections, connection_id, &player_id, server_state).await? {
+                    info!(
+                        "Client {connection_id} logged in by peer credentials as uid {} ({player_id})",
+                        cred.uid()
+                    );
+                    return Ok(());
+                }
+                info!("Peer uid {} is already logged in", cred.uid());
+            }
+            send_to_connection(connections, connection_id, ServerMessage::AskUsername).await?;
+        }
+        ClientMessage::AnswerUsername(username) => {
+            if !can_log_in(connections, connection_id, session).await? {
+                return Ok(());
+            }
+            let challenge = create_challenge(&server_state.accounts, &username)?;
+            start_login(
+                connections,
+                connection_id,
+                PendingLogin {
+                    username,
+                    nonce: challenge.nonce.clone(),
+                },
+            )
+            .await?;
+            send_to_connection(
+                connections,
+                connection_id,
+                ServerMessage::AskPassword(challenge),
+            )
+            .await?;
+        }
+        ClientMessage::AnswerPassword(proof) => {
             debug!("password attempt");
-            if password.eq("password") {
-                let response = ServerMessage::AssignId(*player_id);
-                server_state.add_available_player(player_id);
-                send_message(connections, player_id, response).await?;
+            if !can_log_in(connections, connection_id, session).await? {
+                return Ok(());
+            }
+            // Password has to answer the challenge created for a username
+            let Some(login) = take_pending_login(connections, connection_id).await? else {
+                send_to_connection(
+                    connections,
+                    connection_id,
+                    ServerMessage::BadRequest(ClientRequestError::PermissionDenied),
+                )
+                .await?;
+                return Ok(());
+            };
+            let username = login.username.clone();
+
+            let verified_player =
+                server_state
+                    .accounts
+                    .verify_proof(&login.username, &login.nonce, &proof);
+            if let Some(player_id) = verified_player {
+                if complete_login(connections, connection_id, &player_id, server_state).await? {
+                    info!("Client {connection_id} logged in as {username} ({player_id})");
+                } else {
+                    info!("Account {username} is already logged in");
+                    send_to_connection(connections, connection_id, ServerMessage::AskUsername)
+                        .await?;
+                }
+            } else {
+                let failed_attempts = register_failed_password(connections, connection_id).await?;
+                info!(
+                    "Wrong credentials for {username} from {connection_id} ({failed_attempts} failed attempts)"
+                );
+                send_to_connection(connections, connection_id, ServerMessage::WrongPassword)
+                    .await?;
+                if failed_attempts >= config.max_password_attempts {
+                    send_to_connection(
+                        connections,
+                        connection_id,
+                        ServerMessage::BadRequest(ClientRequestError::TooManyAttempts),
+                    )
+                    .await?;
+                    disconnect(connections, connection_id).await?;
+                } else {
+                    send_to_connection(connections, connection_id, ServerMessage::AskUsername)
+                        .await?;
+                }
+            }
+        }
+        ClientMessage::Resume(resume_token) => {
+            if !can_log_in(connections, connection_id, session).await? {
+                return Ok(());
+            }
+            let Some(previous_id) = find_resumable_session(connections, &resume_token).await else {
+                info!("Client {connection_id} tried to resume an unknown or expired session");
+                send_to_connection(
+                    connections,
+                    connection_id,
+                    ServerMessage::BadRequest(ClientRequestError::ResumeFailed),
+                )
+                .await?;
+                send_to_connection(connections, connection_id, ServerMessage::AskUsername).await?;
+                return Ok(());
+            };
+            let player_id =
+                take_over_session(connections, connection_id, &previous_id, server_state).await?;
+            info!("Client {connection_id} resumed the session of {player_id}");
+        }
+        msg => match (session, player_id) {
+            (SessionState::Authenticated, Some(player_id)) => {
+                if matches!(msg, ClientMessage::LeaveGame) {
+                    set_session_state(connections, connection_id, SessionState::Leaving).await?;
+                }
+                react_to_player_msg(&player_id, msg, connections, server_state, config).await?;
+            }
+            _ if matches!(msg, ClientMessage::LeaveGame) => {
+                disconnect(connections, connection_id).await?;
+            }
+            _ => {
+                debug!("Rejecting {msg:?} from {connection_id} in {session:?} session");
+                send_to_connection(
+                    connections,
+                    connection_id,
+                    ServerMessage::BadRequest(ClientRequestError::NotAuthenticated),
+                )
+                .await?;
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// Clean up after a closed connection
+/// Players that didn't leave with `LeaveGame` keep their session for the resume grace period,
+/// afterwards they lose their matches the same way as if they left
+pub async fn react_to_connection_closed(
+    connection_id: &Uuid,
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+    config: &ServerConfig,
+) {
+    let mut conns = connections.write().await;
+    // Connections closed by `disconnect` are already gone
+    let Some(connection) = conns.get_mut(connection_id) else {
+        return;
+    };
+    info!("Connection {} closed", connection_id);
+    match (connection.session, connection.player_id) {
+        (SessionState::Authenticated, Some(player_id)) if config.resume_grace_secs > 0 => {
+            info!(
+                "Player {player_id} dropped the connection, session is kept for {}s",
+                config.resume_grace_secs
+            );
+            connection.session = SessionState::Suspended {
+                since: Instant::now(),
+            };
+            // Close the stream in case it's still open after an idle timeout, so the client notices
+            // and reconnects. Messages are queued in the outbox until the session is resumed
+            connection.tx = mpsc::channel(1).0;
+            drop(conns);
+            server_state.suspend_player(&player_id);
+        }
+        (SessionState::Authenticated, Some(player_id)) => {
+            conns.remove(connection_id);
+            drop(conns);
+            info!("Player {player_id} dropped the connection");
+            leave_game(&player_id, connections, server_state).await;
+        }
+        _ => {
+            conns.remove(connection_id);
+        }
+    }
+}
+
+/// End suspended sessions that haven't been resumed within `resume_grace`
+pub async fn expire_suspended_sessions(
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+    resume_grace: Duration,
+) {
+    let mut expired = Vec::new();
+    connections.write().await.retain(|_, connection| {
+        match (connection.session, connection.player_id) {
+            (SessionState::Suspended { since }, Some(player_id))
+                if since.elapsed() >= resume_grace =>
+            {
+                expired.push(player_id);
+                false
             }
+            _ => true,
+        }
+    });
+    for player_id in expired {
+        info!("Session of player {player_id} expired");
+        leave_game(&player_id, connections, server_state).await;
+    }
+}
+
+/// Refuse guesses that shouldn't count as an attempt
+fn check_guess(
+    active_match: &Match,
+    guess: &str,
+    dictionaries: &Dictionaries,
+) -> Result<(), ClientRequestError> {
+    let mut letters = guess.chars();
+    if let (GameMode::Hangman, Some(letter), None) =
+        (active_match.mode, letters.next(), letters.next())
+    {
+        if active_match.tried_letters.contains(&letter) {
+            return Err(ClientRequestError::InvalidWord(format!(
+                "letter {letter} has already been tried"
+            )));
         }
+        // Single letters are never in the dictionary
+        return Ok(());
+    }
+    if !dictionaries.allows(active_match.language.as_deref(), guess) {
+        return Err(ClientRequestError::UnknownWord);
+    }
+    let length = active_match.guess_word.chars().count();
+    if active_match.mode == GameMode::Wordle && guess.chars().count() != length {
+        return Err(ClientRequestError::InvalidWord(format!(
+            "guess must have {length} letters"
+        )));
+    }
+    Ok(())
+}
+
+fn match_ended(active_match: &Match, reason: EndReason) -> ServerMessage {
+    ServerMessage::MatchEnded(
+        active_match.id,
+        MatchOutcome {
+            reason,
+            attempts: active_match.attempts,
+            hints: active_match.hints.len() as u32,
+            word: active_match.guess_word.clone(),
+            duration_secs: active_match.duration_secs(),
+        },
+    )
+}
+
+fn hangman_progress(active_match: &Match) -> ServerMessage {
+    ServerMessage::HangmanProgress(
+        active_match.id,
+        active_match.attempts,
+        active_match.mask(),
+        active_match.lives,
+        active_match.tried_letters.iter().collect(),
+    )
+}
+
+/// Start the match the opponent has agreed to
+async fn start_match(
+    invite: Invite,
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+) -> Result<(), anyhow::Error> {
+    let Some(match_id) = server_state.create_new_match(
+        (&invite.challenger, &invite.opponent),
+        &invite.guess_word,
+        invite.language.as_deref(),
+        invite.mode,
+        invite.limits,
+    ) else {
+        // Challenger has started another match in the meantime
+        let _ = send_message(
+            connections,
+            &invite.challenger,
+            ServerMessage::InviteCancelled(invite.id),
+        )
+        .await;
+        send_message(
+            connections,
+            &invite.opponent,
+            ServerMessage::BadRequest(ClientRequestError::CannotCreateMatch),
+        )
+        .await?;
+        return Ok(());
+    };
+    // Players in a match can't answer their other invites
+    for player_id in [&invite.challenger, &invite.opponent] {
+        let invites = server_state.cancel_invites(player_id);
+        cancel_invites(&invites, connections).await;
+    }
+    send_message(
+        connections,
+        &invite.opponent,
+        ServerMessage::MatchStarted(match_id),
+    )
+    .await?;
+    send_message(
+        connections,
+        &invite.challenger,
+        ServerMessage::MatchAccepted(match_id),
+    )
+    .await?;
+    if invite.mode == GameMode::Hangman {
+        let active_match = &server_state.active_matches[&match_id];
+        for player_id in [&invite.opponent, &invite.challenger] {
+            send_message(connections, player_id, hangman_progress(active_match)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Process requests of authenticated players and update `server_state` accordingly
+/// React to messages and let other players know if there is an update
+async fn react_to_player_msg(
+    player_id: &Uuid,
+    msg: ClientMessage,
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+    config: &ServerConfig,
+) -> Result<(), anyhow::Error> {
+    match msg {
+        // Handled by `react_to_client_msg` before the player is known
+        ClientMessage::Hello(..)
+        | ClientMessage::AnswerUsername(..)
+        | ClientMessage::AnswerPassword(..)
+        | ClientMessage::Resume(..) => {}
+        // Answered by the connection task in `handle_client`
+        ClientMessage::Ping | ClientMessage::Pong => {}
         ClientMessage::GetOpponents => {
             let opponents = &server_state
                 .available_players
@@ -116,22 +1072,87 @@ pub async fn react_to_client_msg(
             let response = ServerMessage::ListOpponents(opponents.clone());
             send_message(connections, player_id, response).await?;
         }
-        ClientMessage::RequestMatch(opponent, guess_word) => {
-            if let Some(match_id) =
-                server_state.create_new_match((player_id, &opponent), &guess_word)
+        ClientMessage::GetStats(player) => {
+            let stats = server_state.history.stats(&player);
+            send_message(
+                connections,
+                player_id,
+                ServerMessage::PlayerStats(player, stats),
+            )
+            .await?;
+        }
+        ClientMessage::GetLeaderboard => {
+            let leaderboard = server_state.history.leaderboard(LEADERBOARD_SIZE);
+            send_message(
+                connections,
+                player_id,
+                ServerMessage::Leaderboard(leaderboard),
+            )
+            .await?;
+        }
+        ClientMessage::RequestMatch(opponent, guess_word, mode, limits) => {
+            // Clients of both players have to know the mode and the limits of the match
+            let challenger_version = protocol_version_of(connections, player_id).await;
+            let opponent_version = protocol_version_of(connections, &opponent).await;
+            if !can_play(challenger_version, mode, &limits)
+                || !can_play(opponent_version, mode, &limits)
             {
                 send_message(
                     connections,
-                    &opponent,
-                    ServerMessage::MatchStarted(match_id),
+                    player_id,
+                    ServerMessage::BadRequest(ClientRequestError::CannotCreateMatch),
                 )
                 .await?;
+                return Ok(());
+            }
+            let guess_word = match config.words.validate(&guess_word) {
+                Ok(guess_word) => guess_word,
+                Err(invalid) => {
+                    send_message(
+                        connections,
+                        player_id,
+                        ServerMessage::BadRequest(ClientRequestError::InvalidWord(
+                            invalid.to_string(),
+                        )),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            };
+            let language = server_state
+                .dictionaries
+                .language_of(&guess_word)
+                .map(str::to_string);
+            if language.is_none() && !server_state.dictionaries.is_empty() {
                 send_message(
                     connections,
                     player_id,
-                    ServerMessage::MatchAccepted(match_id),
+                    ServerMessage::BadRequest(ClientRequestError::UnknownWord),
+                )
+                .await?;
+                return Ok(());
+            }
+            if let Some(invite_id) = server_state.create_invite(
+                (player_id, &opponent),
+                &guess_word,
+                language.as_deref(),
+                mode,
+                limits,
+            ) {
+                if opponent_version < INVITES_VERSION {
+                    // Clients without invites are put into the match right away
+                    if let Some(invite) = server_state.take_invite(&invite_id, &opponent) {
+                        start_match(invite, connections, server_state).await?;
+                    }
+                    return Ok(());
+                }
+                send_message(
+                    connections,
+                    &opponent,
+                    ServerMessage::MatchInvite(*player_id, invite_id, mode, limits),
                 )
                 .await?;
+                send_message(connections, player_id, ServerMessage::InviteSent(invite_id)).await?;
             } else {
                 send_message(
                     connections,
@@ -141,60 +1162,114 @@ pub async fn react_to_client_msg(
                 .await?;
             }
         }
+        ClientMessage::AcceptInvite(invite_id) => {
+            let Some(invite) = server_state.take_invite(&invite_id, player_id) else {
+                send_message(
+                    connections,
+                    player_id,
+                    ServerMessage::BadRequest(ClientRequestError::InviteNotFound),
+                )
+                .await?;
+                return Ok(());
+            };
+            start_match(invite, connections, server_state).await?;
+        }
+        ClientMessage::DeclineInvite(invite_id) => {
+            let Some(invite) = server_state.take_invite(&invite_id, player_id) else {
+                send_message(
+                    connections,
+                    player_id,
+                    ServerMessage::BadRequest(ClientRequestError::InviteNotFound),
+                )
+                .await?;
+                return Ok(());
+            };
+            let _ = send_message(
+                connections,
+                &invite.challenger,
+                ServerMessage::InviteDeclined(invite_id),
+            )
+            .await;
+        }
         ClientMessage::GuessAttempt(match_id, guess) => {
             if let Some(active_match) = server_state.active_matches.get_mut(&match_id) {
-                active_match.attempt(&guess);
+                if active_match.role_of(player_id) != Some(MatchRole::Guesser) {
+                    send_message(
+                        connections,
+                        player_id,
+                        ServerMessage::BadRequest(ClientRequestError::PermissionDenied),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+                // Guesses are compared in the same form as the word was stored
+                let guess = config.words.normalize(&guess);
+                // Clients without dictionaries can't be told that a guess is unknown
+                let no_dictionaries = Dictionaries::default();
+                let dictionaries =
+                    if protocol_version_of(connections, player_id).await >= DICTIONARY_VERSION {
+                        &server_state.dictionaries
+                    } else {
+                        &no_dictionaries
+                    };
+                if let Err(error) = check_guess(active_match, &guess, dictionaries) {
+                    send_message(connections, player_id, ServerMessage::BadRequest(error)).await?;
+                    return Ok(());
+                }
+                let feedback = active_match.attempt(&guess);
 
-                match active_match.state {
-                    MatchState::Active => {
+                match active_match.state.end_reason() {
+                    None if active_match.mode == GameMode::Hangman => {
                         send_message(
                             connections,
                             &active_match.challenger,
-                            ServerMessage::MatchAttempt(
-                                match_id,
-                                active_match.attempts,
-                                active_match.hints.len() as u32,
-                                guess,
-                            ),
+                            hangman_progress(active_match),
                         )
                         .await?;
                         send_message(
                             connections,
                             &active_match.guesser,
-                            ServerMessage::IncorrectGuess(match_id, active_match.attempts),
+                            hangman_progress(active_match),
                         )
                         .await?;
                     }
-                    MatchState::Solved => {
+                    None => {
                         send_message(
                             connections,
                             &active_match.challenger,
-                            ServerMessage::MatchEnded(
+                            ServerMessage::MatchAttempt(
                                 match_id,
                                 active_match.attempts,
                                 active_match.hints.len() as u32,
-                                true,
+                                guess,
+                                feedback.clone(),
                             ),
                         )
                         .await?;
                         send_message(
                             connections,
                             &active_match.guesser,
-                            ServerMessage::MatchEnded(
+                            ServerMessage::IncorrectGuess(
                                 match_id,
                                 active_match.attempts,
-                                active_match.hints.len() as u32,
-                                true,
+                                feedback,
                             ),
                         )
                         .await?;
-                        server_state.finish_match(match_id);
-                    }
-                    // No actions needed
-                    MatchState::GivenUp => {
-                        server_state.finish_match(match_id);
                     }
-                    MatchState::Cancelled => {
+                    Some(reason) => {
+                        send_message(
+                            connections,
+                            &active_match.challenger,
+                            match_ended(active_match, reason),
+                        )
+                        .await?;
+                        send_message(
+                            connections,
+                            &active_match.guesser,
+                            match_ended(active_match, reason),
+                        )
+                        .await?;
                         server_state.finish_match(match_id);
                     }
                 }
@@ -209,6 +1284,15 @@ pub async fn react_to_client_msg(
         }
         ClientMessage::SendHint(match_id, hint) => {
             if let Some(active_match) = server_state.active_matches.get_mut(&match_id) {
+                if active_match.role_of(player_id) != Some(MatchRole::Challenger) {
+                    send_message(
+                        connections,
+                        player_id,
+                        ServerMessage::BadRequest(ClientRequestError::PermissionDenied),
+                    )
+                    .await?;
+                    return Ok(());
+                }
                 active_match.add_hint(&hint);
                 send_message(
                     connections,
@@ -227,7 +1311,7 @@ pub async fn react_to_client_msg(
         }
         ClientMessage::GiveUp(match_id) => {
             if let Some(active_match) = server_state.active_matches.get_mut(&match_id) {
-                if active_match.guesser.ne(player_id) {
+                if active_match.role_of(player_id) != Some(MatchRole::Guesser) {
                     send_message(
                         connections,
                         player_id,
@@ -240,23 +1324,13 @@ pub async fn react_to_client_msg(
                 send_message(
                     connections,
                     &active_match.guesser,
-                    ServerMessage::MatchEnded(
-                        match_id,
-                        active_match.attempts,
-                        active_match.hints.len() as u32,
-                        false,
-                    ),
+                    match_ended(active_match, EndReason::GivenUp),
                 )
                 .await?;
                 send_message(
                     connections,
                     &active_match.challenger,
-                    ServerMessage::MatchEnded(
-                        match_id,
-                        active_match.attempts,
-                        active_match.hints.len() as u32,
-                        false,
-                    ),
+                    match_ended(active_match, EndReason::GivenUp),
                 )
                 .await?;
                 server_state.finish_match(match_id);
@@ -271,61 +1345,1594 @@ pub async fn react_to_client_msg(
         }
         ClientMessage::LeaveGame => {
             trace!("player leaving a game");
-            // Check if player was in a guesser in active games
-            let mut matches_to_finish = Vec::<Uuid>::new();
-            let guesser_matches = server_state
-                .active_matches
-                .values_mut()
-                .filter(|active_match| active_match.guesser.eq(player_id));
+            leave_game(player_id, connections, server_state).await;
+            send_message(connections, player_id, ServerMessage::Disconnect).await?;
+        }
+    }
 
-            for active_match in guesser_matches {
-                active_match.give_up();
+    Ok(())
+}
 
-                send_message(
+/// End matches of the leaving player, let their opponents know and make the player unavailable
+/// Opponents are notified on a best effort basis, they might be gone already
+async fn leave_game(
+    player_id: &Uuid,
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+) {
+    // Check if player was in a guesser in active games
+    let mut matches_to_finish = Vec::<Uuid>::new();
+    let guesser_matches = server_state
+        .active_matches
+        .values_mut()
+        .filter(|active_match| active_match.guesser.eq(player_id));
+
+    for active_match in guesser_matches {
+        active_match.leave(MatchRole::Guesser);
+
+        let _ = send_message(
+            connections,
+            &active_match.challenger,
+            match_ended(active_match, EndReason::GuesserLeft),
+        )
+        .await;
+        matches_to_finish.push(active_match.id);
+    }
+
+    let challenger_matches = server_state
+        .active_matches
+        .values_mut()
+        .filter(|active_match| active_match.challenger.eq(player_id));
+
+    for active_match in challenger_matches {
+        active_match.leave(MatchRole::Challenger);
+
+        let _ = send_message(
+            connections,
+            &active_match.guesser,
+            match_ended(active_match, EndReason::ChallengerLeft),
+        )
+        .await;
+        matches_to_finish.push(active_match.id);
+    }
+
+    matches_to_finish.iter().for_each(|match_id| {
+        server_state.finish_match(*match_id);
+    });
+
+    let invites = server_state.cancel_invites(player_id);
+    cancel_invites(&invites, connections).await;
+
+    server_state.end_suspension(player_id);
+    server_state.remove_available_player(player_id);
+}
+
+/// End matches whose time limit or guess timeout has passed at `now`
+pub async fn expire_matches(
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+    now: std::time::Instant,
+) {
+    for match_id in server_state.time_out_matches(now) {
+        info!("Match {match_id} timed out");
+        let active_match = &server_state.active_matches[&match_id];
+        for player_id in [&active_match.guesser, &active_match.challenger] {
+            let _ = send_message(
+                connections,
+                player_id,
+                match_ended(active_match, EndReason::TimedOut),
+            )
+            .await;
+        }
+        server_state.finish_match(match_id);
+    }
+}
+
+/// End all matches in progress when the server shuts down
+pub async fn cancel_matches(connections: &mut ActiveConnections, server_state: &mut ServerState) {
+    let match_ids = server_state
+        .active_matches
+        .keys()
+        .copied()
+        .collect::<Vec<Uuid>>();
+    for match_id in match_ids {
+        if let Some(active_match) = server_state.active_matches.get_mut(&match_id) {
+            active_match.cancel();
+            for player_id in [&active_match.guesser, &active_match.challenger] {
+                let _ = send_message(
                     connections,
-                    &active_match.challenger,
-                    ServerMessage::MatchEnded(
-                        active_match.id,
-                        active_match.attempts,
-                        active_match.hints.len() as u32,
-                        false,
-                    ),
+                    player_id,
+                    match_ended(active_match, EndReason::Cancelled),
                 )
-                .await?;
-                matches_to_finish.push(active_match.id);
+                .await;
+            }
+        }
+        server_state.finish_match(match_id);
+    }
+}
+
+/// Cancel invites that haven't been answered in time
+pub async fn expire_invites(
+    connections: &mut ActiveConnections,
+    server_state: &mut ServerState,
+    invite_timeout: Duration,
+) {
+    let invites = server_state.expire_invites(invite_timeout);
+    cancel_invites(&invites, connections).await;
+}
+
+/// Let both players know the invites are no longer valid, they might be gone already
+async fn cancel_invites(invites: &[Invite], connections: &mut ActiveConnections) {
+    for invite in invites {
+        debug!("Cancelling invite {}", invite.id);
+        for player_id in [&invite.challenger, &invite.opponent] {
+            let _ = send_message(
+                connections,
+                player_id,
+                ServerMessage::InviteCancelled(invite.id),
+            )
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use tokio::{
+        io::duplex,
+        sync::{mpsc::Receiver, RwLock},
+        time::{self, sleep},
+    };
+
+    use super::*;
+    use crate::{
+        codec::WireMessage,
+        dictionary::Dictionary,
+        framing::{read_frame, write_frame},
+        protocol::{LetterFeedback, MatchLimits, HEARTBEAT_VERSION},
+        server_config::TrustedPeers,
+        server_state::HANGMAN_LIVES,
+    };
+
+    struct Fixture {
+        connections: ActiveConnections,
+        server_state: ServerState,
+        config: ServerConfig,
+        match_id: Uuid,
+        challenger: (Uuid, Receiver<ServerMessage>),
+        guesser: (Uuid, Receiver<ServerMessage>),
+        outsider: (Uuid, Receiver<ServerMessage>),
+    }
+
+    impl Fixture {
+        /// Three authenticated players, first two of them in a match
+        async fn new() -> Self {
+            let connections: ActiveConnections = Arc::new(RwLock::new(HashMap::new()));
+            let mut server_state = ServerState::default();
+            let mut players = Vec::new();
+            for _ in 0..3 {
+                let player_id = Uuid::new_v4();
+                let (tx, rx) = mpsc::channel(10);
+                let mut connection = Connection::new(tx);
+                connection.session = SessionState::Authenticated;
+                connection.player_id = Some(player_id);
+                connection.resume_token = Some(player_id.as_bytes().to_vec());
+                connections.write().await.insert(Uuid::new_v4(), connection);
+                server_state.add_available_player(&player_id);
+                players.push((player_id, rx));
+            }
+            let outsider = players.pop().unwrap();
+            let guesser = players.pop().unwrap();
+            let challenger = players.pop().unwrap();
+            let match_id = server_state
+                .create_new_match(
+                    (&challenger.0, &guesser.0),
+                    "secret",
+                    None,
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                )
+                .unwrap();
+
+            Self {
+                connections,
+                server_state,
+                config: ServerConfig::default(),
+                match_id,
+                challenger,
+                guesser,
+                outsider,
+            }
+        }
+
+        async fn send(&mut self, player_id: Uuid, msg: ClientMessage) {
+            let connection_id = find_player_connection(&self.connections, &player_id)
+                .await
+                .unwrap();
+            self.send_from(connection_id, msg).await;
+        }
+
+        async fn send_from(&mut self, connection_id: Uuid, msg: ClientMessage) {
+            react_to_client_msg(
+                &connection_id,
+                msg,
+                &mut self.connections,
+                &mut self.server_state,
+                &self.config,
+            )
+            .await
+            .unwrap();
+        }
+
+        async fn close(&mut self, player_id: Uuid) {
+            let connection_id = find_player_connection(&self.connections, &player_id)
+                .await
+                .unwrap();
+            react_to_connection_closed(
+                &connection_id,
+                &mut self.connections,
+                &mut self.server_state,
+                &self.config,
+            )
+            .await;
+        }
+
+        /// End all suspended sessions as if their grace period was over
+        async fn expire(&mut self) {
+            expire_suspended_sessions(
+                &mut self.connections,
+                &mut self.server_state,
+                Duration::ZERO,
+            )
+            .await;
+        }
+
+        /// Another authenticated player who is available for a match
+        async fn add_player(&mut self) -> (Uuid, Receiver<ServerMessage>) {
+            let (connection_id, rx) = self.connect().await;
+            let player_id = Uuid::new_v4();
+            log_in(&mut self.connections, &connection_id, &player_id)
+                .await
+                .unwrap();
+            self.server_state.add_available_player(&player_id);
+            (player_id, rx)
+        }
+
+        /// Invite the outsider sends to a new player
+        async fn invite(&mut self) -> (Uuid, Receiver<ServerMessage>, Uuid) {
+            let (opponent, mut opponent_rx) = self.add_player().await;
+            self.send(
+                self.outsider.0,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "word".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+            let Ok(ServerMessage::InviteSent(invite_id)) = self.outsider.1.try_recv() else {
+                panic!("invite wasn't sent");
+            };
+            assert!(matches!(
+                opponent_rx.try_recv(),
+                Ok(ServerMessage::MatchInvite(from, id, _, _)) if from == self.outsider.0 && id == invite_id
+            ));
+            (opponent, opponent_rx, invite_id)
+        }
+
+        /// New connection that has finished the handshake but isn't logged in
+        async fn connect(&mut self) -> (Uuid, Receiver<ServerMessage>) {
+            let connection_id = Uuid::new_v4();
+            let (tx, rx) = mpsc::channel(10);
+            let mut connection = Connection::new(tx);
+            connection.protocol_version = Some(PROTOCOL_VERSION);
+            self.connections
+                .write()
+                .await
+                .insert(connection_id, connection);
+            (connection_id, rx)
+        }
+
+        /// Pretend the player's client negotiated an older protocol version
+        async fn downgrade(&mut self, player_id: Uuid, version: u32) {
+            let connection_id = find_player_connection(&self.connections, &player_id)
+                .await
+                .unwrap();
+            if let Some(connection) = self.connections.write().await.get_mut(&connection_id) {
+                connection.protocol_version = Some(version);
             }
+        }
 
-            let challenger_matches = server_state
+        fn active_match(&self) -> &Match {
+            self.server_state
                 .active_matches
-                .values_mut()
-                .filter(|active_match| active_match.challenger.eq(player_id));
+                .get(&self.match_id)
+                .unwrap()
+        }
+    }
 
-            for active_match in challenger_matches {
-                active_match.cancel();
+    fn assert_permission_denied(rx: &mut Receiver<ServerMessage>) {
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::PermissionDenied
+            ))
+        ));
+    }
 
-                send_message(
-                    connections,
-                    &active_match.guesser,
-                    ServerMessage::MatchEnded(
-                        active_match.id,
-                        active_match.attempts,
-                        active_match.hints.len() as u32,
-                        false,
+    fn assert_no_message(rx: &mut Receiver<ServerMessage>) {
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn guesser_can_guess() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, match_id) = (fixture.guesser.0, fixture.match_id);
+        fixture
+            .send(
+                guesser,
+                ClientMessage::GuessAttempt(match_id, "wrong".into()),
+            )
+            .await;
+
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::IncorrectGuess(_, 1, _))
+        ));
+        assert!(matches!(
+            fixture.challenger.1.try_recv(),
+            Ok(ServerMessage::MatchAttempt(_, 1, 0, _, _))
+        ));
+        assert_eq!(fixture.active_match().attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn challenger_cannot_guess() {
+        let mut fixture = Fixture::new().await;
+        let (challenger, match_id) = (fixture.challenger.0, fixture.match_id);
+        fixture
+            .send(
+                challenger,
+                ClientMessage::GuessAttempt(match_id, "secret".into()),
+            )
+            .await;
+
+        assert_permission_denied(&mut fixture.challenger.1);
+        assert_no_message(&mut fixture.guesser.1);
+        assert_eq!(fixture.active_match().attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn outsider_cannot_guess() {
+        let mut fixture = Fixture::new().await;
+        let (outsider, match_id) = (fixture.outsider.0, fixture.match_id);
+        fixture
+            .send(
+                outsider,
+                ClientMessage::GuessAttempt(match_id, "secret".into()),
+            )
+            .await;
+
+        assert_permission_denied(&mut fixture.outsider.1);
+        assert_no_message(&mut fixture.challenger.1);
+        assert_no_message(&mut fixture.guesser.1);
+        assert_eq!(fixture.active_match().attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn challenger_can_send_hint() {
+        let mut fixture = Fixture::new().await;
+        let (challenger, match_id) = (fixture.challenger.0, fixture.match_id);
+        fixture
+            .send(challenger, ClientMessage::SendHint(match_id, "psst".into()))
+            .await;
+
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::MatchHint(_, hint)) if hint == "psst"
+        ));
+        assert_eq!(fixture.active_match().hints[0].text, "psst");
+    }
+
+    #[tokio::test]
+    async fn guesser_cannot_send_hint() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, match_id) = (fixture.guesser.0, fixture.match_id);
+        fixture
+            .send(guesser, ClientMessage::SendHint(match_id, "psst".into()))
+            .await;
+
+        assert_permission_denied(&mut fixture.guesser.1);
+        assert_no_message(&mut fixture.challenger.1);
+        assert!(fixture.active_match().hints.is_empty());
+    }
+
+    #[tokio::test]
+    async fn outsider_cannot_send_hint() {
+        let mut fixture = Fixture::new().await;
+        let (outsider, match_id) = (fixture.outsider.0, fixture.match_id);
+        fixture
+            .send(outsider, ClientMessage::SendHint(match_id, "psst".into()))
+            .await;
+
+        assert_permission_denied(&mut fixture.outsider.1);
+        assert_no_message(&mut fixture.guesser.1);
+        assert!(fixture.active_match().hints.is_empty());
+    }
+
+    #[tokio::test]
+    async fn guesser_can_give_up() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, match_id) = (fixture.guesser.0, fixture.match_id);
+        fixture.send(guesser, ClientMessage::GiveUp(match_id)).await;
+
+        let expected = MatchOutcome {
+            reason: EndReason::GivenUp,
+            attempts: 0,
+            hints: 0,
+            word: "secret".to_string(),
+            duration_secs: 0,
+        };
+        for rx in [&mut fixture.guesser.1, &mut fixture.challenger.1] {
+            match rx.try_recv() {
+                Ok(ServerMessage::MatchEnded(id, outcome)) => {
+                    assert_eq!(id, match_id);
+                    assert_eq!(outcome, expected);
+                }
+                msg => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert!(fixture.server_state.active_matches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn challenger_cannot_give_up() {
+        let mut fixture = Fixture::new().await;
+        let (challenger, match_id) = (fixture.challenger.0, fixture.match_id);
+        fixture
+            .send(challenger, ClientMessage::GiveUp(match_id))
+            .await;
+
+        assert_permission_denied(&mut fixture.challenger.1);
+        assert_no_message(&mut fixture.guesser.1);
+        assert!(fixture.server_state.active_matches.contains_key(&match_id));
+    }
+
+    #[tokio::test]
+    async fn outsider_cannot_give_up() {
+        let mut fixture = Fixture::new().await;
+        let (outsider, match_id) = (fixture.outsider.0, fixture.match_id);
+        fixture
+            .send(outsider, ClientMessage::GiveUp(match_id))
+            .await;
+
+        assert_permission_denied(&mut fixture.outsider.1);
+        assert_no_message(&mut fixture.guesser.1);
+        assert_no_message(&mut fixture.challenger.1);
+        assert!(fixture.server_state.active_matches.contains_key(&match_id));
+    }
+
+    #[tokio::test]
+    async fn dropped_guesser_leaves_match() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, challenger) = (fixture.guesser.0, fixture.challenger.0);
+        fixture.close(guesser).await;
+        fixture.expire().await;
+
+        assert!(matches!(
+            fixture.challenger.1.try_recv(),
+            Ok(ServerMessage::MatchEnded(
+                _,
+                MatchOutcome {
+                    reason: EndReason::GuesserLeft,
+                    ..
+                }
+            ))
+        ));
+        assert!(fixture.server_state.active_matches.is_empty());
+        assert!(matches!(
+            fixture.server_state.history.records.back(),
+            Some(record) if record.end_reason == EndReason::GuesserLeft
+        ));
+        assert!(fixture.server_state.available_players.contains(&challenger));
+        assert!(!fixture.server_state.available_players.contains(&guesser));
+        assert!(find_player_connection(&fixture.connections, &guesser)
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_challenger_leaves_match() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, challenger) = (fixture.guesser.0, fixture.challenger.0);
+        fixture.close(challenger).await;
+        fixture.expire().await;
+
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::MatchEnded(
+                _,
+                MatchOutcome {
+                    reason: EndReason::ChallengerLeft,
+                    ..
+                }
+            ))
+        ));
+        assert!(matches!(
+            fixture.server_state.history.records.back(),
+            Some(record) if record.end_reason == EndReason::ChallengerLeft
+        ));
+        assert!(fixture.server_state.available_players.contains(&guesser));
+        assert!(!fixture.server_state.available_players.contains(&challenger));
+    }
+
+    #[tokio::test]
+    async fn dropped_idle_player_is_no_longer_available() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        fixture.close(outsider).await;
+        fixture.expire().await;
+
+        assert!(!fixture.server_state.available_players.contains(&outsider));
+        assert_no_message(&mut fixture.guesser.1);
+        assert_no_message(&mut fixture.challenger.1);
+        assert!(fixture
+            .server_state
+            .active_matches
+            .contains_key(&fixture.match_id));
+    }
+
+    #[tokio::test]
+    async fn dropped_player_is_not_available_after_match_ends() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, challenger, match_id) =
+            (fixture.guesser.0, fixture.challenger.0, fixture.match_id);
+        fixture.close(challenger).await;
+        fixture.send(guesser, ClientMessage::GiveUp(match_id)).await;
+
+        assert!(fixture.server_state.active_matches.is_empty());
+        assert!(fixture.server_state.available_players.contains(&guesser));
+        assert!(!fixture.server_state.available_players.contains(&challenger));
+
+        let (connection_id, _rx) = fixture.connect().await;
+        fixture
+            .send_from(
+                connection_id,
+                ClientMessage::Resume(challenger.as_bytes().to_vec()),
+            )
+            .await;
+        assert!(fixture.server_state.available_players.contains(&challenger));
+    }
+
+    #[tokio::test]
+    async fn dropped_player_keeps_match_until_session_expires() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, challenger, match_id) =
+            (fixture.guesser.0, fixture.challenger.0, fixture.match_id);
+        fixture.close(guesser).await;
+        fixture
+            .send(challenger, ClientMessage::SendHint(match_id, "psst".into()))
+            .await;
+
+        assert_no_message(&mut fixture.challenger.1);
+        assert_eq!(fixture.active_match().hints[0].text, "psst");
+        assert!(!fixture.server_state.available_players.contains(&guesser));
+    }
+
+    #[tokio::test]
+    async fn resumed_session_replays_queued_messages() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, challenger, match_id) =
+            (fixture.guesser.0, fixture.challenger.0, fixture.match_id);
+        fixture.close(guesser).await;
+        fixture
+            .send(challenger, ClientMessage::SendHint(match_id, "psst".into()))
+            .await;
+
+        let (connection_id, mut rx) = fixture.connect().await;
+        fixture
+            .send_from(
+                connection_id,
+                ClientMessage::Resume(guesser.as_bytes().to_vec()),
+            )
+            .await;
+
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMessage::AssignId(id, token)) if id == guesser && token != guesser.as_bytes()
+        ));
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMessage::MatchHint(_, hint)) if hint == "psst"
+        ));
+        assert_eq!(
+            find_player_connection(&fixture.connections, &guesser).await,
+            Some(connection_id)
+        );
+        fixture.expire().await;
+        assert!(fixture.server_state.active_matches.contains_key(&match_id));
+    }
+
+    #[tokio::test]
+    async fn resume_with_unknown_token_fails() {
+        let mut fixture = Fixture::new().await;
+        let guesser = fixture.guesser.0;
+        fixture.close(guesser).await;
+
+        let (connection_id, mut rx) = fixture.connect().await;
+        fixture
+            .send_from(connection_id, ClientMessage::Resume(vec![0; 16]))
+            .await;
+
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMessage::BadRequest(ClientRequestError::ResumeFailed))
+        ));
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::AskUsername)));
+        assert!(
+            is_suspended(
+                &fixture.connections,
+                &find_player_connection(&fixture.connections, &guesser)
+                    .await
+                    .unwrap()
+            )
+            .await
+        );
+    }
+
+    #[tokio::test]
+    async fn requests_before_login_are_rejected() {
+        let mut fixture = Fixture::new().await;
+        let (outsider, match_id) = (fixture.outsider.0, fixture.match_id);
+        let available_players = fixture.server_state.available_players.clone();
+        let (connection_id, mut rx) = fixture.connect().await;
+        for msg in [
+            ClientMessage::GetOpponents,
+            ClientMessage::RequestMatch(
+                outsider,
+                "word".into(),
+                GameMode::Classic,
+                MatchLimits::default(),
+            ),
+            ClientMessage::GuessAttempt(match_id, "secret".into()),
+        ] {
+            fixture.send_from(connection_id, msg).await;
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(ServerMessage::BadRequest(
+                    ClientRequestError::NotAuthenticated
+                ))
+            ));
+        }
+
+        assert_no_message(&mut fixture.outsider.1);
+        assert_no_message(&mut fixture.guesser.1);
+        assert_no_message(&mut fixture.challenger.1);
+        assert_eq!(fixture.server_state.available_players, available_players);
+        assert_eq!(fixture.server_state.active_matches.len(), 1);
+        assert_eq!(fixture.active_match().attempts, 0);
+        assert!(matches!(
+            session_state(&fixture.connections, &connection_id).await,
+            Ok((SessionState::Unauthenticated, None))
+        ));
+    }
+
+    #[tokio::test]
+    async fn hello_negotiates_version_and_capabilities() {
+        let mut fixture = Fixture::new().await;
+        for (version, capabilities, negotiated) in [
+            (
+                PROTOCOL_VERSION,
+                Capabilities(u32::MAX),
+                Some((PROTOCOL_VERSION, SUPPORTED_CAPABILITIES)),
+            ),
+            (
+                PROTOCOL_VERSION + 1,
+                Capabilities::SPECTATE,
+                Some((PROTOCOL_VERSION, Capabilities::NONE)),
+            ),
+            (
+                HEARTBEAT_VERSION,
+                Capabilities::HEARTBEAT,
+                Some((HEARTBEAT_VERSION, Capabilities::HEARTBEAT)),
+            ),
+            (
+                HEARTBEAT_VERSION - 1,
+                Capabilities::HEARTBEAT,
+                Some((HEARTBEAT_VERSION - 1, Capabilities::NONE)),
+            ),
+            (
+                MIN_PROTOCOL_VERSION,
+                Capabilities::NONE,
+                Some((MIN_PROTOCOL_VERSION, Capabilities::NONE)),
+            ),
+            (MIN_PROTOCOL_VERSION - 1, Capabilities::NONE, None),
+        ] {
+            let connection_id = Uuid::new_v4();
+            let (tx, mut rx) = mpsc::channel(10);
+            fixture
+                .connections
+                .write()
+                .await
+                .insert(connection_id, Connection::new(tx));
+            fixture
+                .send_from(connection_id, ClientMessage::Hello(version, capabilities))
+                .await;
+
+            let connections = fixture.connections.read().await;
+            if let Some((version, capabilities)) = negotiated {
+                let connection = &connections[&connection_id];
+                assert_eq!(connection.protocol_version, Some(version));
+                assert_eq!(connection.wire_version.get(), version);
+                assert_eq!(connection.capabilities, capabilities);
+                assert!(matches!(rx.try_recv(), Ok(ServerMessage::AskUsername)));
+            } else {
+                assert!(!connections.contains_key(&connection_id));
+                assert!(matches!(
+                    rx.try_recv(),
+                    Ok(ServerMessage::BadRequest(
+                        ClientRequestError::UnsupportedVersion
+                    ))
+                ));
+                assert!(matches!(rx.try_recv(), Ok(ServerMessage::Disconnect)));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn trusted_peer_is_logged_in_without_password() {
+        let (stream, _peer) = tokio::net::UnixStream::pair().unwrap();
+        let cred = stream.peer_cred().unwrap();
+        let player_id = peer_player_id(cred.uid());
+        for (uids, gids, trusted) in [
+            (vec![cred.uid()], vec![], true),
+            (vec![], vec![cred.gid()], true),
+            (
+                vec![cred.uid().wrapping_add(1)],
+                vec![cred.gid().wrapping_add(1)],
+                false,
+            ),
+        ] {
+            let mut fixture = Fixture::new().await;
+            fixture.config.trusted_peers = TrustedPeers { uids, gids };
+            let connection_id = Uuid::new_v4();
+            let (tx, mut rx) = mpsc::channel(10);
+            let mut connection = Connection::new(tx);
+            connection.peer_cred = Some(cred);
+            fixture
+                .connections
+                .write()
+                .await
+                .insert(connection_id, connection);
+
+            fixture
+                .send_from(
+                    connection_id,
+                    ClientMessage::Hello(PROTOCOL_VERSION, Capabilities::NONE),
+                )
+                .await;
+
+            if trusted {
+                assert!(matches!(
+                    rx.try_recv(),
+                    Ok(ServerMessage::AssignId(id, _)) if id == player_id
+                ));
+                assert!(fixture.server_state.available_players.contains(&player_id));
+            } else {
+                assert!(matches!(rx.try_recv(), Ok(ServerMessage::AskUsername)));
+                assert!(!fixture.server_state.available_players.contains(&player_id));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn too_many_wrong_passwords_disconnect() {
+        let mut fixture = Fixture::new().await;
+        let (connection_id, mut rx) = fixture.connect().await;
+        for attempt in 1..=fixture.config.max_password_attempts {
+            fixture
+                .send_from(connection_id, ClientMessage::AnswerUsername("ghost".into()))
+                .await;
+            assert!(matches!(rx.try_recv(), Ok(ServerMessage::AskPassword(_))));
+            fixture
+                .send_from(connection_id, ClientMessage::AnswerPassword(vec![0; 32]))
+                .await;
+            assert!(matches!(rx.try_recv(), Ok(ServerMessage::WrongPassword)));
+            if attempt < fixture.config.max_password_attempts {
+                assert!(matches!(rx.try_recv(), Ok(ServerMessage::AskUsername)));
+            }
+        }
+
+        assert!(matches!(
+            rx.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::TooManyAttempts
+            ))
+        ));
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::Disconnect)));
+        assert!(!fixture
+            .connections
+            .read()
+            .await
+            .contains_key(&connection_id));
+    }
+
+    const HEARTBEAT: HeartbeatConfig = HeartbeatConfig {
+        interval_secs: 5,
+        idle_timeout_secs: 15,
+    };
+
+    /// Client side of a logged in connection that has negotiated the heartbeat capability
+    struct HeartbeatClient {
+        tx: Sender<ClientMessage>,
+        rx: Receiver<Result<ServerMessage, MalformedMessage>>,
+        events: Receiver<(Uuid, ConnectionEvent)>,
+        connections: ActiveConnections,
+    }
+
+    impl HeartbeatClient {
+        async fn connect() -> Self {
+            let (client_stream, server_stream) = duplex(1024);
+            let (main_tx, events) = mpsc::channel(10);
+            let mut connections: ActiveConnections = Arc::new(RwLock::new(HashMap::new()));
+            handle_client(
+                server_stream,
+                None,
+                main_tx,
+                &mut connections,
+                10,
+                HEARTBEAT,
+            )
+            .await
+            .unwrap();
+            for connection in connections.write().await.values_mut() {
+                connection.protocol_version = Some(PROTOCOL_VERSION);
+                connection.capabilities = Capabilities::HEARTBEAT;
+                connection.session = SessionState::Authenticated;
+                connection.player_id = Some(Uuid::new_v4());
+            }
+
+            let (client_tx, mut rx) = mpsc::channel(10);
+            let tx = handle_stream(client_stream, client_tx, 10, WireVersion::default())
+                .await
+                .unwrap();
+            assert!(matches!(
+                rx.recv().await,
+                Some(Ok(ServerMessage::Hello(..)))
+            ));
+            Self {
+                tx,
+                rx,
+                events,
+                connections,
+            }
+        }
+
+        /// Wait until the game loop is told that the connection is closed and return its ID
+        async fn closed(&mut self) -> Uuid {
+            let Some((connection_id, ConnectionEvent::Closed)) = self.events.recv().await else {
+                panic!("connection wasn't closed");
+            };
+            connection_id
+        }
+    }
+
+    #[tokio::test]
+    async fn heartbeat_pings_at_interval() {
+        time::pause();
+        let start = Instant::now();
+        let mut client = HeartbeatClient::connect().await;
+
+        // Timers of the paused clock fire a millisecond late, so only whole seconds are compared
+        assert!(matches!(
+            client.rx.recv().await,
+            Some(Ok(ServerMessage::Ping))
+        ));
+        assert_eq!(start.elapsed().as_secs(), HEARTBEAT.interval_secs);
+        assert!(matches!(
+            client.rx.recv().await,
+            Some(Ok(ServerMessage::Ping))
+        ));
+        assert_eq!(start.elapsed().as_secs(), HEARTBEAT.interval_secs * 2);
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed() {
+        time::pause();
+        let start = Instant::now();
+        let mut client = HeartbeatClient::connect().await;
+
+        let connection_id = client.closed().await;
+        assert_eq!(start.elapsed().as_secs(), HEARTBEAT.idle_timeout_secs);
+
+        // Session is kept for resumption, but the client must see the stream end
+        let mut server_state = ServerState::default();
+        react_to_connection_closed(
+            &connection_id,
+            &mut client.connections,
+            &mut server_state,
+            &ServerConfig::default(),
+        )
+        .await;
+        assert!(is_suspended(&client.connections, &connection_id).await);
+        let eof = time::timeout(HEARTBEAT.interval(), async {
+            while let Some(msg) = client.rx.recv().await {
+                assert!(matches!(msg, Ok(ServerMessage::Ping)));
+            }
+        })
+        .await;
+        assert!(eof.is_ok(), "stream of the suspended session wasn't closed");
+    }
+
+    #[tokio::test]
+    async fn pong_keeps_connection_open() {
+        time::pause();
+        let start = Instant::now();
+        let mut client = HeartbeatClient::connect().await;
+
+        sleep(HEARTBEAT.idle_timeout() - Duration::from_secs(3)).await;
+        client.tx.send(ClientMessage::Pong).await.unwrap();
+
+        client.closed().await;
+        // Idle timeout starts over at the pong and is noticed on the next ping after it
+        assert_eq!(start.elapsed().as_secs(), 30);
+    }
+
+    #[tokio::test]
+    async fn undecodable_frame_is_answered_with_its_opcode() {
+        let (mut client_stream, server_stream) = duplex(1024);
+        let (main_tx, mut events) = mpsc::channel(10);
+        let mut connections: ActiveConnections = Arc::new(RwLock::new(HashMap::new()));
+        handle_client(
+            server_stream,
+            None,
+            main_tx,
+            &mut connections,
+            10,
+            HEARTBEAT,
+        )
+        .await
+        .unwrap();
+
+        let mut buf = Vec::new();
+        let mut replies = Vec::new();
+        for frame in [&[0x99, 0x01][..], &[]] {
+            write_frame(&mut client_stream, frame).await.unwrap();
+        }
+        for _ in 0..3 {
+            assert!(read_frame(&mut client_stream, &mut buf).await.unwrap());
+            replies.push(ServerMessage::decode(&buf, PROTOCOL_VERSION).unwrap());
+        }
+        assert!(matches!(
+            &replies[..],
+            [
+                ServerMessage::Hello(..),
+                ServerMessage::BadRequest(ClientRequestError::UnknownRequest(Some(0x99))),
+                ServerMessage::BadRequest(ClientRequestError::UnknownRequest(None)),
+            ]
+        ));
+        // Malformed requests are not passed to the game loop and the connection stays open
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn accepted_invite_starts_match() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        let (opponent, mut opponent_rx, invite_id) = fixture.invite().await;
+        assert!(fixture.server_state.available_players.contains(&opponent));
+
+        fixture
+            .send(opponent, ClientMessage::AcceptInvite(invite_id))
+            .await;
+
+        assert!(matches!(
+            opponent_rx.try_recv(),
+            Ok(ServerMessage::MatchStarted(_))
+        ));
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::MatchAccepted(_))
+        ));
+        assert!(!fixture.server_state.available_players.contains(&opponent));
+        assert!(!fixture.server_state.available_players.contains(&outsider));
+        assert!(fixture.server_state.pending_invites.is_empty());
+    }
+
+    #[tokio::test]
+    async fn declined_invite_notifies_challenger() {
+        let mut fixture = Fixture::new().await;
+        let (opponent, _opponent_rx, invite_id) = fixture.invite().await;
+
+        fixture
+            .send(opponent, ClientMessage::DeclineInvite(invite_id))
+            .await;
+
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::InviteDeclined(id)) if id == invite_id
+        ));
+        assert_eq!(fixture.server_state.active_matches.len(), 1);
+        assert!(fixture.server_state.pending_invites.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_invited_player_can_accept() {
+        let mut fixture = Fixture::new().await;
+        let challenger = fixture.challenger.0;
+        let (_opponent, _opponent_rx, invite_id) = fixture.invite().await;
+
+        fixture
+            .send(challenger, ClientMessage::AcceptInvite(invite_id))
+            .await;
+
+        assert!(matches!(
+            fixture.challenger.1.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::InviteNotFound
+            ))
+        ));
+        assert!(fixture
+            .server_state
+            .pending_invites
+            .contains_key(&invite_id));
+    }
+
+    #[tokio::test]
+    async fn accepted_invite_cancels_other_invites() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        let (opponent, mut opponent_rx, invite_id) = fixture.invite().await;
+        let (third, mut third_rx) = fixture.add_player().await;
+        for (challenger, invited) in [(outsider, third), (third, opponent)] {
+            fixture
+                .send(
+                    challenger,
+                    ClientMessage::RequestMatch(
+                        invited,
+                        "word".into(),
+                        GameMode::Classic,
+                        MatchLimits::default(),
                     ),
                 )
-                .await?;
-                matches_to_finish.push(active_match.id);
+                .await;
+        }
+        assert_eq!(fixture.server_state.pending_invites.len(), 3);
+        while fixture.outsider.1.try_recv().is_ok() {}
+        while opponent_rx.try_recv().is_ok() {}
+        while third_rx.try_recv().is_ok() {}
+
+        fixture
+            .send(opponent, ClientMessage::AcceptInvite(invite_id))
+            .await;
+
+        assert!(fixture.server_state.pending_invites.is_empty());
+        assert!(matches!(
+            third_rx.try_recv(),
+            Ok(ServerMessage::InviteCancelled(_))
+        ));
+        assert!(matches!(
+            third_rx.try_recv(),
+            Ok(ServerMessage::InviteCancelled(_))
+        ));
+        assert!(fixture.server_state.available_players.contains(&third));
+    }
+
+    #[tokio::test]
+    async fn second_invite_to_same_opponent_is_refused() {
+        let mut fixture = Fixture::new().await;
+        let (opponent, _opponent_rx, invite_id) = fixture.invite().await;
+
+        fixture
+            .send(
+                fixture.outsider.0,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "other".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch
+            ))
+        ));
+        assert_eq!(
+            fixture
+                .server_state
+                .pending_invites
+                .keys()
+                .collect::<Vec<_>>(),
+            [&invite_id]
+        );
+    }
+
+    #[tokio::test]
+    async fn opponent_without_invites_is_put_into_match() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        let (opponent, mut opponent_rx) = fixture.add_player().await;
+        fixture.downgrade(opponent, INVITES_VERSION - 1).await;
+
+        fixture
+            .send(
+                outsider,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "word".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+
+        assert!(matches!(
+            opponent_rx.try_recv(),
+            Ok(ServerMessage::MatchStarted(_))
+        ));
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::MatchAccepted(_))
+        ));
+        assert!(fixture.server_state.pending_invites.is_empty());
+        assert_eq!(fixture.server_state.active_matches.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn opponent_must_know_mode_and_limits() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        let limits = MatchLimits {
+            max_attempts: 3,
+            ..MatchLimits::default()
+        };
+        for (mode, limits, version) in [
+            (
+                GameMode::Wordle,
+                MatchLimits::default(),
+                GAME_MODES_VERSION - 1,
+            ),
+            (
+                GameMode::Hangman,
+                MatchLimits::default(),
+                HANGMAN_VERSION - 1,
+            ),
+            (GameMode::Classic, limits, LIMITS_VERSION - 1),
+        ] {
+            let (opponent, mut opponent_rx) = fixture.add_player().await;
+            fixture.downgrade(opponent, version).await;
+            fixture
+                .send(
+                    outsider,
+                    ClientMessage::RequestMatch(opponent, "word".into(), mode, limits),
+                )
+                .await;
+
+            assert!(matches!(
+                fixture.outsider.1.try_recv(),
+                Ok(ServerMessage::BadRequest(
+                    ClientRequestError::CannotCreateMatch
+                ))
+            ));
+            assert_no_message(&mut opponent_rx);
+        }
+        assert!(fixture.server_state.pending_invites.is_empty());
+    }
+
+    #[tokio::test]
+    async fn challenger_must_know_mode() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        fixture.downgrade(outsider, HANGMAN_VERSION - 1).await;
+        let (opponent, mut opponent_rx) = fixture.add_player().await;
+        fixture
+            .send(
+                outsider,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "word".into(),
+                    GameMode::Hangman,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch
+            ))
+        ));
+        assert_no_message(&mut opponent_rx);
+        assert!(fixture.server_state.pending_invites.is_empty());
+        assert_eq!(fixture.server_state.active_matches.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn challenger_without_invites_gets_refusal() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        fixture.downgrade(outsider, INVITES_VERSION - 1).await;
+        let (opponent, mut opponent_rx) = fixture.add_player().await;
+        fixture
+            .send(
+                outsider,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "word".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+        let Ok(ServerMessage::MatchInvite(_, invite_id, ..)) = opponent_rx.try_recv() else {
+            panic!("invite wasn't sent");
+        };
+        // `InviteSent` is left out for the older client
+        assert_no_message(&mut fixture.outsider.1);
+
+        fixture
+            .send(opponent, ClientMessage::DeclineInvite(invite_id))
+            .await;
+
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_invite_is_cancelled() {
+        let mut fixture = Fixture::new().await;
+        let (_opponent, mut opponent_rx, invite_id) = fixture.invite().await;
+
+        expire_invites(
+            &mut fixture.connections,
+            &mut fixture.server_state,
+            Duration::ZERO,
+        )
+        .await;
+
+        assert!(matches!(
+            opponent_rx.try_recv(),
+            Ok(ServerMessage::InviteCancelled(id)) if id == invite_id
+        ));
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::InviteCancelled(id)) if id == invite_id
+        ));
+        assert!(fixture.server_state.pending_invites.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_word_is_rejected() {
+        let mut fixture = Fixture::new().await;
+        let (opponent, mut opponent_rx) = fixture.add_player().await;
+        fixture
+            .send(
+                fixture.outsider.0,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "two words".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::BadRequest(ClientRequestError::InvalidWord(
+                _
+            )))
+        ));
+        assert_no_message(&mut opponent_rx);
+        assert!(fixture.server_state.pending_invites.is_empty());
+    }
+
+    #[tokio::test]
+    async fn invalid_word_is_refused_for_older_challenger() {
+        let mut fixture = Fixture::new().await;
+        let outsider = fixture.outsider.0;
+        fixture.downgrade(outsider, WORD_RULES_VERSION - 1).await;
+        let (opponent, mut opponent_rx) = fixture.add_player().await;
+        fixture
+            .send(
+                outsider,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "two words".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::BadRequest(
+                ClientRequestError::CannotCreateMatch
+            ))
+        ));
+        assert_no_message(&mut opponent_rx);
+    }
+
+    #[tokio::test]
+    async fn unknown_words_are_rejected_without_counting_attempts() {
+        let mut fixture = Fixture::new().await;
+        fixture.server_state.dictionaries = Dictionaries::new(vec![Dictionary::new(
+            "en",
+            ["secret", "wrong", "word"].map(String::from),
+        )]);
+        fixture
+            .send(
+                fixture.guesser.0,
+                ClientMessage::GuessAttempt(fixture.match_id, "xyzzy".into()),
+            )
+            .await;
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::BadRequest(ClientRequestError::UnknownWord))
+        ));
+        assert_no_message(&mut fixture.challenger.1);
+        assert_eq!(fixture.active_match().attempts, 0);
+
+        fixture
+            .send(
+                fixture.guesser.0,
+                ClientMessage::GuessAttempt(fixture.match_id, "wrong".into()),
+            )
+            .await;
+        assert_eq!(fixture.active_match().attempts, 1);
+
+        let (opponent, mut opponent_rx) = fixture.add_player().await;
+        fixture
+            .send(
+                fixture.outsider.0,
+                ClientMessage::RequestMatch(
+                    opponent,
+                    "xyzzy".into(),
+                    GameMode::Classic,
+                    MatchLimits::default(),
+                ),
+            )
+            .await;
+        assert!(matches!(
+            fixture.outsider.1.try_recv(),
+            Ok(ServerMessage::BadRequest(ClientRequestError::UnknownWord))
+        ));
+        assert_no_message(&mut opponent_rx);
+
+        let (_, _, invite_id) = fixture.invite().await;
+        assert_eq!(
+            fixture.server_state.pending_invites[&invite_id]
+                .language
+                .as_deref(),
+            Some("en")
+        );
+    }
+
+    #[tokio::test]
+    async fn guesses_of_older_clients_are_not_checked() {
+        let mut fixture = Fixture::new().await;
+        let guesser = fixture.guesser.0;
+        fixture.downgrade(guesser, DICTIONARY_VERSION - 1).await;
+        fixture.server_state.dictionaries = Dictionaries::new(vec![Dictionary::new(
+            "en",
+            ["secret", "wrong", "word"].map(String::from),
+        )]);
+        fixture
+            .send(
+                guesser,
+                ClientMessage::GuessAttempt(fixture.match_id, "xyzzy".into()),
+            )
+            .await;
+
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::IncorrectGuess(_, 1, _))
+        ));
+        assert_eq!(fixture.active_match().attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn wordle_guesses_get_letter_feedback() {
+        use LetterFeedback::{Absent, Correct, Present};
+
+        let mut fixture = Fixture::new().await;
+        let match_id = fixture.match_id;
+        fixture
+            .server_state
+            .active_matches
+            .get_mut(&match_id)
+            .unwrap()
+            .mode = GameMode::Wordle;
+
+        fixture
+            .send(
+                fixture.guesser.0,
+                ClientMessage::GuessAttempt(match_id, "sect".into()),
+            )
+            .await;
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::BadRequest(ClientRequestError::InvalidWord(
+                _
+            )))
+        ));
+        assert_eq!(fixture.active_match().attempts, 0);
+
+        fixture
+            .send(
+                fixture.guesser.0,
+                ClientMessage::GuessAttempt(match_id, "sector".into()),
+            )
+            .await;
+        let feedback = vec![Correct, Correct, Correct, Present, Absent, Present];
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::IncorrectGuess(_, 1, letters)) if letters == feedback
+        ));
+        assert!(matches!(
+            fixture.challenger.1.try_recv(),
+            Ok(ServerMessage::MatchAttempt(_, 1, 0, _, letters)) if letters == feedback
+        ));
+    }
+
+    #[tokio::test]
+    async fn hangman_reveals_letters_until_lives_run_out() {
+        let mut fixture = Fixture::new().await;
+        let match_id = fixture.match_id;
+        fixture
+            .server_state
+            .active_matches
+            .get_mut(&match_id)
+            .unwrap()
+            .mode = GameMode::Hangman;
+
+        fixture
+            .send(
+                fixture.guesser.0,
+                ClientMessage::GuessAttempt(match_id, "e".into()),
+            )
+            .await;
+        for rx in [&mut fixture.guesser.1, &mut fixture.challenger.1] {
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(ServerMessage::HangmanProgress(_, 1, mask, HANGMAN_LIVES, tried))
+                    if mask == "_e__e_" && tried == "e"
+            ));
+        }
+
+        fixture
+            .send(
+                fixture.guesser.0,
+                ClientMessage::GuessAttempt(match_id, "e".into()),
+            )
+            .await;
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::BadRequest(ClientRequestError::InvalidWord(
+                _
+            )))
+        ));
+
+        for letter in ["a", "b", "d", "f", "g", "h"] {
+            fixture
+                .send(
+                    fixture.guesser.0,
+                    ClientMessage::GuessAttempt(match_id, letter.into()),
+                )
+                .await;
+        }
+        while let Ok(msg) = fixture.guesser.1.try_recv() {
+            if let ServerMessage::MatchEnded(_, outcome) = msg {
+                assert_eq!(outcome.attempts, 7);
+                assert_eq!(outcome.reason, EndReason::OutOfLives);
+                assert!(!fixture.server_state.active_matches.contains_key(&match_id));
+                return;
             }
+        }
+        panic!("match didn't end");
+    }
 
-            matches_to_finish.iter().for_each(|match_id| {
-                server_state.finish_match(*match_id);
-            });
+    #[tokio::test]
+    async fn timed_out_match_ends_for_both_players() {
+        let mut fixture = Fixture::new().await;
+        let match_id = fixture.match_id;
+        let active_match = fixture
+            .server_state
+            .active_matches
+            .get_mut(&match_id)
+            .unwrap();
+        active_match.limits.guess_timeout_secs = 30;
+        let last_guess_at = active_match.last_guess_at;
+        expire_matches(
+            &mut fixture.connections,
+            &mut fixture.server_state,
+            last_guess_at + Duration::from_secs(29),
+        )
+        .await;
+        assert_no_message(&mut fixture.guesser.1);
 
-            trace!("Player is going to be removed");
-            server_state.remove_available_player(player_id);
-            send_message(connections, player_id, ServerMessage::Disconnect).await?;
+        expire_matches(
+            &mut fixture.connections,
+            &mut fixture.server_state,
+            last_guess_at + Duration::from_secs(30),
+        )
+        .await;
+        for rx in [&mut fixture.guesser.1, &mut fixture.challenger.1] {
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(ServerMessage::MatchEnded(
+                    _,
+                    MatchOutcome {
+                        reason: EndReason::TimedOut,
+                        ..
+                    }
+                ))
+            ));
         }
+        assert!(fixture.server_state.active_matches.is_empty());
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn shutdown_cancels_matches() {
+        let mut fixture = Fixture::new().await;
+        cancel_matches(&mut fixture.connections, &mut fixture.server_state).await;
+        for rx in [&mut fixture.guesser.1, &mut fixture.challenger.1] {
+            assert!(matches!(
+                rx.try_recv(),
+                Ok(ServerMessage::MatchEnded(
+                    _,
+                    MatchOutcome {
+                        reason: EndReason::Cancelled,
+                        ..
+                    }
+                ))
+            ));
+        }
+        assert!(fixture.server_state.active_matches.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stats_include_finished_matches() {
+        let mut fixture = Fixture::new().await;
+        let (guesser, match_id) = (fixture.guesser.0, fixture.match_id);
+        fixture
+            .send(
+                guesser,
+                ClientMessage::GuessAttempt(match_id, "secret".into()),
+            )
+            .await;
+        while fixture.guesser.1.try_recv().is_ok() {}
+
+        fixture
+            .send(guesser, ClientMessage::GetStats(guesser))
+            .await;
+        match fixture.guesser.1.try_recv() {
+            Ok(ServerMessage::PlayerStats(player_id, stats)) => {
+                assert_eq!(player_id, guesser);
+                assert_eq!(stats.games_as_guesser, 1);
+                assert_eq!(stats.solved, 1);
+                assert_eq!(stats.attempts, 1);
+            }
+            msg => panic!("unexpected message {msg:?}"),
+        }
+
+        fixture.send(guesser, ClientMessage::GetLeaderboard).await;
+        assert!(matches!(
+            fixture.guesser.1.try_recv(),
+            Ok(ServerMessage::Leaderboard(players)) if players.len() == 1 && players[0].0 == guesser
+        ));
+    }
 }
@@ -0,0 +1,215 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, Permissions},
+    net::SocketAddr,
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{chown, FileTypeExt, PermissionsExt},
+            net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+        },
+    },
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use log::{debug, error, warn};
+use socket2::{Domain, SockAddr, Socket, Type};
+use tokio::{
+    net::{TcpListener, TcpSocket, UnixListener},
+    sync::mpsc::Sender,
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+use crate::{
+    server_config::{is_abstract_socket, HeartbeatConfig, ListenerConfig},
+    server_connection::{handle_client, ConnectionEvent},
+    server_tls::create_acceptor,
+    ActiveConnections,
+};
+
+/// Clients that don't finish the TLS handshake in time are dropped
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bound socket ready to accept clients
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    Tls(TcpListener, TlsAcceptor),
+}
+
+/// Bind the socket described by `config`
+pub fn bind_listener(config: &ListenerConfig) -> Result<Listener, anyhow::Error> {
+    let listener = match config {
+        ListenerConfig::Tcp { addr, backlog } => Listener::Tcp(bind_tcp(addr, *backlog)?),
+        ListenerConfig::Unix {
+            path,
+            mode,
+            owner,
+            group,
+            backlog,
+        } => {
+            let listener = bind_unix(path, *backlog)?;
+            if let Some(mode) = mode {
+                fs::set_permissions(path, Permissions::from_mode(*mode))
+                    .map_err(|e| anyhow!("Unable to set mode of {}: {e}", path.display()))?;
+            }
+            if owner.is_some() || group.is_some() {
+                chown(path, *owner, *group)
+                    .map_err(|e| anyhow!("Unable to change owner of {}: {e}", path.display()))?;
+            }
+            Listener::Unix(listener)
+        }
+        ListenerConfig::Tls {
+            addr,
+            backlog,
+            cert_file,
+            key_file,
+            client_ca_file,
+        } => {
+            let acceptor = create_acceptor(cert_file, key_file, client_ca_file.as_deref())?;
+            Listener::Tls(bind_tcp(addr, *backlog)?, acceptor)
+        }
+    };
+    Ok(listener)
+}
+
+fn bind_tcp(addr: &SocketAddr, backlog: u32) -> Result<TcpListener, anyhow::Error> {
+    let bind = || {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.bind(*addr)?;
+        socket.listen(backlog)
+    };
+    bind().map_err(|e| anyhow!("Unable to listen on {addr}: {e}"))
+}
+
+fn bind_unix(path: &Path, backlog: u32) -> Result<UnixListener, anyhow::Error> {
+    let mut name = path.as_os_str().as_bytes().to_vec();
+    if is_abstract_socket(path) {
+        // Abstract socket names start with a NUL byte instead of `@`
+        name[0] = 0;
+    } else {
+        remove_stale_socket(path)?;
+    }
+    let bind = || {
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.bind(&SockAddr::unix(OsStr::from_bytes(&name))?)?;
+        socket.listen(backlog as i32)?;
+        socket.set_nonblocking(true)?;
+        UnixListener::from_std(StdUnixListener::from(OwnedFd::from(socket)))
+    };
+    bind().map_err(|e| anyhow!("Unable to listen on {}: {e}", path.display()))
+}
+
+/// Remove socket file left behind by a previous run
+/// Refuse to touch other files and sockets another server is still listening on
+fn remove_stale_socket(path: &Path) -> Result<(), anyhow::Error> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if StdUnixStream::connect(path).is_ok() {
+                return Err(anyhow!("{} is already in use", path.display()));
+            }
+            fs::remove_file(path)
+                .map_err(|e| anyhow!("Unable to remove stale socket {}: {e}", path.display()))
+        }
+        Ok(_) => Err(anyhow!(
+            "{} already exists and is not a socket",
+            path.display()
+        )),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Remove socket files of Unix listeners
+pub fn remove_socket_files(listeners: &[ListenerConfig]) {
+    for listener in listeners {
+        if let ListenerConfig::Unix { path, .. } = listener {
+            if !is_abstract_socket(path) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Accept clients on the listener until the server shuts down
+pub async fn accept_clients(
+    listener: Listener,
+    main_tx: Sender<(Uuid, ConnectionEvent)>,
+    mut connections: ActiveConnections,
+    channel_capacity: usize,
+    heartbeat: HeartbeatConfig,
+) {
+    loop {
+        match &listener {
+            Listener::Tcp(listener) => match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("TCP connection from {addr}");
+                    let _ = handle_client(
+                        stream,
+                        None,
+                        main_tx.clone(),
+                        &mut connections,
+                        channel_capacity,
+                        heartbeat,
+                    )
+                    .await;
+                }
+                Err(e) => error!("Failed to accept TCP connection: {}", e),
+            },
+            Listener::Unix(listener) => match listener.accept().await {
+                Ok((stream, _addr)) => {
+                    let peer_cred = stream
+                        .peer_cred()
+                        .inspect_err(|e| warn!("Unable to read Unix socket peer credentials: {e}"))
+                        .ok();
+                    let _ = handle_client(
+                        stream,
+                        peer_cred,
+                        main_tx.clone(),
+                        &mut connections,
+                        channel_capacity,
+                        heartbeat,
+                    )
+                    .await;
+                }
+                Err(e) => error!("Failed to accept Unix socket connection: {}", e),
+            },
+            // Handshake runs in its own task so a slow client doesn't block the listener
+            Listener::Tls(listener, acceptor) => match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("TLS connection from {addr}");
+                    let acceptor = acceptor.clone();
+                    let main_tx = main_tx.clone();
+                    let mut connections = connections.clone();
+                    tokio::spawn(async move {
+                        match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => {
+                                let _ = handle_client(
+                                    stream,
+                                    None,
+                                    main_tx,
+                                    &mut connections,
+                                    channel_capacity,
+                                    heartbeat,
+                                )
+                                .await;
+                            }
+                            Ok(Err(e)) => warn!("TLS handshake with {addr} failed: {e}"),
+                            Err(_) => warn!("TLS handshake with {addr} timed out"),
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept TLS connection: {}", e),
+            },
+        }
+    }
+}
@@ -1,50 +1,226 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant, SystemTime},
+};
 
+use log::error;
 use uuid::Uuid;
 
+use crate::{
+    accounts::Accounts,
+    dictionary::Dictionaries,
+    history::{MatchHistory, MatchRecord},
+    protocol::{EndReason, GameMode, LetterFeedback, MatchLimits},
+};
+
+/// Wrong guesses a hangman guesser can make before losing the match
+pub const HANGMAN_LIVES: u32 = 6;
+/// Placeholder for letters of a hangman word that haven't been guessed yet
+pub const HIDDEN_LETTER: char = '_';
+
 #[derive(Default)]
 pub enum MatchState {
     #[default]
     Active,
     GivenUp,
     Solved,
+    /// Server has shut down
     Cancelled,
+    /// Hangman guesser has run out of lives
+    OutOfLives,
+    OutOfAttempts,
+    TimedOut,
+    GuesserLeft,
+    ChallengerLeft,
+}
+
+impl MatchState {
+    /// Reason the players are told when the match is over, `None` while it is active
+    pub fn end_reason(&self) -> Option<EndReason> {
+        match self {
+            MatchState::Active => None,
+            MatchState::GivenUp => Some(EndReason::GivenUp),
+            MatchState::Solved => Some(EndReason::Solved),
+            MatchState::Cancelled => Some(EndReason::Cancelled),
+            MatchState::OutOfLives => Some(EndReason::OutOfLives),
+            MatchState::OutOfAttempts => Some(EndReason::OutOfAttempts),
+            MatchState::TimedOut => Some(EndReason::TimedOut),
+            MatchState::GuesserLeft => Some(EndReason::GuesserLeft),
+            MatchState::ChallengerLeft => Some(EndReason::ChallengerLeft),
+        }
+    }
+}
+
+/// Part a player plays in a match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchRole {
+    /// Player who picked the word and can send hints
+    Challenger,
+    /// Player who is guessing the word
+    Guesser,
+}
+
+/// Hint the challenger has sent to the guesser
+pub struct Hint {
+    pub text: String,
+    pub sent_at: SystemTime,
 }
 
-#[derive(Default)]
 pub struct Match {
     pub id: Uuid,
     pub challenger: Uuid,
     pub guesser: Uuid,
     pub attempts: u32,
-    pub hints: Vec<String>,
+    pub hints: Vec<Hint>,
     pub guess_word: String,
+    /// Language of the dictionary the word was found in, guesses are checked against it
+    pub language: Option<String>,
+    pub mode: GameMode,
+    /// Letters tried by a hangman guesser in the order they were guessed
+    pub tried_letters: Vec<char>,
+    /// Wrong guesses left in hangman
+    pub lives: u32,
+    pub limits: MatchLimits,
+    pub started_at: Instant,
+    /// Start of the match until the first guess, guess timeout counts from here
+    pub last_guess_at: Instant,
     pub state: MatchState,
 }
 
 impl Match {
-    pub fn new((challenger, guesser): (&Uuid, &Uuid), guess_word: &str) -> Self {
+    pub fn new(
+        (challenger, guesser): (&Uuid, &Uuid),
+        guess_word: &str,
+        language: Option<&str>,
+        mode: GameMode,
+        limits: MatchLimits,
+    ) -> Self {
+        let now = Instant::now();
         Self {
             id: Uuid::new_v4(),
             challenger: *challenger,
             guesser: *guesser,
             attempts: 0,
-            hints: Vec::<String>::new(),
+            hints: Vec::<Hint>::new(),
             guess_word: guess_word.to_string(),
+            language: language.map(str::to_string),
+            mode,
+            tried_letters: Vec::new(),
+            lives: HANGMAN_LIVES,
+            limits,
+            started_at: now,
+            last_guess_at: now,
             state: MatchState::Active,
         }
     }
 
-    pub fn attempt(&mut self, guess: &str) {
+    /// Role of the player in this match, `None` if the player doesn't participate
+    pub fn role_of(&self, player_id: &Uuid) -> Option<MatchRole> {
+        if self.guesser.eq(player_id) {
+            Some(MatchRole::Guesser)
+        } else if self.challenger.eq(player_id) {
+            Some(MatchRole::Challenger)
+        } else {
+            None
+        }
+    }
+
+    /// Count the attempt and return feedback for the guess, empty unless playing wordle
+    pub fn attempt(&mut self, guess: &str) -> Vec<LetterFeedback> {
         self.attempts += 1;
+        self.last_guess_at = Instant::now();
 
-        if guess.eq(&self.guess_word) {
+        let feedback = match self.mode {
+            GameMode::Hangman => {
+                self.hangman_attempt(guess);
+                Vec::new()
+            }
+            GameMode::Classic | GameMode::Wordle => {
+                if guess.eq(&self.guess_word) {
+                    self.state = MatchState::Solved;
+                }
+                if self.mode == GameMode::Wordle {
+                    wordle_feedback(&self.guess_word, guess)
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+        let max_attempts = self.limits.max_attempts;
+        if matches!(self.state, MatchState::Active)
+            && max_attempts > 0
+            && self.attempts >= max_attempts
+        {
+            self.state = MatchState::OutOfAttempts;
+        }
+        feedback
+    }
+
+    /// When the time limit or the guess timeout of an active match passes, whichever comes first
+    pub fn deadline(&self) -> Option<Instant> {
+        if !matches!(self.state, MatchState::Active) {
+            return None;
+        }
+        let after = |limit_secs: u32, since: Instant| {
+            (limit_secs > 0).then(|| since + Duration::from_secs(limit_secs.into()))
+        };
+        [
+            after(self.limits.time_limit_secs, self.started_at),
+            after(self.limits.guess_timeout_secs, self.last_guess_at),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// End the match as `TimedOut` once its time limit or guess timeout has passed at `now`
+    pub fn check_time_limits(&mut self, now: Instant) -> bool {
+        if self.deadline().is_some_and(|deadline| now >= deadline) {
+            self.state = MatchState::TimedOut;
+            return true;
+        }
+        false
+    }
+
+    /// Single letters are revealed in the word, wrong letters and wrong words cost a life
+    fn hangman_attempt(&mut self, guess: &str) {
+        let mut letters = guess.chars();
+        let is_correct = match (letters.next(), letters.next()) {
+            (Some(letter), None) => {
+                self.tried_letters.push(letter);
+                self.guess_word.contains(letter)
+            }
+            _ => guess.eq(&self.guess_word),
+        };
+        if !is_correct {
+            self.lives = self.lives.saturating_sub(1);
+        }
+        if guess.eq(&self.guess_word) || !self.mask().contains(HIDDEN_LETTER) {
             self.state = MatchState::Solved;
+        } else if self.lives == 0 {
+            self.state = MatchState::OutOfLives;
         }
     }
 
+    /// Word with letters that haven't been tried yet replaced by `HIDDEN_LETTER`
+    pub fn mask(&self) -> String {
+        self.guess_word
+            .chars()
+            .map(|letter| {
+                if self.tried_letters.contains(&letter) {
+                    letter
+                } else {
+                    HIDDEN_LETTER
+                }
+            })
+            .collect()
+    }
+
     pub fn add_hint(&mut self, hint: &str) {
-        self.hints.push(hint.to_string());
+        self.hints.push(Hint {
+            text: hint.to_string(),
+            sent_at: SystemTime::now(),
+        });
     }
 
     pub fn give_up(&mut self) {
@@ -54,13 +230,78 @@ impl Match {
     pub fn cancel(&mut self) {
         self.state = MatchState::Cancelled;
     }
+
+    /// End the match because the player in `role` has left
+    pub fn leave(&mut self, role: MatchRole) {
+        self.state = match role {
+            MatchRole::Challenger => MatchState::ChallengerLeft,
+            MatchRole::Guesser => MatchState::GuesserLeft,
+        };
+    }
+
+    pub fn duration_secs(&self) -> u32 {
+        self.started_at.elapsed().as_secs() as u32
+    }
+}
+
+/// Compare the guess with the word letter by letter.
+/// Exact matches are marked first, remaining letters are `Present` only as many times
+/// as the letter is left unmatched in the word, so duplicates aren't reported twice
+fn wordle_feedback(word: &str, guess: &str) -> Vec<LetterFeedback> {
+    let word = word.chars().collect::<Vec<char>>();
+    let guess = guess.chars().collect::<Vec<char>>();
+    let mut unmatched = HashMap::<char, usize>::new();
+    let mut feedback = guess
+        .iter()
+        .enumerate()
+        .map(|(idx, letter)| {
+            if word.get(idx) == Some(letter) {
+                LetterFeedback::Correct
+            } else {
+                LetterFeedback::Absent
+            }
+        })
+        .collect::<Vec<LetterFeedback>>();
+    for (idx, letter) in word.iter().enumerate() {
+        if guess.get(idx) != Some(letter) {
+            *unmatched.entry(*letter).or_default() += 1;
+        }
+    }
+    for (idx, letter) in guess.iter().enumerate() {
+        if feedback[idx] == LetterFeedback::Correct {
+            continue;
+        }
+        if let Some(count) = unmatched.get_mut(letter).filter(|count| **count > 0) {
+            *count -= 1;
+            feedback[idx] = LetterFeedback::Present;
+        }
+    }
+    feedback
+}
+
+/// Challenge waiting for the opponent to accept or decline it
+pub struct Invite {
+    pub id: Uuid,
+    pub challenger: Uuid,
+    pub opponent: Uuid,
+    pub guess_word: String,
+    pub language: Option<String>,
+    pub mode: GameMode,
+    pub limits: MatchLimits,
+    pub created_at: Instant,
 }
 
 #[derive(Default)]
 pub struct ServerState {
     pub available_players: HashSet<Uuid>,
+    /// Players whose connection dropped, they become available again once they resume the session
+    pub suspended_players: HashSet<Uuid>,
+    pub pending_invites: HashMap<Uuid, Invite>,
     pub active_matches: HashMap<Uuid, Match>,
-    pub finished_matches: HashMap<Uuid, Match>,
+    pub history: MatchHistory,
+    pub dictionaries: Dictionaries,
+    /// Loaded at startup and again on `SIGHUP`
+    pub accounts: Accounts,
 }
 
 impl ServerState {
@@ -70,18 +311,29 @@ impl ServerState {
     pub fn remove_available_player(&mut self, player_id: &Uuid) {
         self.available_players.remove(player_id);
     }
+    /// Nobody can challenge the player until the session is resumed
+    pub fn suspend_player(&mut self, player_id: &Uuid) {
+        self.available_players.remove(player_id);
+        self.suspended_players.insert(*player_id);
+    }
+    pub fn end_suspension(&mut self, player_id: &Uuid) {
+        self.suspended_players.remove(player_id);
+    }
 
     pub fn create_new_match(
         &mut self,
         player_duo: (&Uuid, &Uuid),
         guess_word: &str,
+        language: Option<&str>,
+        mode: GameMode,
+        limits: MatchLimits,
     ) -> Option<Uuid> {
         if !self.available_players.contains(player_duo.1)
             || !self.available_players.contains(player_duo.0)
         {
             return None;
         }
-        let new_match = Match::new(player_duo, guess_word);
+        let new_match = Match::new(player_duo, guess_word, language, mode, limits);
         let id = new_match.id;
         self.active_matches.insert(new_match.id, new_match);
         self.available_players.remove(player_duo.0);
@@ -90,11 +342,374 @@ impl ServerState {
         Some(id)
     }
 
+    /// Invite an available opponent to a match, players stay available until the invite is accepted
+    /// Only one invite can be pending from the challenger to the same opponent
+    pub fn create_invite(
+        &mut self,
+        (challenger, opponent): (&Uuid, &Uuid),
+        guess_word: &str,
+        language: Option<&str>,
+        mode: GameMode,
+        limits: MatchLimits,
+    ) -> Option<Uuid> {
+        if challenger == opponent
+            || !self.available_players.contains(challenger)
+            || !self.available_players.contains(opponent)
+            || self
+                .pending_invites
+                .values()
+                .any(|invite| invite.challenger.eq(challenger) && invite.opponent.eq(opponent))
+        {
+            return None;
+        }
+        let invite = Invite {
+            id: Uuid::new_v4(),
+            challenger: *challenger,
+            opponent: *opponent,
+            guess_word: guess_word.to_string(),
+            language: language.map(str::to_string),
+            mode,
+            limits,
+            created_at: Instant::now(),
+        };
+        let id = invite.id;
+        self.pending_invites.insert(id, invite);
+        Some(id)
+    }
+
+    /// Remove the invite if it is addressed to `opponent`
+    pub fn take_invite(&mut self, invite_id: &Uuid, opponent: &Uuid) -> Option<Invite> {
+        if self
+            .pending_invites
+            .get(invite_id)
+            .is_some_and(|invite| invite.opponent.eq(opponent))
+        {
+            self.pending_invites.remove(invite_id)
+        } else {
+            None
+        }
+    }
+
+    /// Remove invites that haven't been answered within `timeout`
+    pub fn expire_invites(&mut self, timeout: Duration) -> Vec<Invite> {
+        self.take_invites_where(|invite| invite.created_at.elapsed() >= timeout)
+    }
+
+    /// Remove all invites sent or received by the player
+    pub fn cancel_invites(&mut self, player_id: &Uuid) -> Vec<Invite> {
+        self.take_invites_where(|invite| {
+            invite.challenger.eq(player_id) || invite.opponent.eq(player_id)
+        })
+    }
+
+    fn take_invites_where(&mut self, predicate: impl Fn(&Invite) -> bool) -> Vec<Invite> {
+        let ids = self
+            .pending_invites
+            .values()
+            .filter(|invite| predicate(invite))
+            .map(|invite| invite.id)
+            .collect::<Vec<Uuid>>();
+        ids.iter()
+            .filter_map(|id| self.pending_invites.remove(id))
+            .collect()
+    }
+
+    /// Time out matches whose time limit has passed at `now`, they stay active until finished
+    pub fn time_out_matches(&mut self, now: Instant) -> Vec<Uuid> {
+        self.active_matches
+            .values_mut()
+            .filter_map(|active_match| {
+                active_match
+                    .check_time_limits(now)
+                    .then_some(active_match.id)
+            })
+            .collect()
+    }
+
+    /// Earliest deadline of the active matches, `None` if none of them has a time limit
+    pub fn next_match_deadline(&self) -> Option<Instant> {
+        self.active_matches
+            .values()
+            .filter_map(Match::deadline)
+            .min()
+    }
+
+    /// Make the players available again and move the match to the history
+    /// Suspended players stay unavailable until they come back
     pub fn finish_match(&mut self, match_id: Uuid) {
         if let Some(active_match) = self.active_matches.remove(&match_id) {
-            self.add_available_player(&active_match.guesser);
-            self.add_available_player(&active_match.challenger);
-            self.finished_matches.insert(match_id, active_match);
+            for player_id in [&active_match.guesser, &active_match.challenger] {
+                if !self.suspended_players.contains(player_id) {
+                    self.add_available_player(player_id);
+                }
+            }
+            // Matches are always ended before they are finished
+            let end_reason = active_match
+                .state
+                .end_reason()
+                .unwrap_or(EndReason::Cancelled);
+            let record = MatchRecord::new(&active_match, end_reason);
+            if let Err(e) = self.history.record(record) {
+                error!("Unable to store match {match_id} in the history: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LetterFeedback::{Absent, Correct, Present};
+
+    #[test]
+    fn wordle_feedback_handles_duplicate_letters() {
+        assert_eq!(
+            wordle_feedback("crane", "react"),
+            vec![Present, Present, Correct, Present, Absent]
+        );
+        // Only one `e` left unmatched in the word, the second one is absent
+        assert_eq!(
+            wordle_feedback("abbey", "keeps"),
+            vec![Absent, Present, Absent, Absent, Absent]
+        );
+        // Exact match takes precedence over an earlier misplaced duplicate
+        assert_eq!(
+            wordle_feedback("those", "geese"),
+            vec![Absent, Absent, Absent, Correct, Correct]
+        );
+        assert_eq!(wordle_feedback("žaba", "žaba"), vec![Correct; 4]);
+    }
+
+    #[test]
+    fn hangman_reveals_letters_and_takes_lives() {
+        let players = (&Uuid::new_v4(), &Uuid::new_v4());
+        let mut hangman = Match::new(
+            players,
+            "otto",
+            None,
+            GameMode::Hangman,
+            MatchLimits::default(),
+        );
+        hangman.attempt("t");
+        assert_eq!(hangman.mask(), "_tt_");
+        assert_eq!(hangman.lives, HANGMAN_LIVES);
+        hangman.attempt("a");
+        hangman.attempt("auto");
+        assert_eq!(hangman.lives, HANGMAN_LIVES - 2);
+        hangman.attempt("o");
+        assert!(matches!(hangman.state, MatchState::Solved));
+
+        let mut lost = Match::new(
+            players,
+            "otto",
+            None,
+            GameMode::Hangman,
+            MatchLimits::default(),
+        );
+        for letter in ["a", "b", "c", "d", "e", "f"] {
+            lost.attempt(letter);
+        }
+        assert_eq!(lost.lives, 0);
+        assert!(matches!(lost.state, MatchState::OutOfLives));
+    }
+
+    #[test]
+    fn limits_end_the_match() {
+        let players = (&Uuid::new_v4(), &Uuid::new_v4());
+        let limits = MatchLimits {
+            max_attempts: 2,
+            time_limit_secs: 60,
+            guess_timeout_secs: 5,
+        };
+        let mut limited = Match::new(players, "cat", None, GameMode::Classic, limits);
+        limited.attempt("dog");
+        assert!(!limited.check_time_limits(Instant::now()));
+        limited.attempt("cow");
+        assert!(matches!(limited.state, MatchState::OutOfAttempts));
+
+        let mut slow = Match::new(players, "cat", None, GameMode::Classic, limits);
+        assert_eq!(
+            slow.deadline(),
+            Some(slow.last_guess_at + Duration::from_secs(5))
+        );
+        assert!(!slow.check_time_limits(slow.last_guess_at + Duration::from_secs(4)));
+        assert!(slow.check_time_limits(slow.last_guess_at + Duration::from_secs(5)));
+        assert!(matches!(slow.state, MatchState::TimedOut));
+
+        let mut solved = Match::new(players, "cat", None, GameMode::Classic, limits);
+        solved.attempt("cat");
+        assert_eq!(solved.deadline(), None);
+        assert!(!solved.check_time_limits(solved.started_at + Duration::from_secs(60)));
+        assert!(matches!(solved.state, MatchState::Solved));
+    }
+
+    #[test]
+    fn invites_need_available_players_and_are_not_repeated() {
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (classic, limits) = (GameMode::Classic, MatchLimits::default());
+        let mut state = ServerState::default();
+        for player_id in [&alice, &bob, &carol] {
+            state.add_available_player(player_id);
+        }
+
+        assert!(state
+            .create_invite((&alice, &alice), "cat", None, classic, limits)
+            .is_none());
+        assert!(state
+            .create_invite((&alice, &bob), "cat", None, classic, limits)
+            .is_some());
+        assert!(state
+            .create_invite((&alice, &bob), "cow", None, classic, limits)
+            .is_none());
+        // Other direction is a different challenge
+        assert!(state
+            .create_invite((&bob, &alice), "cat", None, classic, limits)
+            .is_some());
+        // Players stay available until a match starts
+        assert_eq!(state.available_players.len(), 3);
+
+        state.suspend_player(&carol);
+        assert!(state
+            .create_invite((&alice, &carol), "cat", None, classic, limits)
+            .is_none());
+        assert_eq!(state.pending_invites.len(), 2);
+    }
+
+    #[test]
+    fn only_opponent_takes_invite_once() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut state = ServerState::default();
+        state.add_available_player(&alice);
+        state.add_available_player(&bob);
+        let invite_id = state
+            .create_invite(
+                (&alice, &bob),
+                "cat",
+                None,
+                GameMode::Classic,
+                MatchLimits::default(),
+            )
+            .unwrap();
+
+        assert!(state.take_invite(&invite_id, &alice).is_none());
+        assert!(state.pending_invites.contains_key(&invite_id));
+        assert_eq!(state.take_invite(&invite_id, &bob).unwrap().id, invite_id);
+        assert!(state.pending_invites.is_empty());
+        assert!(state.take_invite(&invite_id, &bob).is_none());
+    }
+
+    #[test]
+    fn cancelled_invites_are_those_of_the_player() {
+        let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let (classic, limits) = (GameMode::Classic, MatchLimits::default());
+        let mut state = ServerState::default();
+        for player_id in [&alice, &bob, &carol] {
+            state.add_available_player(player_id);
         }
+        let sent = state.create_invite((&alice, &bob), "cat", None, classic, limits);
+        let received = state.create_invite((&carol, &alice), "cat", None, classic, limits);
+        let other = state.create_invite((&bob, &carol), "cat", None, classic, limits);
+
+        let mut cancelled = state
+            .cancel_invites(&alice)
+            .into_iter()
+            .map(|invite| Some(invite.id))
+            .collect::<Vec<_>>();
+        cancelled.sort();
+        let mut expected = vec![sent, received];
+        expected.sort();
+        assert_eq!(cancelled, expected);
+        assert_eq!(
+            state
+                .pending_invites
+                .keys()
+                .copied()
+                .map(Some)
+                .collect::<Vec<_>>(),
+            [other]
+        );
+        assert!(state.expire_invites(Duration::from_secs(60)).is_empty());
+        assert_eq!(state.expire_invites(Duration::ZERO).len(), 1);
+    }
+
+    #[test]
+    fn earliest_deadline_times_out_its_match_only() {
+        let players = [
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        ];
+        let mut state = ServerState::default();
+        for player_id in &players {
+            state.add_available_player(player_id);
+        }
+        let quick_limits = MatchLimits {
+            guess_timeout_secs: 10,
+            ..MatchLimits::default()
+        };
+        let slow_limits = MatchLimits {
+            guess_timeout_secs: 20,
+            ..MatchLimits::default()
+        };
+        let quick = state
+            .create_new_match(
+                (&players[0], &players[1]),
+                "cat",
+                None,
+                GameMode::Classic,
+                quick_limits,
+            )
+            .unwrap();
+        let slow = state
+            .create_new_match(
+                (&players[2], &players[3]),
+                "cat",
+                None,
+                GameMode::Classic,
+                slow_limits,
+            )
+            .unwrap();
+
+        let deadline = state.next_match_deadline().unwrap();
+        assert_eq!(Some(deadline), state.active_matches[&quick].deadline());
+        assert!(state
+            .time_out_matches(deadline - Duration::from_millis(1))
+            .is_empty());
+        assert_eq!(state.time_out_matches(deadline), [quick]);
+        // Timed out match waits to be finished, it has no deadline anymore
+        assert!(state.time_out_matches(deadline).is_empty());
+        assert_eq!(
+            state.next_match_deadline(),
+            state.active_matches[&slow].deadline()
+        );
+    }
+
+    #[test]
+    fn finished_match_is_counted_in_stats() {
+        let (challenger, guesser) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut state = ServerState::default();
+        state.add_available_player(&challenger);
+        state.add_available_player(&guesser);
+        let match_id = state
+            .create_new_match(
+                (&challenger, &guesser),
+                "cat",
+                None,
+                GameMode::Classic,
+                MatchLimits::default(),
+            )
+            .unwrap();
+        let active_match = state.active_matches.get_mut(&match_id).unwrap();
+        active_match.attempt("dog");
+        active_match.attempt("cat");
+        state.finish_match(match_id);
+
+        let stats = state.history.stats(&guesser);
+        assert_eq!(stats.games_as_guesser, 1);
+        assert_eq!(stats.solved, 1);
+        assert_eq!(stats.attempts, 2);
+        assert_eq!(state.history.stats(&challenger).games_as_challenger, 1);
+        assert_eq!(state.available_players.len(), 2);
     }
 }
@@ -0,0 +1,36 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::anyhow;
+use tokio_rustls::{
+    rustls::{server::WebPkiClientVerifier, ServerConfig},
+    TlsAcceptor,
+};
+
+use crate::tls::{load_certs, load_private_key, load_root_store};
+
+/// Create acceptor for the TLS listener from the configured PEM files
+/// Client certificates are required only when a client CA is configured
+pub fn create_acceptor(
+    cert_file: &Path,
+    key_file: &Path,
+    client_ca_file: Option<&Path>,
+) -> Result<TlsAcceptor, anyhow::Error> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+
+    let builder = match client_ca_file {
+        Some(client_ca_file) => {
+            let roots = load_root_store(client_ca_file)?;
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| anyhow!("Unable to create client certificate verifier: {e}"))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| anyhow!("Invalid TLS certificate or key: {e}"))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
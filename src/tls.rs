@@ -0,0 +1,38 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::anyhow;
+use tokio_rustls::rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer},
+    RootCertStore,
+};
+
+/// Load all certificates from a PEM file
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, anyhow::Error> {
+    let file = File::open(path).map_err(|e| anyhow!("Unable to open {}: {e}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("Invalid certificate in {}: {e}", path.display()))?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", path.display()));
+    }
+    Ok(certs)
+}
+
+/// Load the first private key from a PEM file
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, anyhow::Error> {
+    let file = File::open(path).map_err(|e| anyhow!("Unable to open {}: {e}", path.display()))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| anyhow!("Invalid private key in {}: {e}", path.display()))?
+        .ok_or(anyhow!("No private key found in {}", path.display()))
+}
+
+/// Load certificate authorities used to verify the other side of the connection
+pub fn load_root_store(path: &Path) -> Result<RootCertStore, anyhow::Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| anyhow!("Invalid CA certificate in {}: {e}", path.display()))?;
+    }
+    Ok(roots)
+}
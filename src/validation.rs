@@ -1,8 +1,158 @@
-pub fn is_valid_word(input: &str) -> bool {
-    if input.is_empty() {
-        return false;
+use std::fmt;
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+const DEFAULT_MIN_LENGTH: usize = 1;
+const DEFAULT_MAX_LENGTH: usize = 32;
+
+/// Rules for words challengers pick for their opponents.
+/// Words always consist of lowercase letters, lengths are counted in characters
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WordRules {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Letters words may consist of, any letter is allowed if not set
+    pub alphabet: Option<String>,
+    /// Allow letters outside of ASCII
+    pub unicode: bool,
+    /// Convert words to lowercase instead of rejecting uppercase letters.
+    /// Guesses are converted as well so they match regardless of case
+    pub case_folding: bool,
+}
+
+impl Default for WordRules {
+    fn default() -> Self {
+        Self {
+            min_length: DEFAULT_MIN_LENGTH,
+            max_length: DEFAULT_MAX_LENGTH,
+            alphabet: None,
+            unicode: true,
+            case_folding: false,
+        }
+    }
+}
+
+/// Reason why a word doesn't follow the rules
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidWord {
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for InvalidWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidWord::TooShort(min) => write!(f, "word must have at least {min} letters"),
+            InvalidWord::TooLong(max) => write!(f, "word can have at most {max} letters"),
+            InvalidWord::InvalidCharacter(c) => write!(f, "character {c:?} is not allowed"),
+        }
+    }
+}
+
+impl WordRules {
+    /// Apply case folding if enabled
+    pub fn normalize(&self, word: &str) -> String {
+        if self.case_folding {
+            word.to_lowercase()
+        } else {
+            word.to_string()
+        }
     }
 
-    // Iterate over each character and ensure it is alphabetic
-    input.chars().all(|c| c.is_alphabetic() && c.is_lowercase())
+    /// Check the word and return it in the form it should be played with
+    pub fn validate(&self, word: &str) -> Result<String, InvalidWord> {
+        let word = self.normalize(word);
+        if let Some(c) = word.chars().find(|c| !self.allows(*c)) {
+            return Err(InvalidWord::InvalidCharacter(c));
+        }
+        let length = word.chars().count();
+        if length < self.min_length {
+            return Err(InvalidWord::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            return Err(InvalidWord::TooLong(self.max_length));
+        }
+        Ok(word)
+    }
+
+    fn allows(&self, c: char) -> bool {
+        let is_letter = c.is_alphabetic() && c.is_lowercase();
+        let in_alphabet = match &self.alphabet {
+            Some(alphabet) => alphabet.contains(c),
+            None => true,
+        };
+        is_letter && in_alphabet && (self.unicode || c.is_ascii())
+    }
+
+    /// Check that the rules can be satisfied by at least some words
+    pub fn check(&self) -> Result<(), anyhow::Error> {
+        if self.min_length == 0 || self.max_length < self.min_length {
+            return Err(anyhow!(
+                "Word length limits must be positive with min_length <= max_length"
+            ));
+        }
+        if let Some(alphabet) = &self.alphabet {
+            if alphabet.is_empty() || !alphabet.chars().all(|c| self.allows(c)) {
+                return Err(anyhow!(
+                    "Word alphabet must consist of allowed lowercase letters"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rules_accept_lowercase_letters() {
+        let rules = WordRules::default();
+        assert_eq!(rules.validate("cat"), Ok("cat".to_string()));
+        assert_eq!(rules.validate("žaba"), Ok("žaba".to_string()));
+        assert_eq!(rules.validate(""), Err(InvalidWord::TooShort(1)));
+        assert_eq!(
+            rules.validate("two words"),
+            Err(InvalidWord::InvalidCharacter(' '))
+        );
+        assert_eq!(
+            rules.validate("Cat"),
+            Err(InvalidWord::InvalidCharacter('C'))
+        );
+        assert_eq!(
+            rules.validate(&"a".repeat(33)),
+            Err(InvalidWord::TooLong(32))
+        );
+    }
+
+    #[test]
+    fn configured_rules_are_applied() {
+        let rules = WordRules {
+            min_length: 3,
+            max_length: 5,
+            alphabet: Some("abct".to_string()),
+            unicode: false,
+            case_folding: true,
+        };
+        assert_eq!(rules.validate("CAT"), Ok("cat".to_string()));
+        assert_eq!(rules.validate("ab"), Err(InvalidWord::TooShort(3)));
+        assert_eq!(
+            rules.validate("dog"),
+            Err(InvalidWord::InvalidCharacter('d'))
+        );
+        assert!(rules.check().is_ok());
+
+        let ascii_only = WordRules {
+            unicode: false,
+            ..WordRules::default()
+        };
+        assert_eq!(
+            ascii_only.validate("žaba"),
+            Err(InvalidWord::InvalidCharacter('ž'))
+        );
+    }
 }